@@ -0,0 +1,1384 @@
+// Copyright 2024, G. Christopher Warrington <code@cw.codes>
+//
+// find-dups is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License Version 3 as
+// published by the Free Software Foundation.
+//
+// find-dups is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public
+// License for more details.
+//
+// A copy of the GNU Affero General Public License Version 3 is included in
+// the file LICENSE in the root of the repository.
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::hasher::{HashAlgorithm, Sha256Sum};
+use crate::scanner::{FileId, HashedFile, PathLocation, WorkResult};
+#[cfg(test)]
+use crate::test_dir::TempDir;
+use clap::ValueEnum;
+#[cfg(test)]
+use proptest::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::path;
+use std::thread;
+use std::time;
+
+// Map keyed by digest. The standard HashMap is already an open-addressing
+// table that confirms each match against the whole key, so a purpose-built
+// one keyed on the digest's first eight bytes would still have to store
+// the whole digest, and wouldn't save any memory.
+pub type DigestMap<V> = HashMap<Sha256Sum, V>;
+
+// What makes two files the same file.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Identity {
+    /// Files with the same content.
+    Content,
+    /// Files with the same content and file name.
+    #[value(name = "content+name")]
+    ContentAndName,
+    /// Files with the same content and path relative to their root.
+    #[value(name = "content+relpath")]
+    ContentAndRelPath,
+}
+
+// Returns the key that files are grouped by for an identity. For anything
+// but content alone, the metadata that is part of the identity is mixed
+// into the content hash, so that every identity is grouped the same way.
+// Relative paths are mixed in component by component, so that the
+// separator used doesn't matter.
+pub fn identity_key(identity: Identity, hash: &Sha256Sum, rel_path: &path::Path) -> Sha256Sum {
+    let mut hasher = Sha256::new();
+    hasher.update(hash);
+
+    match identity {
+        Identity::Content => return *hash,
+        Identity::ContentAndName => {
+            let name = rel_path.file_name().unwrap_or(rel_path.as_os_str());
+            hasher.update(name.as_encoded_bytes());
+        }
+        Identity::ContentAndRelPath => {
+            for component in rel_path.components() {
+                hasher.update(b"/");
+                hasher.update(component.as_os_str().as_encoded_bytes());
+            }
+        }
+    }
+
+    hasher.finalize().into()
+}
+
+#[test]
+fn identity_key_mixes_in_chosen_metadata() {
+    let hash: Sha256Sum = [7; 32];
+    let key = |identity, rel_path: &str| identity_key(identity, &hash, path::Path::new(rel_path));
+
+    assert_eq!(key(Identity::Content, "a/x"), hash);
+    assert_eq!(key(Identity::Content, "b/y"), hash);
+
+    assert_eq!(
+        key(Identity::ContentAndName, "a/x"),
+        key(Identity::ContentAndName, "b/x")
+    );
+    assert_ne!(
+        key(Identity::ContentAndName, "a/x"),
+        key(Identity::ContentAndName, "a/y")
+    );
+
+    assert_ne!(
+        key(Identity::ContentAndRelPath, "a/x"),
+        key(Identity::ContentAndRelPath, "b/x")
+    );
+    assert_eq!(
+        key(Identity::ContentAndRelPath, "a/x"),
+        key(Identity::ContentAndRelPath, "a/x")
+    );
+}
+
+pub fn add_to_result_hash_map(
+    map: &mut DigestMap<Vec<path::PathBuf>>,
+    hash: Sha256Sum,
+    path: path::PathBuf,
+) {
+    map.entry(hash)
+        .or_insert_with(|| Vec::with_capacity(1))
+        .push(path);
+}
+
+// Records the file found at a relative path. When overlapping roots on the
+// same side put more than one file at the same relative path, the one with
+// the smallest path is kept, so that which one wins doesn't depend on the
+// order they were found in.
+pub fn add_to_rel_path_map(
+    map: &mut HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    rel_path: path::PathBuf,
+    path: path::PathBuf,
+    hashed: HashedFile,
+) {
+    match map.entry(rel_path) {
+        Entry::Occupied(mut entry) => {
+            if path < entry.get().0 {
+                entry.insert((path, hashed));
+            }
+        }
+        Entry::Vacant(entry) => {
+            entry.insert((path, hashed));
+        }
+    }
+}
+
+// Returns the path relative to the command line root that it was found
+// under, so that paths from the two sides can be lined up with each other.
+// When more than one root contains the path, the closest one wins. A root
+// that is itself a file is relative to its parent directory.
+pub fn relative_path<'a>(roots: &[OsString], path: &'a path::Path) -> &'a path::Path {
+    let rel = roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|rel| rel.components().count());
+
+    match rel {
+        Some(rel) if rel.as_os_str().is_empty() => {
+            path.file_name().map(path::Path::new).unwrap_or(path)
+        }
+        Some(rel) => rel,
+        None => path,
+    }
+}
+
+#[test]
+fn relative_path_strips_closest_root() {
+    let roots: Vec<OsString> = vec!["a".into(), "a/b".into()];
+
+    assert_eq!(
+        relative_path(&roots, path::Path::new("a/b/c/d")),
+        path::Path::new("c/d")
+    );
+    assert_eq!(
+        relative_path(&roots, path::Path::new("a/x")),
+        path::Path::new("x")
+    );
+    assert_eq!(
+        relative_path(&roots, path::Path::new("a/b")),
+        path::Path::new("b")
+    );
+}
+
+// A file that has the same relative path, size, and modification time on
+// both sides was almost certainly copied from one side to the other and
+// then left alone. If the content differs anyway, the likely explanation
+// is that one of the copies was silently corrupted.
+pub fn find_bit_rot_candidates(
+    left: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    right: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) -> Vec<(path::PathBuf, path::PathBuf)> {
+    let mut candidates: Vec<(path::PathBuf, path::PathBuf)> = left
+        .iter()
+        .filter_map(|(rel_path, (lpath, lhashed))| {
+            let (rpath, rhashed) = right.get(rel_path)?;
+            if lhashed.size.is_some()
+                && lhashed.size == rhashed.size
+                && same_modified_second(lhashed.modified, rhashed.modified)
+                && lhashed.hash != rhashed.hash
+            {
+                Some((lpath.clone(), rpath.clone()))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    candidates.sort_unstable();
+    candidates
+}
+
+pub struct Conflict<'a> {
+    pub rel_path: &'a path::Path,
+    pub left: &'a (path::PathBuf, HashedFile),
+    pub right: &'a (path::PathBuf, HashedFile),
+}
+
+// Returns the files at the same relative path on both sides whose content
+// differs, sorted by relative path.
+pub fn find_conflicts<'a>(
+    left: &'a HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    right: &'a HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) -> Vec<Conflict<'a>> {
+    let mut conflicts: Vec<Conflict> = left
+        .iter()
+        .filter_map(|(rel_path, lfile)| {
+            let rfile = right.get(rel_path)?;
+            (lfile.1.hash != rfile.1.hash).then_some(Conflict {
+                rel_path,
+                left: lfile,
+                right: rfile,
+            })
+        })
+        .collect();
+
+    conflicts.sort_unstable_by_key(|conflict| conflict.rel_path);
+    conflicts
+}
+
+// Returns the groups of files whose relative paths differ only by case.
+// Case-insensitive file systems, like those Windows and macOS use by
+// default, can only hold one file of each group, so copying them to one
+// would overwrite all but one. Each group is sorted, and so are the groups.
+pub fn find_case_collisions(
+    files: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) -> Vec<Vec<path::PathBuf>> {
+    let mut by_folded: HashMap<String, Vec<path::PathBuf>> = HashMap::new();
+    for (rel_path, (path, _)) in files {
+        by_folded
+            .entry(rel_path.to_string_lossy().to_lowercase())
+            .or_default()
+            .push(path.clone());
+    }
+
+    let mut collisions: Vec<Vec<path::PathBuf>> = by_folded
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .map(|mut paths| {
+            paths.sort_unstable();
+            paths
+        })
+        .collect();
+    collisions.sort_unstable();
+    collisions
+}
+
+#[test]
+fn case_collisions_differ_only_by_case() {
+    let hashed = HashedFile {
+        hash: [0; 32],
+        size: Some(0),
+        modified: None,
+        file_id: None,
+    };
+    let files: HashMap<path::PathBuf, (path::PathBuf, HashedFile)> = [
+        "docs/README.md",
+        "docs/readme.md",
+        "Docs/Readme.md",
+        "docs/other.md",
+        "Photos/IMG.jpg",
+        "photos/img.JPG",
+        "unique",
+    ]
+    .iter()
+    .map(|rel_path| {
+        (
+            rel_path.into(),
+            (path::Path::new("l").join(rel_path), hashed),
+        )
+    })
+    .collect();
+
+    let paths = |names: &[&str]| -> Vec<path::PathBuf> {
+        names
+            .iter()
+            .map(|name| path::Path::new("l").join(name))
+            .collect()
+    };
+    assert_eq!(
+        find_case_collisions(&files),
+        vec![
+            paths(&["Docs/Readme.md", "docs/README.md", "docs/readme.md"]),
+            paths(&["Photos/IMG.jpg", "photos/img.JPG"]),
+        ]
+    );
+}
+
+// Which side a comparison's files appear to have been copied from.
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    Left,
+    Right,
+}
+
+// What the files at each relative path say about which side is the source
+// and which is a stale mirror of it.
+#[derive(Debug, Default, PartialEq)]
+pub struct DirectionEvidence {
+    // Files with different content on each side that were modified later
+    // on the left, and on the right.
+    pub newer_left: usize,
+    pub newer_right: usize,
+    // Files that are only on the left, and only on the right.
+    pub only_left: usize,
+    pub only_right: usize,
+}
+
+impl DirectionEvidence {
+    // Files are changed on the source and then copied, so the side with
+    // more of the newer copies is the source. When that doesn't tell them
+    // apart, the side with more files of its own is, as new files haven't
+    // been copied to the mirror yet.
+    pub fn source(&self) -> Option<Source> {
+        let by_count = |left: usize, right: usize| match left.cmp(&right) {
+            std::cmp::Ordering::Greater => Some(Source::Left),
+            std::cmp::Ordering::Less => Some(Source::Right),
+            std::cmp::Ordering::Equal => None,
+        };
+        by_count(self.newer_left, self.newer_right)
+            .or_else(|| by_count(self.only_left, self.only_right))
+    }
+}
+
+pub fn infer_direction(
+    left: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    right: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) -> DirectionEvidence {
+    let mut evidence = DirectionEvidence::default();
+
+    for (rel_path, (_, lhashed)) in left {
+        let Some((_, rhashed)) = right.get(rel_path) else {
+            evidence.only_left += 1;
+            continue;
+        };
+        if lhashed.hash == rhashed.hash || same_modified_second(lhashed.modified, rhashed.modified)
+        {
+            continue;
+        }
+        match (lhashed.modified, rhashed.modified) {
+            (Some(lmodified), Some(rmodified)) if lmodified > rmodified => evidence.newer_left += 1,
+            (Some(_), Some(_)) => evidence.newer_right += 1,
+            _ => {}
+        }
+    }
+
+    evidence.only_right = right
+        .keys()
+        .filter(|rel_path| !left.contains_key(*rel_path))
+        .count();
+
+    evidence
+}
+
+#[test]
+fn infer_direction_prefers_newer_copies_over_counts() {
+    let at = |secs: u64| Some(time::UNIX_EPOCH + time::Duration::from_secs(secs));
+    let hashed = |hash: u8, modified: Option<time::SystemTime>| HashedFile {
+        hash: [hash; 32],
+        size: Some(1),
+        modified,
+        file_id: None,
+    };
+
+    let mut left: HashMap<path::PathBuf, (path::PathBuf, HashedFile)> = HashMap::new();
+    let mut right: HashMap<path::PathBuf, (path::PathBuf, HashedFile)> = HashMap::new();
+
+    left.insert("edited".into(), ("l/edited".into(), hashed(1, at(200))));
+    right.insert("edited".into(), ("r/edited".into(), hashed(2, at(100))));
+    left.insert("same".into(), ("l/same".into(), hashed(3, at(100))));
+    right.insert("same".into(), ("r/same".into(), hashed(3, at(300))));
+    right.insert("extra1".into(), ("r/extra1".into(), hashed(4, at(100))));
+    right.insert("extra2".into(), ("r/extra2".into(), hashed(5, at(100))));
+
+    let evidence = infer_direction(&left, &right);
+    assert_eq!(
+        evidence,
+        DirectionEvidence {
+            newer_left: 1,
+            newer_right: 0,
+            only_left: 0,
+            only_right: 2,
+        }
+    );
+    assert_eq!(evidence.source(), Some(Source::Left));
+
+    left.remove(path::Path::new("edited"));
+    assert_eq!(infer_direction(&left, &right).source(), Some(Source::Right));
+
+    assert_eq!(DirectionEvidence::default().source(), None);
+}
+
+// Modification times are compared to the second, as many copy tools and
+// filesystems don't preserve anything finer than that.
+pub fn same_modified_second(lhs: Option<time::SystemTime>, rhs: Option<time::SystemTime>) -> bool {
+    let secs = |t: time::SystemTime| match t.duration_since(time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i128,
+        Err(e) => -(e.duration().as_secs() as i128),
+    };
+
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => secs(lhs) == secs(rhs),
+        _ => false,
+    }
+}
+
+#[test]
+fn bit_rot_candidates_need_matching_size_and_mtime() {
+    let mtime = time::UNIX_EPOCH + time::Duration::from_secs(1_700_000_000);
+    let hashed = |hash: u8, size: u64, modified: time::SystemTime| HashedFile {
+        hash: [hash; 32],
+        size: Some(size),
+        modified: Some(modified),
+        file_id: None,
+    };
+
+    let mut left: HashMap<path::PathBuf, (path::PathBuf, HashedFile)> = HashMap::new();
+    let mut right: HashMap<path::PathBuf, (path::PathBuf, HashedFile)> = HashMap::new();
+
+    left.insert("rot".into(), ("l/rot".into(), hashed(1, 10, mtime)));
+    right.insert(
+        "rot".into(),
+        (
+            "r/rot".into(),
+            hashed(2, 10, mtime + time::Duration::from_millis(500)),
+        ),
+    );
+
+    left.insert("same".into(), ("l/same".into(), hashed(1, 10, mtime)));
+    right.insert("same".into(), ("r/same".into(), hashed(1, 10, mtime)));
+
+    left.insert("edited".into(), ("l/edited".into(), hashed(1, 10, mtime)));
+    right.insert(
+        "edited".into(),
+        (
+            "r/edited".into(),
+            hashed(2, 10, mtime + time::Duration::from_secs(60)),
+        ),
+    );
+
+    left.insert("resized".into(), ("l/resized".into(), hashed(1, 10, mtime)));
+    right.insert("resized".into(), ("r/resized".into(), hashed(2, 11, mtime)));
+
+    assert_eq!(
+        find_bit_rot_candidates(&left, &right),
+        vec![("l/rot".into(), "r/rot".into())]
+    );
+}
+
+#[derive(Default)]
+pub struct Acknowledgements {
+    hashes: HashSet<Sha256Sum>,
+    pairs: HashSet<(path::PathBuf, path::PathBuf)>,
+}
+
+impl Acknowledgements {
+    pub fn read_from(path: &path::Path) -> io::Result<Acknowledgements> {
+        let file = fs::File::open(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("unable to open '{}': {}", path.display(), e),
+            )
+        })?;
+        Acknowledgements::parse(io::BufReader::new(file))
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> io::Result<Acknowledgements> {
+        let mut acks = Acknowledgements::default();
+
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some((lpath, rpath)) = line.split_once('\t') {
+                acks.pairs.insert((lpath.into(), rpath.into()));
+                continue;
+            }
+
+            let mut hash: Sha256Sum = [0u8; 32];
+            if hex::decode_to_slice(line.trim(), &mut hash).is_err() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "line {} of acknowledge file is neither a SHA256 hash nor a tab-separated path pair",
+                        line_num + 1
+                    ),
+                ));
+            }
+            acks.hashes.insert(hash);
+        }
+
+        Ok(acks)
+    }
+
+    pub fn contains(
+        &self,
+        hash: &Sha256Sum,
+        lpaths: &[path::PathBuf],
+        rpaths: &[path::PathBuf],
+    ) -> bool {
+        self.hashes.contains(hash)
+            || self
+                .pairs
+                .iter()
+                .any(|(lpath, rpath)| lpaths.contains(lpath) && rpaths.contains(rpath))
+    }
+}
+
+// Acknowledged duplicates are removed from both sides entirely. They'd
+// only ever show up in the 'both' locations, and leaving them on one side
+// would make them look like they only exist on that side.
+pub fn remove_acknowledged(
+    left: &mut DigestMap<Vec<path::PathBuf>>,
+    right: &mut DigestMap<Vec<path::PathBuf>>,
+    acks: &Acknowledgements,
+) {
+    left.retain(|hash, lpaths| match right.get(hash) {
+        Some(rpaths) if acks.contains(hash, lpaths, rpaths) => {
+            right.remove(hash);
+            false
+        }
+        _ => true,
+    });
+}
+
+#[test]
+fn acknowledged_groups_are_removed() {
+    let some_sha256_sum_h: Sha256Sum = [1u8; 32];
+    let some_sha256_sum_p: Sha256Sum = [2u8; 32];
+    let some_sha256_sum_n: Sha256Sum = [4u8; 32];
+
+    let acks_text = format!(
+        "# reviewed\n{}\n\nlpath_p\trpath_p\n",
+        hex::encode(some_sha256_sum_h)
+    );
+    let acks = Acknowledgements::parse(acks_text.as_bytes()).unwrap();
+
+    let mut left: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    left.insert(some_sha256_sum_h, vec!["lpath_h".into()]);
+    left.insert(some_sha256_sum_p, vec!["lpath_p".into()]);
+    left.insert(some_sha256_sum_n, vec!["lpath_n".into()]);
+
+    let mut right: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    right.insert(some_sha256_sum_h, vec!["rpath_h".into()]);
+    right.insert(
+        some_sha256_sum_p,
+        vec!["rpath_other".into(), "rpath_p".into()],
+    );
+    right.insert(some_sha256_sum_n, vec!["rpath_n".into()]);
+
+    remove_acknowledged(&mut left, &mut right, &acks);
+
+    assert_eq!(left.keys().collect::<Vec<_>>(), vec![&some_sha256_sum_n]);
+    assert_eq!(right.keys().collect::<Vec<_>>(), vec![&some_sha256_sum_n]);
+
+    assert!(Acknowledgements::parse("not a hash\n".as_bytes()).is_err());
+}
+
+pub struct MirrorCounts {
+    pub mirrored: usize,
+    pub stray_left: usize,
+    pub stray_right: usize,
+}
+
+// Counts the copies in a 'both' location that are at the same relative
+// path on both sides, which are what a mirror is expected to have, and the
+// extra copies elsewhere on either side.
+pub fn classify_mirrored(
+    left_roots: &[OsString],
+    lpaths: &[path::PathBuf],
+    right_roots: &[OsString],
+    rpaths: &[path::PathBuf],
+) -> MirrorCounts {
+    // Counted rather than searched, as degenerate groups can have millions
+    // of paths on each side.
+    let mut unmatched_right: HashMap<&path::Path, usize> = HashMap::new();
+    for rpath in rpaths {
+        *unmatched_right
+            .entry(relative_path(right_roots, rpath))
+            .or_default() += 1;
+    }
+
+    let mut mirrored = 0;
+    for lpath in lpaths {
+        let rel_path = relative_path(left_roots, lpath);
+        if let Some(count) = unmatched_right
+            .get_mut(rel_path)
+            .filter(|count| **count > 0)
+        {
+            *count -= 1;
+            mirrored += 1;
+        }
+    }
+
+    MirrorCounts {
+        mirrored,
+        stray_left: lpaths.len() - mirrored,
+        stray_right: rpaths.len() - mirrored,
+    }
+}
+
+#[test]
+fn classify_mirrored_counts_stray_copies() {
+    let left_roots: Vec<OsString> = vec!["l".into()];
+    let right_roots: Vec<OsString> = vec!["r".into()];
+    let lpaths = vec![path::PathBuf::from("l/a/x"), path::PathBuf::from("l/b/x")];
+    let rpaths = vec![
+        path::PathBuf::from("r/a/x"),
+        path::PathBuf::from("r/c/x"),
+        path::PathBuf::from("r/d/x"),
+    ];
+
+    let counts = classify_mirrored(&left_roots, &lpaths, &right_roots, &rpaths);
+
+    assert_eq!(
+        (counts.mirrored, counts.stray_left, counts.stray_right),
+        (1, 1, 2)
+    );
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Locations {
+    pub left: Vec<path::PathBuf>,
+    pub both: Vec<(Vec<path::PathBuf>, Vec<path::PathBuf>)>,
+    pub right: Vec<path::PathBuf>,
+}
+
+impl Locations {
+    // Sorts every list of paths, and the 'both' locations by their first
+    // left-hand path, so that results don't depend on the order the files
+    // were found in.
+    pub fn sort(&mut self) {
+        self.left.sort_unstable();
+        self.right.sort_unstable();
+
+        for (lpaths, rpaths) in self.both.iter_mut() {
+            lpaths.sort_unstable();
+            rpaths.sort_unstable();
+        }
+
+        // The vectors are guaranteed to be non-empty, otherwise this
+        // wouldn't be a 'both' location.
+        self.both.sort_unstable_by(|(lpaths_l, _), (lpaths_r, _)| {
+            std::cmp::Ord::cmp(&lpaths_l[0], &lpaths_r[0])
+        });
+    }
+}
+
+pub fn split_into_locations(
+    mut left: DigestMap<Vec<path::PathBuf>>,
+    mut right: DigestMap<Vec<path::PathBuf>>,
+) -> Locations {
+    // When extract_if is stabalized, I think this can be replaced by that.
+    // https://github.com/rust-lang/rust/issues/59618
+    let keys_in_both: HashSet<Sha256Sum> = left
+        .keys()
+        .filter_map(|k| {
+            if right.contains_key(k) {
+                Some(*k)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let both_results: Vec<(Vec<path::PathBuf>, Vec<path::PathBuf>)> = keys_in_both
+        .iter()
+        .map(|k| {
+            // The key was present in both, so unwrapping the Option from
+            // .remove shouldn't panic.
+            let from_left = left.remove(k).unwrap();
+            let from_right = right.remove(k).unwrap();
+            (from_left, from_right)
+        })
+        .collect();
+
+    // The items present in both have already been removed, so consuming the
+    // values to create the results should yield only left/right paths.
+    let left_results: Vec<path::PathBuf> = left.into_values().flatten().collect();
+    let right_results: Vec<path::PathBuf> = right.into_values().flatten().collect();
+
+    Locations {
+        left: left_results,
+        both: both_results,
+        right: right_results,
+    }
+}
+
+#[test]
+fn split_nothing_right_only_left() {
+    let some_sha256_sum1: Sha256Sum = [1u8; 32];
+    let some_sha256_sum2: Sha256Sum = [2u8; 32];
+
+    let mut left: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    left.insert(some_sha256_sum1, vec!["lpath1".into()]);
+    left.insert(some_sha256_sum2, vec!["lpath2".into()]);
+
+    let right: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+
+    let results: Locations = split_into_locations(left, right);
+
+    assert_eq!(results.left.len(), 2);
+    assert!(results.both.is_empty());
+    assert!(results.right.is_empty());
+}
+
+#[test]
+fn split_nothing_left_only_right() {
+    let some_sha256_sum1: Sha256Sum = [1u8; 32];
+    let some_sha256_sum2: Sha256Sum = [2u8; 32];
+
+    let left: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+
+    let mut right: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    right.insert(some_sha256_sum1, vec!["rpath1".into()]);
+    right.insert(some_sha256_sum2, vec!["rpath2".into()]);
+
+    let results: Locations = split_into_locations(left, right);
+
+    assert!(results.left.is_empty());
+    assert!(results.both.is_empty());
+    assert_eq!(results.right.len(), 2);
+}
+
+#[test]
+#[allow(clippy::clone_on_copy)]
+fn split_mix_has_expected_values() {
+    let some_sha256_sum_l: Sha256Sum = [1u8; 32];
+    let some_sha256_sum_r: Sha256Sum = [2u8; 32];
+    let some_sha256_sum_b: Sha256Sum = [4u8; 32];
+
+    let mut left: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    left.insert(
+        some_sha256_sum_l,
+        vec!["lpath1_a".into(), "lpath2_a".into()],
+    );
+    left.insert(some_sha256_sum_b.clone(), vec!["bpath1_l".into()]);
+
+    let mut right: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    right.insert(some_sha256_sum_r, vec!["rpath1".into()]);
+    right.insert(
+        some_sha256_sum_b.clone(),
+        vec!["bpath1_r".into(), "bpath2_r".into()],
+    );
+
+    let mut results: Locations = split_into_locations(left, right);
+
+    results.left.sort_unstable();
+    assert_eq!(
+        results.left,
+        vec![
+            path::PathBuf::from("lpath1_a"),
+            path::PathBuf::from("lpath2_a")
+        ]
+    );
+    assert_eq!(
+        results.both,
+        vec![(
+            vec![path::PathBuf::from("bpath1_l")],
+            vec![
+                path::PathBuf::from("bpath1_r"),
+                path::PathBuf::from("bpath2_r")
+            ]
+        )]
+    );
+    assert_eq!(results.right, vec![path::PathBuf::from("rpath1")]);
+}
+
+// Builds and sorts the locations for files found in the given order.
+#[cfg(test)]
+fn locations_in_order(found: &[(bool, u8, String)]) -> Locations {
+    let mut left: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    let mut right: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    for (is_left, hash, name) in found {
+        let map = if *is_left { &mut left } else { &mut right };
+        add_to_result_hash_map(map, [*hash; 32], path::PathBuf::from(name));
+    }
+
+    let mut locations = split_into_locations(left, right);
+    locations.sort();
+    locations
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn locations_dont_depend_on_discovery_order(
+        (found, shuffled) in prop::collection::hash_map("[a-e]{1,3}", (any::<bool>(), 0u8..4), 0..20)
+            .prop_map(|files| {
+                files
+                    .into_iter()
+                    .map(|(name, (is_left, hash))| (is_left, hash, name))
+                    .collect::<Vec<_>>()
+            })
+            .prop_flat_map(|found| (Just(found.clone()), Just(found).prop_shuffle()))
+    ) {
+        prop_assert_eq!(locations_in_order(&found), locations_in_order(&shuffled));
+    }
+
+    #[test]
+    fn rel_path_map_doesnt_depend_on_discovery_order(
+        (found, shuffled) in prop::collection::vec(("[a-c]", "[x-z]"), 0..10)
+            .prop_flat_map(|found| (Just(found.clone()), Just(found).prop_shuffle()))
+    ) {
+        let hashed = HashedFile { hash: [0; 32], size: Some(0), modified: None, file_id: None };
+        let build = |found: &[(String, String)]| {
+            let mut map = HashMap::new();
+            for (root, rel_path) in found {
+                let path = path::Path::new(root).join(rel_path);
+                add_to_rel_path_map(&mut map, rel_path.into(), path, hashed);
+            }
+            let mut entries: Vec<(path::PathBuf, path::PathBuf)> =
+                map.into_iter().map(|(rel_path, (path, _))| (rel_path, path)).collect();
+            entries.sort_unstable();
+            entries
+        };
+
+        prop_assert_eq!(build(&found), build(&shuffled));
+    }
+}
+
+#[test]
+fn results_dont_depend_on_discovery_order() {
+    let found: Vec<(bool, u8, String)> = [
+        (true, 1, "b"),
+        (true, 1, "a"),
+        (false, 1, "d"),
+        (false, 1, "c"),
+        (true, 2, "e"),
+        (false, 3, "f"),
+    ]
+    .into_iter()
+    .map(|(is_left, hash, name)| (is_left, hash, name.to_string()))
+    .collect();
+    let reversed: Vec<_> = found.iter().rev().cloned().collect();
+    assert_eq!(locations_in_order(&found), locations_in_order(&reversed));
+
+    // Overlapping roots that both have "x" keep the smallest path, either way round.
+    let hashed = HashedFile {
+        hash: [0; 32],
+        size: Some(0),
+        modified: None,
+        file_id: None,
+    };
+    for roots in [["a", "b"], ["b", "a"]] {
+        let mut map = HashMap::new();
+        for root in roots {
+            let path = path::Path::new(root).join("x");
+            add_to_rel_path_map(&mut map, "x".into(), path, hashed);
+        }
+        assert_eq!(map[path::Path::new("x")].0, path::PathBuf::from("a/x"));
+    }
+}
+
+// Moves the 'both' locations whose paths are all hard links to the same
+// file out of the locations and returns them. They're one file reachable
+// from both sides rather than copies of it. Locations that mix links and
+// copies are left alone.
+pub fn take_hardlinked(
+    locations: &mut Locations,
+    file_ids: &HashMap<path::PathBuf, FileId>,
+) -> Vec<(Vec<path::PathBuf>, Vec<path::PathBuf>)> {
+    let (hardlinked, both) =
+        std::mem::take(&mut locations.both)
+            .into_iter()
+            .partition(|(lpaths, rpaths)| {
+                let mut ids = lpaths.iter().chain(rpaths).map(|path| file_ids.get(path));
+                match ids.next() {
+                    Some(Some(first)) => ids.all(|id| id == Some(first)),
+                    _ => false,
+                }
+            });
+    locations.both = both;
+    hardlinked
+}
+
+#[test]
+fn take_hardlinked_only_takes_locations_of_one_file() {
+    let paths = |names: &[&str]| -> Vec<path::PathBuf> { names.iter().map(Into::into).collect() };
+    let mut locations = Locations {
+        left: Vec::new(),
+        both: vec![
+            (paths(&["l/linked"]), paths(&["r/linked"])),
+            (paths(&["l/mixed"]), paths(&["r/mixed", "r/copy"])),
+            (paths(&["l/copy"]), paths(&["r/copy2"])),
+        ],
+        right: Vec::new(),
+    };
+    let file_ids = HashMap::from([
+        (path::PathBuf::from("l/linked"), (1, 1)),
+        (path::PathBuf::from("r/linked"), (1, 1)),
+        (path::PathBuf::from("l/mixed"), (1, 2)),
+        (path::PathBuf::from("r/mixed"), (1, 2)),
+    ]);
+
+    let hardlinked = take_hardlinked(&mut locations, &file_ids);
+
+    assert_eq!(
+        hardlinked,
+        vec![(paths(&["l/linked"]), paths(&["r/linked"]))]
+    );
+    assert_eq!(locations.both.len(), 2);
+}
+
+// Compares the files in each 'both' location byte for byte, instead of
+// trusting that files with the same hash are identical. Files that turn
+// out to differ from the rest of their location are split off into a
+// location of their own, which can leave them only on one side. Files
+// that can't be read are left out, and returned as errors sorted by path.
+pub fn verify_both_byte_by_byte(locations: &mut Locations, num_threads: usize) -> Vec<WorkResult> {
+    let both = std::mem::take(&mut locations.both);
+    let chunk_size = both.len().div_ceil(num_threads).max(1);
+
+    let (verified, errors): (Vec<Vec<SidePaths>>, Vec<Vec<WorkResult>>) = thread::scope(|scope| {
+        let threads: Vec<_> = both
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(lpaths, rpaths)| split_by_contents(lpaths, rpaths))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .unzip()
+    });
+
+    for (lpaths, rpaths) in verified.into_iter().flatten() {
+        match (lpaths.is_empty(), rpaths.is_empty()) {
+            (false, false) => locations.both.push((lpaths, rpaths)),
+            (false, true) => locations.left.extend(lpaths),
+            (true, false) => locations.right.extend(rpaths),
+            (true, true) => {}
+        }
+    }
+
+    locations.sort();
+    let mut errors: Vec<WorkResult> = errors.into_iter().flatten().collect();
+    errors.sort_by(|lhs, rhs| lhs.path.path().cmp(rhs.path.path()));
+    errors
+}
+
+// The left-hand and right-hand paths of a location in 'both'.
+type SidePaths = (Vec<path::PathBuf>, Vec<path::PathBuf>);
+
+// A group of files with the same bytes, while they're being compared.
+struct ContentGroup {
+    // The file the others are compared with. It's one of the group's
+    // paths, until it can't be read.
+    first: PathLocation,
+    // Whether first has been read to the end.
+    read: bool,
+    lpaths: Vec<path::PathBuf>,
+    rpaths: Vec<path::PathBuf>,
+}
+
+impl ContentGroup {
+    fn new(path: PathLocation) -> ContentGroup {
+        let mut group = ContentGroup {
+            first: path.clone(),
+            read: false,
+            lpaths: Vec::new(),
+            rpaths: Vec::new(),
+        };
+        group.add(path);
+        group
+    }
+
+    fn add(&mut self, path: PathLocation) {
+        match path {
+            PathLocation::Left(path) => self.lpaths.push(path),
+            PathLocation::Right(path) => self.rpaths.push(path),
+        }
+    }
+
+    // Takes first out of the group, and makes another of its files the one
+    // to compare with. Returns false if there are none left.
+    fn drop_first(&mut self) -> bool {
+        let first = self.first.path();
+        self.lpaths.retain(|path| path != first);
+        self.rpaths.retain(|path| path != first);
+        match (self.lpaths.first(), self.rpaths.first()) {
+            (Some(lpath), _) => self.first = PathLocation::new_left(lpath),
+            (None, Some(rpath)) => self.first = PathLocation::new_right(rpath),
+            (None, None) => return false,
+        }
+        self.read = false;
+        true
+    }
+}
+
+// Splits the paths of a location into groups of files with the same bytes.
+// Files that can't be read aren't put in a group, and are returned as
+// errors instead.
+fn split_by_contents(
+    lpaths: &[path::PathBuf],
+    rpaths: &[path::PathBuf],
+) -> (Vec<SidePaths>, Vec<WorkResult>) {
+    let mut groups: Vec<ContentGroup> = Vec::new();
+    let mut errors = Vec::new();
+    let unreadable = |path: PathLocation, e: io::Error| WorkResult {
+        path,
+        result: Err(e),
+        is_dir: false,
+    };
+
+    let paths = lpaths
+        .iter()
+        .map(PathLocation::new_left)
+        .chain(rpaths.iter().map(PathLocation::new_right));
+    'paths: for path in paths {
+        let mut i = 0;
+        while i < groups.len() {
+            let group = &mut groups[i];
+            match same_contents(group.first.path(), path.path()) {
+                Ok(true) => {
+                    group.read = true;
+                    group.add(path);
+                    continue 'paths;
+                }
+                Ok(false) => {
+                    eprintln!(
+                        "WARN: '{}' has the same hash as '{}', but different content.",
+                        path.path().display(),
+                        group.first.path().display()
+                    );
+                    i += 1;
+                }
+                Err(Unreadable::Rhs(e)) => {
+                    errors.push(unreadable(path, e));
+                    continue 'paths;
+                }
+                // The rest of the group is compared with another of its
+                // files from now on.
+                Err(Unreadable::Lhs(e)) => {
+                    errors.push(unreadable(group.first.clone(), e));
+                    if !group.drop_first() {
+                        groups.remove(i);
+                    }
+                }
+            }
+        }
+        groups.push(ContentGroup::new(path));
+    }
+
+    // A file that wasn't the same as any other hasn't been read yet.
+    groups.retain(|group| {
+        if group.read {
+            return true;
+        }
+        let read = fs::File::open(group.first.path())
+            .and_then(|mut file| io::copy(&mut file, &mut io::sink()));
+        match read {
+            Ok(_) => true,
+            Err(e) => {
+                errors.push(unreadable(group.first.clone(), e));
+                false
+            }
+        }
+    });
+
+    let groups = groups
+        .into_iter()
+        .map(|group| (group.lpaths, group.rpaths))
+        .collect();
+    (groups, errors)
+}
+
+// Which of the two files being compared couldn't be read.
+enum Unreadable {
+    Lhs(io::Error),
+    Rhs(io::Error),
+}
+
+fn same_contents(lhs: &path::Path, rhs: &path::Path) -> Result<bool, Unreadable> {
+    let lhs = fs::File::open(lhs).map_err(Unreadable::Lhs)?;
+    let rhs = fs::File::open(rhs).map_err(Unreadable::Rhs)?;
+    let lsize = lhs.metadata().map_err(Unreadable::Lhs)?.len();
+    if lsize != rhs.metadata().map_err(Unreadable::Rhs)?.len() {
+        return Ok(false);
+    }
+
+    let mut lhs = io::BufReader::with_capacity(64 * 1024, lhs);
+    let mut rhs = io::BufReader::with_capacity(64 * 1024, rhs);
+    loop {
+        let lbuf = lhs.fill_buf().map_err(Unreadable::Lhs)?;
+        let rbuf = rhs.fill_buf().map_err(Unreadable::Rhs)?;
+        if lbuf.is_empty() || rbuf.is_empty() {
+            return Ok(lbuf.is_empty() && rbuf.is_empty());
+        }
+
+        let len = lbuf.len().min(rbuf.len());
+        if lbuf[..len] != rbuf[..len] {
+            return Ok(false);
+        }
+        lhs.consume(len);
+        rhs.consume(len);
+    }
+}
+
+#[test]
+fn split_by_contents_separates_different_files() {
+    let manifest_dir = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+    let cargo_toml = manifest_dir.join("Cargo.toml");
+    let license = manifest_dir.join("LICENSE");
+
+    let (groups, errors) = split_by_contents(
+        std::slice::from_ref(&cargo_toml),
+        &[cargo_toml.clone(), license.clone()],
+    );
+
+    assert_eq!(
+        groups,
+        vec![
+            (vec![cargo_toml.clone()], vec![cargo_toml]),
+            (vec![], vec![license])
+        ]
+    );
+    assert!(errors.is_empty());
+}
+
+#[test]
+fn unreadable_files_are_errors_rather_than_differences() {
+    let root = TempDir::new("unreadable");
+    for name in ["a", "b", "c"] {
+        fs::write(root.join(name), "same").unwrap();
+    }
+    let (a, b, c, missing) = (
+        root.join("a"),
+        root.join("b"),
+        root.join("c"),
+        root.join("missing"),
+    );
+
+    // The first file can't be read, so the rest are compared with the next.
+    let mut locations = Locations {
+        left: Vec::new(),
+        both: vec![(vec![missing.clone(), a.clone()], vec![b.clone(), c.clone()])],
+        right: Vec::new(),
+    };
+    let errors = verify_both_byte_by_byte(&mut locations, 1);
+    assert_eq!(
+        locations.both,
+        vec![(vec![a.clone()], vec![b.clone(), c.clone()])]
+    );
+    assert!(locations.left.is_empty() && locations.right.is_empty());
+    let error_paths: Vec<&path::Path> = errors.iter().map(|e| e.path.path()).collect();
+    assert_eq!(error_paths, vec![missing.as_path()]);
+
+    // Without a copy that could be read, the other file is only on its
+    // side, but the unreadable one isn't reported on the other.
+    let mut locations = Locations {
+        left: Vec::new(),
+        both: vec![(vec![a.clone()], vec![missing.clone()])],
+        right: Vec::new(),
+    };
+    let errors = verify_both_byte_by_byte(&mut locations, 1);
+    assert_eq!(locations.left, vec![a]);
+    assert!(locations.both.is_empty() && locations.right.is_empty());
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].path, PathLocation::Right(_)));
+}
+
+// The most digests --cross-check samples. Every file with a sampled digest
+// is read again, once for each algorithm.
+const CROSS_CHECK_SAMPLE: usize = 1000;
+
+// Picks up to CROSS_CHECK_SAMPLE of the digests found on either side,
+// spread evenly over them in digest order so that the same files always
+// give the same sample, and returns the paths of every file with one.
+pub fn sample_for_cross_check(
+    left: &DigestMap<Vec<path::PathBuf>>,
+    right: &DigestMap<Vec<path::PathBuf>>,
+) -> Vec<path::PathBuf> {
+    let mut digests: Vec<&Sha256Sum> = left
+        .keys()
+        .chain(right.keys().filter(|hash| !left.contains_key(*hash)))
+        .collect();
+    digests.sort_unstable();
+    let step = digests.len().div_ceil(CROSS_CHECK_SAMPLE).max(1);
+
+    digests
+        .into_iter()
+        .step_by(step)
+        .flat_map(|hash| left.get(hash).into_iter().chain(right.get(hash)).flatten())
+        .cloned()
+        .collect()
+}
+
+// Two sampled files that one algorithm gives the same digest and another
+// doesn't.
+#[derive(Debug, PartialEq)]
+pub struct Disagreement {
+    pub path: path::PathBuf,
+    pub other: path::PathBuf,
+    pub grouped_by: HashAlgorithm,
+    pub split_by: HashAlgorithm,
+}
+
+// Hashes each file with every algorithm, returning how many files were
+// hashed and the ones the algorithms group differently. Files that can't
+// be read are reported and left out.
+pub fn cross_check(
+    paths: &[path::PathBuf],
+    algorithms: &[HashAlgorithm],
+    num_threads: usize,
+) -> (usize, Vec<Disagreement>) {
+    let chunk_size = paths.len().div_ceil(num_threads).max(1);
+
+    let digests: Vec<(&path::Path, Vec<Vec<u8>>)> = thread::scope(|scope| {
+        let threads: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .filter_map(|path| {
+                            let digests = algorithms
+                                .iter()
+                                .map(|algorithm| algorithm.hash(fs::File::open(path)?))
+                                .collect::<io::Result<Vec<_>>>();
+                            match digests {
+                                Ok(digests) => Some((path.as_path(), digests)),
+                                Err(e) => {
+                                    eprintln!(
+                                        "WARN: unable to cross-check '{}': {}",
+                                        path.display(),
+                                        e
+                                    );
+                                    None
+                                }
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect()
+    });
+
+    (digests.len(), find_disagreements(&digests, algorithms))
+}
+
+// Compares each file with the first one found that an algorithm gives the
+// same digest, and returns the pairs that another algorithm doesn't.
+fn find_disagreements(
+    digests: &[(&path::Path, Vec<Vec<u8>>)],
+    algorithms: &[HashAlgorithm],
+) -> Vec<Disagreement> {
+    let mut disagreements = Vec::new();
+    for (i, grouped_by) in algorithms.iter().enumerate() {
+        let mut first_with: HashMap<&[u8], usize> = HashMap::new();
+        for (index, (path, file_digests)) in digests.iter().enumerate() {
+            let first = *first_with.entry(&file_digests[i]).or_insert(index);
+            let (other, other_digests) = &digests[first];
+            for (j, split_by) in algorithms.iter().enumerate() {
+                if file_digests[j] != other_digests[j] {
+                    disagreements.push(Disagreement {
+                        path: path.to_path_buf(),
+                        other: other.to_path_buf(),
+                        grouped_by: *grouped_by,
+                        split_by: *split_by,
+                    });
+                }
+            }
+        }
+    }
+    disagreements
+}
+
+#[test]
+fn cross_check_finds_grouping_disagreements() {
+    let algorithms = [HashAlgorithm::Sha256, HashAlgorithm::Blake3];
+    let digests: Vec<(&path::Path, Vec<Vec<u8>>)> = [("a", 1, 1), ("b", 1, 2), ("c", 2, 2)]
+        .into_iter()
+        .map(|(name, sha256, blake3)| (path::Path::new(name), vec![vec![sha256], vec![blake3]]))
+        .collect();
+
+    assert_eq!(
+        find_disagreements(&digests, &algorithms),
+        vec![
+            Disagreement {
+                path: path::PathBuf::from("b"),
+                other: path::PathBuf::from("a"),
+                grouped_by: HashAlgorithm::Sha256,
+                split_by: HashAlgorithm::Blake3,
+            },
+            Disagreement {
+                path: path::PathBuf::from("c"),
+                other: path::PathBuf::from("b"),
+                grouped_by: HashAlgorithm::Blake3,
+                split_by: HashAlgorithm::Sha256,
+            },
+        ]
+    );
+}
+
+#[test]
+fn cross_check_agrees_on_real_files() {
+    let root = TempDir::new("cross-check");
+    for (name, contents) in [("a", "same"), ("b", "same"), ("c", "diff")] {
+        fs::write(root.join(name), contents).unwrap();
+    }
+    let paths: Vec<path::PathBuf> = ["a", "b", "c", "missing"]
+        .iter()
+        .map(|name| root.join(name))
+        .collect();
+
+    let (checked, disagreements) =
+        cross_check(&paths, &[HashAlgorithm::Sha256, HashAlgorithm::Blake3], 2);
+
+    assert_eq!(checked, 3);
+    assert!(disagreements.is_empty());
+}
+
+// Returns the groups of paths that share a hash within one side, each
+// sorted, and sorted by their first path. Groups whose paths are all hard
+// links to the same file are left out, as they're one file rather than
+// copies of it.
+pub fn duplicates_within(
+    files: DigestMap<Vec<path::PathBuf>>,
+    file_ids: &HashMap<path::PathBuf, FileId>,
+) -> Vec<Vec<path::PathBuf>> {
+    let mut groups: Vec<Vec<path::PathBuf>> = files
+        .into_values()
+        .filter(|paths| {
+            let first = file_ids.get(&paths[0]);
+            paths.len() > 1
+                && (first.is_none() || paths.iter().any(|path| file_ids.get(path) != first))
+        })
+        .map(|mut paths| {
+            paths.sort_unstable();
+            paths
+        })
+        .collect();
+
+    groups.sort_unstable_by(|lhs, rhs| lhs[0].cmp(&rhs[0]));
+    groups
+}
+
+#[test]
+fn duplicates_within_groups_copies() {
+    let mut files: DigestMap<Vec<path::PathBuf>> = DigestMap::default();
+    files.insert([1; 32], vec!["b/copy".into(), "a/copy".into()]);
+    files.insert([2; 32], vec!["unique".into()]);
+    files.insert([3; 32], vec!["link1".into(), "link2".into()]);
+
+    let file_ids = HashMap::from([
+        (path::PathBuf::from("link1"), (1, 7)),
+        (path::PathBuf::from("link2"), (1, 7)),
+    ]);
+
+    assert_eq!(
+        duplicates_within(files, &file_ids),
+        vec![vec![
+            path::PathBuf::from("a/copy"),
+            path::PathBuf::from("b/copy")
+        ]]
+    );
+}
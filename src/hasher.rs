@@ -0,0 +1,686 @@
+// Copyright 2024, G. Christopher Warrington <code@cw.codes>
+//
+// find-dups is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License Version 3 as
+// published by the Free Software Foundation.
+//
+// find-dups is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public
+// License for more details.
+//
+// A copy of the GNU Affero General Public License Version 3 is included in
+// the file LICENSE in the root of the repository.
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path;
+use std::str::FromStr;
+
+pub type Sha256Sum = [u8; 32];
+
+// How file contents are transformed before they're hashed. With the
+// defaults, the hash is of the contents as they are.
+#[derive(Clone, Default)]
+pub struct HashOptions {
+    pub normalize_encoding: bool,
+    pub normalize_whitespace: Option<Indentation>,
+    pub structured_formats: Vec<StructuredFormat>,
+    // Only hash the size and the first and last PARTIAL_HASH_WINDOW bytes
+    // of files big enough for that to skip anything. Meant for a first
+    // pass that finds which files could be identical, which is why it
+    // can't be combined with the other options.
+    pub partial: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum StructuredFormat {
+    Json,
+    Yaml,
+    #[cfg(feature = "documents")]
+    Docx,
+    #[cfg(feature = "documents")]
+    Pdf,
+}
+
+// Structured files are parsed in memory, so really big ones are left alone.
+pub(crate) const MAX_STRUCTURED_FILE_LEN: u64 = 64 * 1024 * 1024;
+
+// Hashes the canonical form of a structured file. For data formats, that's
+// JSON with object keys sorted and no insignificant whitespace. Both
+// formats end up in the same form, so a YAML file and a JSON file with the
+// same data are identical too. For documents, it's their text with runs
+// of whitespace collapsed to a single space.
+pub(crate) fn hash_structured_contents<R: io::Read>(
+    mut reader: R,
+    format: StructuredFormat,
+    hash_options: &HashOptions,
+) -> io::Result<Sha256Sum> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+
+    let canonical: Option<Vec<u8>> = match format {
+        StructuredFormat::Json => serde_json::from_slice::<serde_json::Value>(&contents)
+            .ok()
+            .and_then(|value| serde_json::to_vec(&value).ok()),
+        StructuredFormat::Yaml => serde_yaml::from_slice::<serde_json::Value>(&contents)
+            .ok()
+            .and_then(|value| serde_json::to_vec(&value).ok()),
+        #[cfg(feature = "documents")]
+        StructuredFormat::Docx => docx_text(&contents).map(|text| collapse_whitespace(&text)),
+        #[cfg(feature = "documents")]
+        StructuredFormat::Pdf => pdf_text(&contents).map(|text| collapse_whitespace(&text)),
+    };
+
+    match canonical {
+        Some(canonical) => Ok(Sha256::digest(canonical).into()),
+        None => hash_contents(&contents[..], hash_options),
+    }
+}
+
+#[cfg(feature = "documents")]
+fn collapse_whitespace(text: &str) -> Vec<u8> {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .into_bytes()
+}
+
+// The text of a Word document is in the <w:t> elements of its main part.
+// Everything else in the package, like the properties with the last saved
+// time, is ignored.
+#[cfg(feature = "documents")]
+fn docx_text(contents: &[u8]) -> Option<String> {
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(contents)).ok()?;
+    let mut document = String::new();
+    io::Read::read_to_string(
+        &mut archive.by_name("word/document.xml").ok()?,
+        &mut document,
+    )
+    .ok()?;
+
+    let mut text = String::new();
+    let mut rest = document.as_str();
+    while let Some(start) = rest.find("<w:t") {
+        rest = &rest[start + 4..];
+
+        // Skip over other elements that start with "w:t", like <w:tab/>.
+        if !rest.starts_with('>') && !rest.starts_with(' ') {
+            continue;
+        }
+
+        let (Some(open_end), Some(close)) = (rest.find('>'), rest.find("</w:t>")) else {
+            break;
+        };
+        if open_end < close {
+            text.push_str(&unescape_xml(&rest[open_end + 1..close]));
+            text.push(' ');
+        }
+        rest = &rest[close..];
+    }
+
+    Some(text)
+}
+
+#[cfg(feature = "documents")]
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(feature = "documents")]
+fn pdf_text(contents: &[u8]) -> Option<String> {
+    // The PDF parser panics on some malformed files. Those are hashed as
+    // they are, like any other file that fails to parse, rather than
+    // taking the worker thread down.
+    std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(contents).ok())
+        .ok()
+        .flatten()
+}
+
+#[cfg(feature = "documents")]
+#[test]
+fn docx_text_ignores_markup_and_metadata() {
+    let docx = |document_xml: &str, core_xml: &str| {
+        let mut buf = io::Cursor::new(Vec::new());
+        let mut writer = zip::ZipWriter::new(&mut buf);
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("word/document.xml", options).unwrap();
+        io::Write::write_all(&mut writer, document_xml.as_bytes()).unwrap();
+        writer.start_file("docProps/core.xml", options).unwrap();
+        io::Write::write_all(&mut writer, core_xml.as_bytes()).unwrap();
+        writer.finish().unwrap();
+        buf.into_inner()
+    };
+
+    let first = docx(
+        r#"<w:body><w:p><w:r><w:t>Fish &amp;</w:t><w:tab/><w:t xml:space="preserve"> chips</w:t></w:r></w:p></w:body>"#,
+        "<modified>2024-01-01</modified>",
+    );
+    let resaved = docx(
+        r#"<w:body><w:p><w:r w:rsidR="1"><w:t>Fish &amp;</w:t></w:r><w:r><w:t>chips</w:t></w:r></w:p></w:body>"#,
+        "<modified>2024-06-01</modified>",
+    );
+
+    let hash = |contents: &[u8]| {
+        hash_structured_contents(contents, StructuredFormat::Docx, &HashOptions::default()).unwrap()
+    };
+
+    assert_eq!(
+        collapse_whitespace(&docx_text(&first).unwrap()),
+        b"Fish & chips"
+    );
+    assert_eq!(hash(&first), hash(&resaved));
+}
+
+#[test]
+fn normalize_structured_ignores_key_order_and_format() {
+    let hash = |text: &str, format: StructuredFormat| {
+        hash_structured_contents(text.as_bytes(), format, &HashOptions::default()).unwrap()
+    };
+
+    let json = hash(
+        r#"{"b": [1, 2], "a": {"y": null, "x": "s"}}"#,
+        StructuredFormat::Json,
+    );
+
+    assert_eq!(
+        hash(
+            "{\n  \"a\": {\"x\": \"s\", \"y\": null},\n  \"b\": [1,2]\n}\n",
+            StructuredFormat::Json
+        ),
+        json
+    );
+    assert_eq!(
+        hash(
+            "a:\n  x: s\n  y: ~\nb:\n  - 1\n  - 2\n",
+            StructuredFormat::Yaml
+        ),
+        json
+    );
+    assert_ne!(
+        hash(r#"{"b": [2, 1], "a": {}}"#, StructuredFormat::Json),
+        json
+    );
+
+    let broken = "{not json";
+    assert_eq!(
+        hash(broken, StructuredFormat::Json),
+        hash_contents(broken.as_bytes(), &HashOptions::default()).unwrap()
+    );
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Indentation {
+    Keep,
+    Ignore,
+    ExpandTabs(NonZeroUsize),
+}
+
+// The algorithms --cross-check can compare. Everything else is hashed
+// with SHA256.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    // Hashes the contents as they are, without any of HashOptions.
+    pub fn hash<R: io::Read>(self, mut reader: R) -> io::Result<Vec<u8>> {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut reader, &mut hasher)?;
+                Ok(hasher.finalize().to_vec())
+            }
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(&mut reader, &mut hasher)?;
+                Ok(hasher.finalize().as_bytes().to_vec())
+            }
+        }
+    }
+}
+
+#[test]
+fn hash_algorithms_have_expected_digests() {
+    let digest = |algorithm: HashAlgorithm| hex::encode(algorithm.hash(&b"abc"[..]).unwrap());
+
+    assert_eq!(
+        digest(HashAlgorithm::Sha256),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        digest(HashAlgorithm::Blake3),
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    );
+    assert_eq!(
+        HashAlgorithm::from_str("blake3", false),
+        Ok(HashAlgorithm::Blake3)
+    );
+}
+
+pub const PARTIAL_HASH_WINDOW: u64 = 64 * 1024;
+
+// Whether a file of this size is only partially hashed with
+// HashOptions::partial. Smaller files are hashed in full, as reading their
+// middle too costs next to nothing.
+pub fn is_partially_hashed(size: u64) -> bool {
+    size > 2 * PARTIAL_HASH_WINDOW
+}
+
+// Hashes the size of the contents and their first and last
+// PARTIAL_HASH_WINDOW bytes. Contents with different partial hashes are
+// different, but contents with the same partial hash may still differ in
+// the middle.
+pub(crate) fn hash_partial_contents<R: io::Read + io::Seek>(
+    mut reader: R,
+    size: u64,
+) -> io::Result<Sha256Sum> {
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+
+    io::copy(
+        &mut io::Read::take(&mut reader, PARTIAL_HASH_WINDOW),
+        &mut hasher,
+    )?;
+    reader.seek(io::SeekFrom::Start(size - PARTIAL_HASH_WINDOW))?;
+    io::copy(&mut reader.take(PARTIAL_HASH_WINDOW), &mut hasher)?;
+
+    Ok(hasher.finalize().into())
+}
+
+#[test]
+fn partial_hash_only_covers_the_ends() {
+    let len = 3 * PARTIAL_HASH_WINDOW as usize;
+    let original = vec![0u8; len];
+    let mut middle_changed = original.clone();
+    middle_changed[len / 2] = 1;
+    let mut end_changed = original.clone();
+    end_changed[len - 1] = 1;
+
+    let partial_hash = |contents: &Vec<u8>| {
+        hash_partial_contents(io::Cursor::new(contents), contents.len() as u64).unwrap()
+    };
+
+    assert_eq!(partial_hash(&original), partial_hash(&middle_changed));
+    assert_ne!(partial_hash(&original), partial_hash(&end_changed));
+    assert!(is_partially_hashed(len as u64));
+    assert!(!is_partially_hashed(2 * PARTIAL_HASH_WINDOW));
+}
+
+pub fn hash_contents<R: io::Read>(reader: R, hash_options: &HashOptions) -> io::Result<Sha256Sum> {
+    let mut hasher = Sha256::new();
+
+    match hash_options.normalize_whitespace {
+        None => copy_contents(reader, &mut hasher, hash_options)?,
+        Some(indentation) => {
+            let mut normalizer = WhitespaceNormalizer::new(&mut hasher, indentation);
+            copy_contents(reader, &mut normalizer, hash_options)?;
+            normalizer.finish()?;
+        }
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+fn copy_contents<R: io::Read, W: io::Write>(
+    mut reader: R,
+    writer: &mut W,
+    hash_options: &HashOptions,
+) -> io::Result<()> {
+    if hash_options.normalize_encoding {
+        copy_as_utf8(reader, writer)
+    } else {
+        io::copy(&mut reader, writer).map(|_| ())
+    }
+}
+
+// Rewrites text line by line as it's written, removing trailing whitespace
+// from each line and blank lines from the end. Lines are joined with '\n',
+// so line ending differences go away too.
+//
+// The first BINARY_SNIFF_LEN bytes are held back to decide whether the
+// contents are text at all. If they contain a NUL byte, like git, the
+// contents are assumed to be binary and are passed through untouched.
+struct WhitespaceNormalizer<'a, W: io::Write> {
+    inner: &'a mut W,
+    indentation: Indentation,
+    is_binary: Option<bool>,
+    line: Vec<u8>,
+    wrote_first_line: bool,
+    owed_newlines: usize,
+}
+
+const BINARY_SNIFF_LEN: usize = 8000;
+
+impl<'a, W: io::Write> WhitespaceNormalizer<'a, W> {
+    fn new(inner: &'a mut W, indentation: Indentation) -> Self {
+        WhitespaceNormalizer {
+            inner,
+            indentation,
+            is_binary: None,
+            line: Vec::new(),
+            wrote_first_line: false,
+            owed_newlines: 0,
+        }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        match self.is_binary {
+            Some(true) => Ok(()),
+            Some(false) => self.finish_line(),
+            None => {
+                let held_back = std::mem::take(&mut self.line);
+                self.decide_and_write(&held_back)?;
+                if self.is_binary == Some(false) {
+                    self.finish_line()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn decide_and_write(&mut self, held_back: &[u8]) -> io::Result<()> {
+        if held_back.contains(&0) {
+            self.is_binary = Some(true);
+            self.inner.write_all(held_back)
+        } else {
+            self.is_binary = Some(false);
+            self.write_text(held_back)
+        }
+    }
+
+    fn write_text(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut rest = buf;
+        while let Some(newline) = rest.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&rest[..newline]);
+            self.finish_line()?;
+            rest = &rest[newline + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(())
+    }
+
+    fn finish_line(&mut self) -> io::Result<()> {
+        let line = std::mem::take(&mut self.line);
+        let trimmed_len = line
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        let line = &line[..trimmed_len];
+
+        // Every line but the first is preceded by a newline. They're only
+        // written once a non-blank line follows them, which is what drops
+        // the blank lines at the end.
+        if self.wrote_first_line {
+            self.owed_newlines += 1;
+        }
+        self.wrote_first_line = true;
+
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        for _ in 0..self.owed_newlines {
+            self.inner.write_all(b"\n")?;
+        }
+        self.owed_newlines = 0;
+
+        let indent_len = line
+            .iter()
+            .position(|&b| b != b' ' && b != b'\t')
+            .unwrap_or(line.len());
+        let (indent, text) = line.split_at(indent_len);
+
+        match self.indentation {
+            Indentation::Keep => self.inner.write_all(indent)?,
+            Indentation::Ignore => (),
+            Indentation::ExpandTabs(tab_width) => {
+                let tab_width = tab_width.get();
+                let columns = indent.iter().fold(0, |column, &b| match b {
+                    b'\t' => (column / tab_width + 1) * tab_width,
+                    _ => column + 1,
+                });
+                self.inner.write_all(&b" ".repeat(columns))?;
+            }
+        }
+
+        self.inner.write_all(text)
+    }
+}
+
+impl<W: io::Write> io::Write for WhitespaceNormalizer<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.is_binary {
+            Some(true) => self.inner.write_all(buf)?,
+            Some(false) => self.write_text(buf)?,
+            None => {
+                self.line.extend_from_slice(buf);
+                if self.line.len() >= BINARY_SNIFF_LEN {
+                    let held_back = std::mem::take(&mut self.line);
+                    self.decide_and_write(&held_back)?;
+                }
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn normalize_whitespace_ignores_formatting_differences() {
+    let hash = |text: &[u8], indentation: Indentation| {
+        let options = HashOptions {
+            normalize_whitespace: Some(indentation),
+            ..HashOptions::default()
+        };
+        hash_contents(text, &options).unwrap()
+    };
+
+    let plain = b"\nfn main() {\n    go();\n\n    stop();\n}";
+    let trailing = b"\nfn main() {  \r\n    go();\t\r\n\r\n    stop();\r\n}\r\n\r\n\r\n";
+    let tabs = b"\nfn main() {\n\tgo();\n\n  \tstop();\n}\n";
+    let two_spaces = b"\nfn main() {\n  go();\n\n  stop();\n}\n";
+
+    let keep = hash(plain, Indentation::Keep);
+    assert_eq!(hash(trailing, Indentation::Keep), keep);
+    assert_ne!(hash(tabs, Indentation::Keep), keep);
+
+    let expand = hash(
+        plain,
+        Indentation::ExpandTabs(NonZeroUsize::new(4).unwrap()),
+    );
+    assert_eq!(
+        hash(tabs, Indentation::ExpandTabs(NonZeroUsize::new(4).unwrap())),
+        expand
+    );
+
+    let ignore = hash(plain, Indentation::Ignore);
+    assert_eq!(hash(two_spaces, Indentation::Ignore), ignore);
+    assert_ne!(hash(b"fn main() {", Indentation::Ignore), ignore);
+
+    let binary = b"\0trailing   \n";
+    assert_eq!(
+        hash(binary, Indentation::Keep),
+        hash_contents(&binary[..], &HashOptions::default()).unwrap()
+    );
+}
+
+// Copies text that starts with a UTF-8 or UTF-16 byte order mark as UTF-8
+// without a byte order mark. Anything else is copied unchanged.
+fn copy_as_utf8<R: io::Read, W: io::Write>(mut reader: R, writer: &mut W) -> io::Result<()> {
+    let mut bom = [0u8; 3];
+    let mut bom_len = 0;
+    while bom_len < bom.len() {
+        match reader.read(&mut bom[bom_len..]) {
+            Ok(0) => break,
+            Ok(n) => bom_len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let bom = &bom[..bom_len];
+
+    match bom {
+        [0xEF, 0xBB, 0xBF] => io::copy(&mut reader, writer).map(|_| ()),
+        [0xFF, 0xFE, rest @ ..] => {
+            copy_utf16_as_utf8(io::Read::chain(rest, reader), writer, u16::from_le_bytes)
+        }
+        [0xFE, 0xFF, rest @ ..] => {
+            copy_utf16_as_utf8(io::Read::chain(rest, reader), writer, u16::from_be_bytes)
+        }
+        _ => io::copy(&mut io::Read::chain(bom, reader), writer).map(|_| ()),
+    }
+}
+
+fn copy_utf16_as_utf8<R, W, F>(mut reader: R, writer: &mut W, to_unit: F) -> io::Result<()>
+where
+    R: io::Read,
+    W: io::Write,
+    F: Fn([u8; 2]) -> u16,
+{
+    let mut buf = vec![0u8; 64 * 1024];
+    // Bytes left over from the previous read that don't form a complete
+    // character yet: an odd byte, or the first half of a surrogate pair.
+    let mut pending: Vec<u8> = Vec::with_capacity(4);
+    let mut text = String::with_capacity(buf.len());
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        pending.extend_from_slice(&buf[..n]);
+        let at_end = n == 0;
+
+        let mut units: Vec<u16> = pending
+            .chunks_exact(2)
+            .map(|pair| to_unit([pair[0], pair[1]]))
+            .collect();
+        let mut consumed = units.len() * 2;
+
+        if !at_end && units.last().is_some_and(|u| (0xD800..0xDC00).contains(u)) {
+            units.pop();
+            consumed -= 2;
+        }
+
+        text.clear();
+        text.extend(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)));
+        if at_end && consumed < pending.len() {
+            text.push(char::REPLACEMENT_CHARACTER);
+            consumed = pending.len();
+        }
+        writer.write_all(text.as_bytes())?;
+        pending.drain(..consumed);
+
+        if at_end {
+            return Ok(());
+        }
+    }
+}
+
+#[test]
+fn normalize_encoding_matches_utf16_and_utf8() {
+    let text = "h\u{e9}llo \u{1F600}\r\n";
+    let options = HashOptions {
+        normalize_encoding: true,
+        ..HashOptions::default()
+    };
+
+    let utf8: Vec<u8> = text.as_bytes().to_vec();
+    let utf8_bom: Vec<u8> = [&[0xEF, 0xBB, 0xBF], text.as_bytes()].concat();
+    let utf16le: Vec<u8> = [0xFF, 0xFE]
+        .into_iter()
+        .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+        .collect();
+    let utf16be: Vec<u8> = [0xFE, 0xFF]
+        .into_iter()
+        .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+        .collect();
+
+    // Hand out one byte at a time so that surrogate pairs are split across
+    // reads.
+    struct OneByteAtATime<'a>(&'a [u8]);
+    impl io::Read for OneByteAtATime<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let expected = hash_contents(&utf8[..], &HashOptions::default()).unwrap();
+    for bytes in [&utf8, &utf8_bom, &utf16le, &utf16be] {
+        assert_eq!(hash_contents(&bytes[..], &options).unwrap(), expected);
+        assert_eq!(
+            hash_contents(OneByteAtATime(bytes), &options).unwrap(),
+            expected
+        );
+    }
+
+    assert_ne!(
+        hash_contents(&utf16le[..], &HashOptions::default()).unwrap(),
+        expected
+    );
+}
+
+impl FromStr for Indentation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Indentation::Keep),
+            "ignore" => Ok(Indentation::Ignore),
+            _ => s
+                .parse::<NonZeroUsize>()
+                .map(Indentation::ExpandTabs)
+                .map_err(|_| {
+                    format!(
+                        "invalid indentation '{}': expected 'keep', 'ignore', or a tab width",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+impl StructuredFormat {
+    pub(crate) fn matches(&self, path: &path::Path) -> bool {
+        let extension = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => extension.to_ascii_lowercase(),
+            None => return false,
+        };
+
+        match self {
+            StructuredFormat::Json => extension == "json",
+            StructuredFormat::Yaml => extension == "yaml" || extension == "yml",
+            #[cfg(feature = "documents")]
+            StructuredFormat::Docx => extension == "docx",
+            #[cfg(feature = "documents")]
+            StructuredFormat::Pdf => extension == "pdf",
+        }
+    }
+}
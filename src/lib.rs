@@ -0,0 +1,165 @@
+// Copyright 2024, G. Christopher Warrington <code@cw.codes>
+//
+// find-dups is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License Version 3 as
+// published by the Free Software Foundation.
+//
+// find-dups is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public
+// License for more details.
+//
+// A copy of the GNU Affero General Public License Version 3 is included in
+// the file LICENSE in the root of the repository.
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The comparison engine behind the find-dups command line tool. The
+//! scanner walks and hashes the roots on both sides, the hasher decides how
+//! each file's contents are hashed, and compare lines the results up. The
+//! profile records where the time went, when asked to.
+//! DupFinder ties them together for the common case.
+
+use crossbeam::channel::Receiver;
+use std::ffi::OsString;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+pub mod compare;
+pub mod hasher;
+pub mod profile;
+pub mod scanner;
+#[cfg(test)]
+mod test_dir;
+
+use compare::{add_to_result_hash_map, split_into_locations, DigestMap, Locations};
+use hasher::HashOptions;
+use scanner::{
+    default_num_threads, join_worker_threads, start_scan, ReaderLimit, WalkOptions, WorkResult,
+};
+
+/// Finds the files whose content is only on the left-hand side, only on the
+/// right-hand side, or on both.
+pub struct DupFinder {
+    /// The roots on the left-hand side.
+    pub left: Vec<OsString>,
+    /// The roots on the right-hand side.
+    pub right: Vec<OsString>,
+    pub hash_options: HashOptions,
+    pub walk_options: WalkOptions,
+    /// Limits on how many files are read at once below given roots.
+    pub reader_limits: Vec<ReaderLimit>,
+    pub num_threads: usize,
+}
+
+/// What a scan found, from DupFinder::compare.
+pub struct Comparison {
+    /// The files only on the left, only on the right, and on both.
+    pub locations: Locations,
+    /// The files and directories that couldn't be read, and so aren't in
+    /// the locations, sorted by path.
+    pub errors: Vec<WorkResult>,
+    /// The files and directories that were left out on purpose, and the
+    /// filter that left each out, sorted by path.
+    pub skipped: Vec<(String, scanner::PathLocation)>,
+}
+
+/// The results of a scan, as they're ready. The worker threads are joined
+/// once all of them have been returned. Dropping a scan part way waits for
+/// the rest of it to finish.
+pub struct Scan {
+    worker_threads: Vec<JoinHandle<()>>,
+    results_receiver: Receiver<WorkResult>,
+}
+
+impl DupFinder {
+    /// Compares the left-hand roots with the right-hand ones, with the
+    /// default options.
+    pub fn new<L, R>(left: L, right: R) -> DupFinder
+    where
+        L: IntoIterator,
+        L::Item: Into<OsString>,
+        R: IntoIterator,
+        R::Item: Into<OsString>,
+    {
+        DupFinder {
+            left: left.into_iter().map(Into::into).collect(),
+            right: right.into_iter().map(Into::into).collect(),
+            hash_options: HashOptions::default(),
+            walk_options: WalkOptions::default(),
+            reader_limits: Vec::new(),
+            num_threads: default_num_threads(),
+        }
+    }
+
+    /// Starts walking and hashing both sides on background threads.
+    pub fn scan(&self) -> Scan {
+        let (worker_threads, results_receiver) = start_scan(
+            &self.left,
+            &self.right,
+            &self.reader_limits,
+            &Arc::new(self.hash_options.clone()),
+            &Arc::new(self.walk_options.clone()),
+            self.num_threads,
+        );
+
+        Scan {
+            worker_threads,
+            results_receiver,
+        }
+    }
+
+    /// Scans both sides and compares them, with every list in the result
+    /// sorted.
+    pub fn compare(&self) -> Comparison {
+        let mut left: DigestMap<Vec<std::path::PathBuf>> = DigestMap::default();
+        let mut right: DigestMap<Vec<std::path::PathBuf>> = DigestMap::default();
+        let mut errors = Vec::new();
+
+        for work_result in self.scan() {
+            match work_result.result {
+                Err(_) => errors.push(work_result),
+                Ok(hashed) => match work_result.path {
+                    scanner::PathLocation::Left(path) => {
+                        add_to_result_hash_map(&mut left, hashed.hash, path)
+                    }
+                    scanner::PathLocation::Right(path) => {
+                        add_to_result_hash_map(&mut right, hashed.hash, path)
+                    }
+                },
+            }
+        }
+
+        let mut locations = split_into_locations(left, right);
+        locations.sort();
+        errors.sort_by(|lhs, rhs| lhs.path.path().cmp(rhs.path.path()));
+
+        Comparison {
+            locations,
+            errors,
+            skipped: self.walk_options.skipped.take(),
+        }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = WorkResult;
+
+    fn next(&mut self) -> Option<WorkResult> {
+        match self.results_receiver.recv() {
+            Ok(work_result) => Some(work_result),
+            Err(_) => {
+                join_worker_threads(std::mem::take(&mut self.worker_threads));
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Scan {
+    fn drop(&mut self) {
+        // The worker threads panic if nothing is receiving their results,
+        // so they're drained rather than abandoned.
+        for _ in self.by_ref() {}
+    }
+}
@@ -14,36 +14,373 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use clap::Parser;
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use crossbeam::channel::unbounded;
+use find_dups::compare::{
+    add_to_rel_path_map, add_to_result_hash_map, classify_mirrored, cross_check, duplicates_within,
+    find_bit_rot_candidates, find_case_collisions, find_conflicts, identity_key, infer_direction,
+    relative_path, remove_acknowledged, sample_for_cross_check, split_into_locations,
+    take_hardlinked, verify_both_byte_by_byte, Acknowledgements, DigestMap, Identity, Locations,
+    Source,
+};
+use find_dups::hasher::{
+    hash_contents, HashAlgorithm, HashOptions, Indentation, Sha256Sum, StructuredFormat,
+};
+use find_dups::profile::Profile;
+use find_dups::scanner::{
+    default_num_threads, dir_id, join_worker_threads, scan_with_prefilter, start_file_scan,
+    start_scan, validate_roots, ControlState, DirId, FileId, HashedFile, IgnoreFiles, PathList,
+    PathLocation, Patterns, ReaderLimit, ScanControl, ScanProgress, Throttle, WalkOptions,
+    WorkResult,
+};
+use indicatif::{BinaryBytes, ProgressBar, ProgressStyle};
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
-use std::convert::AsRef;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::OsString;
-use std::fmt;
 use std::fs;
 use std::io;
+use std::io::BufRead;
+use std::io::Write;
 use std::num::NonZeroUsize;
 use std::panic;
 use std::path;
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::thread::JoinHandle;
+use std::time;
 use std::vec::Vec;
 
-type Sha256Sum = [u8; 32];
+#[cfg(test)]
+mod test_dir;
+#[cfg(feature = "tui")]
+mod tui;
 
-#[derive(Parser)]
+#[cfg(test)]
+use test_dir::TempDir;
+
+// Exit code for when a root given on the command line can't be scanned.
+// Distinct from 1, which is used for other failures, and 2, which clap uses
+// for usage errors.
+const EXIT_INVALID_ROOTS: i32 = 3;
+// Exit code for when every file has a copy on the other side.
+const EXIT_NO_DIFFERENCES: i32 = 4;
+// Exit code for when no files were found on either side.
+const EXIT_NO_FILES: i32 = 5;
+// Exit code for when the scan was cancelled over --control-fd.
+const EXIT_CANCELLED: i32 = 6;
+
+#[derive(Clone, Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(
+    after_help = "Exit codes: 0 when differences were found, 3 when a root can't be scanned, 4 when no differences were found, 5 when no files were found, and 6 when the scan was cancelled over --control-fd."
+)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Paths that make up the "left-hand" side of the comparison. Can be
-    /// repeated.
-    #[arg(long, required = true, short = 'l')]
+    /// repeated. Without a right-hand side, the files within the left-hand
+    /// side that have the same content are reported instead, and --path
+    /// reads better. A pipe or character device given as "fifo:PATH" is
+    /// read to the end and compared as a single file.
+    #[arg(
+        long,
+        required_unless_present_any = ["left_listing", "left_manifest", "sides", "side"],
+        short = 'l',
+        visible_alias = "path"
+    )]
     left: Vec<OsString>,
     /// Paths that make up the "right-hand" side of the comparison. Can be
-    /// repeated.
-    #[arg(long, required = true, short = 'r')]
+    /// repeated. Like --left, "fifo:PATH" reads a pipe or character device
+    /// as a single file.
+    #[arg(
+        long,
+        required_unless_present_any = ["right_listing", "right_manifest", "sides", "left", "side"],
+        short = 'r'
+    )]
     right: Vec<OsString>,
 
+    /// TOML file naming more roots to compare, in addition to any given
+    /// with --left and --right. Each `[[root]]` table has a `name`, a
+    /// `path`, the `side` it's on, "left" or "right", and optionally
+    /// `max_readers`, like --max-readers-per-root for that root.
+    #[arg(long, value_name = "FILE")]
+    sides: Option<path::PathBuf>,
+
+    /// A root on a named side, for comparing more than two sides at once.
+    /// Instead of listing files, prints a table of how many distinct
+    /// contents, and how many bytes of them, are on each set of sides,
+    /// like "laptop+nas" for what is on those two and nowhere else. Roots
+    /// with the same name are on the same side. Needs at least two sides.
+    /// Can be repeated.
+    #[arg(
+        long,
+        value_name = "NAME=PATH",
+        conflicts_with_all = ["left", "right", "sides", "left_listing", "right_listing", "prefilter", "progress"],
+    )]
+    side: Vec<NamedRoot>,
+
+    /// Output of `find -ls` or `dir /s` to use as part of the left-hand
+    /// side. Can be repeated. When any listing is given, the comparison
+    /// is done by relative path and size only and no file contents are
+    /// hashed. Modification times in listings are ignored, as `find -ls`
+    /// leaves out the year for recent files and `dir /s` dates are
+    /// localized.
+    #[arg(long, value_name = "FILE")]
+    left_listing: Vec<path::PathBuf>,
+
+    /// Output of `find -ls` or `dir /s` to use as part of the right-hand
+    /// side. Can be repeated. When any listing is given, the comparison
+    /// is done by relative path and size only and no file contents are
+    /// hashed. Modification times in listings are ignored, as `find -ls`
+    /// leaves out the year for recent files and `dir /s` dates are
+    /// localized.
+    #[arg(long, value_name = "FILE")]
+    right_listing: Vec<path::PathBuf>,
+
+    /// Manifest in the format sha256sum writes, like one from
+    /// --emit-manifest or `index build`, to use as the left-hand side
+    /// instead of a directory. Its paths are compared as they're written,
+    /// so they should be relative to the right-hand root, and nothing on
+    /// the left is read. Useful for checking a restored backup against
+    /// hashes taken when it was made.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "left", "sides", "side", "left_listing", "stats_only", "prefilter",
+            "paranoid", "early_structure", "strict_structure", "report_empty_dirs",
+            "action", "link_duplicates", "move_duplicates_to",
+        ],
+    )]
+    left_manifest: Option<path::PathBuf>,
+
+    /// Manifest to use as the right-hand side instead of a directory, like
+    /// --left-manifest. With both, the manifests are compared to each
+    /// other without reading anything else, which suits snapshots of
+    /// machines that are never online at the same time.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "right", "sides", "side", "right_listing", "stats_only", "prefilter",
+            "paranoid", "early_structure", "strict_structure", "report_empty_dirs",
+            "action", "link_duplicates", "move_duplicates_to",
+        ],
+    )]
+    right_manifest: Option<path::PathBuf>,
+
+    /// Before comparing contents, walk both sides and print the relative
+    /// paths that only exist on one side, prefixed with '<-' or '->'. This
+    /// is available long before hashing finishes.
+    #[arg(long)]
+    early_structure: bool,
+
+    /// Only walk both sides, without hashing anything, and print
+    /// statistics about each: the number of files of each size, how deep
+    /// they are, and the directories with the most data. Use it to pick
+    /// filters before a long comparison.
+    #[arg(long)]
+    stats_only: bool,
+
+    /// Don't print a warning for each directory that can't be read due to
+    /// missing permissions. A count of them, and a suggestion for how to
+    /// include them, is printed at the end instead.
+    #[arg(long)]
+    skip_unreadable_dirs_silently: bool,
+
+    /// Leave out files and directories that match the glob pattern, and
+    /// everything in directories that do, like 'node_modules', '*.tmp', or
+    /// '.git'. Patterns are matched against both the name and the whole
+    /// path of each file and directory under the roots. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Leave a file or directory below one of the left-hand roots, and
+    /// everything in it, out of the left-hand side, like a trash folder
+    /// in one of several roots that make up the side. Paths are printed
+    /// in full, so each copy found shows which root it's under. Can be
+    /// repeated.
+    #[arg(long, value_name = "PATH")]
+    left_exclude: Vec<path::PathBuf>,
+
+    /// Leave a file or directory below one of the right-hand roots, and
+    /// everything in it, out of the right-hand side. Like --left-exclude.
+    /// Can be repeated.
+    #[arg(long, value_name = "PATH")]
+    right_exclude: Vec<path::PathBuf>,
+
+    /// Only compare files that match the glob pattern, like '*.jpg'.
+    /// Directories are still walked to find them, and --exclude still
+    /// applies. Patterns are matched like those for --exclude. Can be
+    /// repeated. Defaults to comparing every file.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// File listing the only paths to compare, one per line, relative to
+    /// each root. Everything below a listed directory is compared, and
+    /// only the directories leading to a listed path are walked. Blank
+    /// lines are ignored. Defaults to comparing everything under the
+    /// roots.
+    #[arg(long, value_name = "FILE")]
+    paths_file: Option<path::PathBuf>,
+
+    /// Only walk this many levels of directories below each root, so that
+    /// files directly in a root are at depth 1. Directories whose contents
+    /// would be deeper are skipped. Defaults to walking everything.
+    #[arg(long, value_name = "N")]
+    max_depth: Option<NonZeroUsize>,
+
+    /// Print every file or directory that can't be read. By default, only
+    /// the first few errors of each kind are printed as they happen, and
+    /// the rest are summarized by directory at the end, so that a whole
+    /// tree failing the same way doesn't bury everything else.
+    #[arg(long, global = true)]
+    all_errors: bool,
+
+    /// Print every option and command, along with its help, as JSON, so
+    /// that other tools can build forms for them. After a command, only
+    /// that command's options and commands are printed.
+    #[arg(long, global = true, exclusive = true)]
+    help_json: bool,
+
+    /// List each file and directory that was left out of the comparison
+    /// on purpose, like symbolic links that aren't followed, and the
+    /// filter that left it out. A count of them for each filter is always
+    /// printed. In "jsonl" output, each is a record. Otherwise they're
+    /// printed on standard error.
+    #[arg(long)]
+    show_skipped: bool,
+
+    /// Follow symbolic links to files and directories, including roots,
+    /// and compare what they point to as if it were at the link's path.
+    /// Links that lead back to a directory above them are reported and not
+    /// followed. Defaults to skipping every symbolic link below the roots,
+    /// counted with the other skipped entries and listed with
+    /// --show-skipped. Symbolic links given as roots are reported.
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Leave out what the .gitignore and .ignore files in each root, and
+    /// in the directories below it, ignore, the way git and ripgrep do, as
+    /// well as .git directories. Ignore files above a root aren't read.
+    /// Defaults to comparing everything.
+    #[arg(long)]
+    respect_gitignore: bool,
+
+    /// Hash text files that start with a byte order mark as UTF-8 without
+    /// the byte order mark, so that UTF-16 and UTF-8 copies of the same
+    /// text are considered identical. Files without a byte order mark are
+    /// hashed as they are.
+    #[arg(long)]
+    normalize_encoding: bool,
+
+    /// Hash text files after removing trailing whitespace from each line
+    /// and blank lines from the end of the file, so that files that only
+    /// differ in formatting are considered identical. Files that look like
+    /// binary files are hashed as they are.
+    #[arg(long)]
+    normalize_whitespace: bool,
+
+    /// How --normalize-whitespace treats indentation: "keep" leaves it
+    /// alone, "ignore" removes it, and a number expands tabs in it to
+    /// spaces using that tab width. Defaults to "keep".
+    #[arg(long, requires = "normalize_whitespace", default_value = "keep")]
+    indentation: Indentation,
+
+    /// Structured formats to parse and hash in a canonical form, so that
+    /// files with the same data but different key order or formatting are
+    /// considered identical. When built with the "documents" feature, PDF
+    /// and Word documents can be compared by their text alone. Files are
+    /// picked by extension. Files that fail to parse, or are larger than
+    /// 64 MiB, are hashed as they are.
+    #[arg(long, value_delimiter = ',', value_name = "FORMATS")]
+    normalize: Vec<StructuredFormat>,
+
+    /// Group photos that look nearly the same, like burst shots or lightly
+    /// edited copies, and print each group with a suggested copy to keep.
+    /// The suggestion is the photo with the highest resolution, then the
+    /// largest file.
+    #[cfg(feature = "images")]
+    #[arg(long, conflicts_with_all = ["left_manifest", "right_manifest"])]
+    cluster_photos: bool,
+
+    /// How different two photos can look and still be grouped by
+    /// --cluster-photos, as the number of differing bits out of 64 in
+    /// their perceptual hashes. Defaults to 10.
+    #[cfg(feature = "images")]
+    #[arg(long, default_value_t = 10, requires = "cluster_photos")]
+    photo_distance: u32,
+
+    /// How to print the results. "text" prints them once the scan is done.
+    /// "jsonl" prints one JSON object per line: one for each file as soon
+    /// as it's hashed, then one for each file or group of files in the
+    /// results. "csv" and "tsv" print a row for each file in the results,
+    /// with columns for where it was found, its side, path, hash, size, and
+    /// modification time. The reports that are only available as text
+    /// can't be used with the other formats. Defaults to "text".
+    #[arg(long, default_value = "text")]
+    format: OutputFormat,
+
+    /// Whether to pipe the text results through the pager named by
+    /// $PAGER, or `less` when it isn't set, so that long reports don't
+    /// flood the terminal. "auto" pages when standard output is a
+    /// terminal, "always" pages even when it isn't, and "never" doesn't
+    /// page. Unless $LESS is set, reports that fit on one screen are
+    /// printed without paging. Only text is paged, and not while --progress
+    /// is drawing. Only supported on Unix. Defaults to "never".
+    #[arg(long, global = true, default_value = "never")]
+    pager: PagerMode,
+
+    /// How hashes are shown in text output: "full", "short" for the first
+    /// 12 hex digits, or "none" to leave them out. The other formats always
+    /// have full hashes. Defaults to "full".
+    #[arg(long, default_value = "full")]
+    hash_display: HashDisplay,
+
+    /// Before hashing files in full, hash only the start and end of the
+    /// big ones, and only hash the rest of those that could have a copy on
+    /// the other side. This saves reading most of the data when comparing
+    /// trees of big files, like videos, that mostly differ. Hashes printed
+    /// for big files that only exist on one side are of their start and
+    /// end only.
+    #[arg(
+        long,
+        conflicts_with_all = ["normalize_encoding", "normalize_whitespace", "normalize"],
+    )]
+    prefilter: bool,
+
+    /// Before reporting files as present in both sides, compare them byte
+    /// for byte instead of relying on their hashes alone. Files that turn
+    /// out to differ are reported as such, and files that can't be read
+    /// are reported as errors. This reads every file that has
+    /// a copy on the other side again. Files compared after normalizing
+    /// them can't be compared byte for byte.
+    #[arg(
+        long,
+        alias = "verify",
+        conflicts_with_all = ["normalize_encoding", "normalize_whitespace", "normalize"],
+    )]
+    paranoid: bool,
+
+    /// Hash a sample of the files again with each of these algorithms,
+    /// like "sha256,blake3", and check that they put the files in the same
+    /// groups. Gives confidence before moving hashes to a faster
+    /// algorithm. Up to 1000 digests are sampled, and every file with one
+    /// is read again for each algorithm.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "ALGORITHMS",
+        conflicts_with_all = [
+            "normalize_encoding", "normalize_whitespace", "normalize",
+            "left_manifest", "right_manifest",
+        ],
+    )]
+    cross_check: Vec<HashAlgorithm>,
+
     /// Omit printing files that only exist on the left-hand side. Defaults
     /// to printing them.
     #[arg(long, short = 'L')]
@@ -55,490 +392,6237 @@ struct Args {
     omit_right: bool,
 
     /// Print the files present in both the left- and right-hand sides.
-    /// Defaults to omitting them.
+    /// Each group of identical files is headed by how many copies are at
+    /// the same relative path on both sides, and how many extra copies
+    /// are elsewhere on each side. Groups whose paths are all hard links
+    /// to the same file are headed "hardlinked" instead, as they aren't
+    /// copies. Defaults to omitting them.
     #[arg(long, short = 'B')]
     show_both: bool,
-}
 
-enum Work {
-    Directory {
-        path: PathLocation,
-        work_sender: Sender<Work>,
-    },
-    File {
-        path: PathLocation,
-    },
-}
+    /// Only print the first N paths on each side of each group of files
+    /// present in both sides, with a count of the rest, so that groups of
+    /// many identical files don't swamp the results. In "jsonl" output,
+    /// the counts are always included, and in "csv" and "tsv" output the
+    /// rest are left out. Defaults to printing every path.
+    #[arg(long, value_name = "N")]
+    max_paths_per_group: Option<usize>,
 
-struct WorkResult {
-    pub path: PathLocation,
-    pub result: io::Result<Sha256Sum>,
-}
+    /// Print the directories whose number of files differs between the
+    /// left- and right-hand sides. Directories are matched by their path
+    /// relative to the root they were found under. This is printed even
+    /// if the other listings are omitted.
+    #[arg(long, short = 'C')]
+    count_parity: bool,
 
-#[derive(Clone)]
-enum PathLocation {
-    Left(path::PathBuf),
-    Right(path::PathBuf),
-}
+    /// Print the directories with no files anywhere below them that are
+    /// only on one side, by their path relative to the root they were
+    /// found under. Only the topmost directory of each such tree is
+    /// printed. They aren't otherwise compared, as only files are hashed,
+    /// and copies and restores often leave them out. This is printed even
+    /// if the other listings are omitted.
+    #[arg(long)]
+    report_empty_dirs: bool,
 
-fn main() -> io::Result<()> {
-    let args = Args::parse();
+    /// Check that the sides are exact mirrors, like `diff -r`: besides
+    /// comparing content, print every file and directory whose relative
+    /// path is only on one side, the ones whose paths only differ in case,
+    /// and, like --conflicts, the files at the same path with different
+    /// content. Any of them counts as a difference.
+    #[arg(long)]
+    strict_structure: bool,
 
-    let (work_sender, work_receiver) = unbounded();
-    let (results_sender, results_receiver) = unbounded();
+    /// What makes two files the same file: "content" alone,
+    /// "content+name" for the same content and file name, or
+    /// "content+relpath" for the same content and path relative to their
+    /// root. Hashes in --acknowledge-file only match files compared by
+    /// content alone. Defaults to "content".
+    #[arg(long, default_value = "content")]
+    identity: Identity,
 
-    enqueue_initial_work_from_args(&args, &work_sender);
+    /// Print the files that are at the same relative path on both sides
+    /// but have different content, with the hash and modification time of
+    /// each copy. This is printed even if the other listings are omitted.
+    #[arg(long)]
+    conflicts: bool,
 
-    // Initial work has been enqueued. Any Directory work has its own clone
-    // of work_sender that is can use to enqueue more work.
-    //
-    // Drop this copy of the sender so that all the copies are dropped when
-    // directory enumeration is complete.
-    drop(work_sender);
+    /// Print which side appears to be the source and which a stale mirror
+    /// of it, judged by which side has the newer copy of files that
+    /// differ, and then by which has more files of its own, along with the
+    /// counts it was judged by.
+    #[arg(long)]
+    infer_direction: bool,
 
-    let worker_threads = start_worker_threads(work_receiver, results_sender);
+    /// Swap the left- and right-hand sides, including those from --sides
+    /// and the listings, as if they had been given the other way around.
+    #[arg(long)]
+    swap: bool,
 
-    let mut left: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
-    let mut right: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
+    /// Number of worker threads that walk and hash files. More threads
+    /// than CPUs can help on network file systems, where most of the time
+    /// is spent waiting, and fewer leave room for other work on shared
+    /// machines. Defaults to the number of CPUs.
+    #[arg(long, short = 'j', value_name = "N", global = true)]
+    threads: Option<NonZeroUsize>,
 
-    for work_result in results_receiver.iter() {
-        if work_result.result.is_err() {
-            eprintln!("{}", work_result);
-            continue;
-        }
+    /// Show how many of the files found so far have been hashed, how
+    /// quickly, and an estimate of how long the rest will take, on
+    /// standard error. The estimate firms up once every directory has been
+    /// walked. Can't be used with --prefilter, which hashes in two passes.
+    #[arg(long, conflicts_with = "prefilter")]
+    progress: bool,
 
-        let sha256sum = work_result.result.unwrap();
+    /// File descriptor, already open for reading and writing, over which
+    /// the scan can be controlled by another program. It sends commands,
+    /// one per line: "pause", "resume", "cancel", or "status". Each is
+    /// answered with a line of JSON with the state of the scan and, unless
+    /// --prefilter is used, how many files have been found and hashed. A
+    /// last line is sent once the scan is done. A cancelled scan exits
+    /// with code 6, without leaving a --record-all-hashes or
+    /// --emit-manifest file behind. Only supported on Unix.
+    #[arg(long, value_name = "N")]
+    control_fd: Option<i32>,
 
-        match work_result.path {
-            PathLocation::Left(path) => add_to_result_hash_map(&mut left, sha256sum, path),
-            PathLocation::Right(path) => add_to_result_hash_map(&mut right, sha256sum, path),
-        }
-    }
+    /// Keep reading files as fast as possible even when most of them fail
+    /// to be read. By default, when at least half of the last 20 files
+    /// failed with I/O errors, as happens when a disk is failing or a
+    /// network share keeps dropping, half as many files are read at a
+    /// time, down to one, and then reads are spaced further and further
+    /// apart, until files can be read again. Each change is printed on
+    /// standard error.
+    #[arg(long)]
+    no_error_backoff: bool,
 
-    for worker_thread in worker_threads {
-        if let Err(e) = worker_thread.join() {
-            panic::resume_unwind(e);
-        }
-    }
+    /// Print when the scan started and finished, how long it took, and
+    /// how quickly files were hashed to standard error, after the text
+    /// results and the line saying if any differences were found.
+    #[arg(long)]
+    footer: bool,
 
-    let mut locations = split_into_locations(left, right);
+    /// Run with lowered CPU and IO priority so that the scan doesn't
+    /// degrade interactive use of the machine.
+    #[arg(long)]
+    background: bool,
 
-    if !args.omit_left {
-        locations.left.sort_unstable();
-        for path in locations.left {
-            println!("<= '{}'", path.display());
-        }
-    }
+    /// Limit how many files are read at the same time from a root. Given
+    /// as N, the limit applies to every root. Given as ROOT=N, it applies
+    /// only to that root and overrides the general limit. Can be
+    /// repeated. Defaults to no limit beyond the number of worker threads.
+    #[arg(long, value_name = "[ROOT=]N")]
+    max_readers_per_root: Vec<ReaderLimit>,
 
-    if !args.omit_right {
-        locations.right.sort_unstable();
-        for path in locations.right {
-            println!("=> '{}'", path.display());
-        }
-    }
+    /// File listing duplicates that have already been reviewed, which are
+    /// then left out of the files present in both sides. Each line is
+    /// either a SHA256 hash in hex or a left-hand path and a right-hand
+    /// path separated by a tab. Blank lines and lines starting with '#'
+    /// are ignored.
+    #[arg(long, value_name = "FILE")]
+    acknowledge_file: Option<path::PathBuf>,
 
-    if args.show_both {
-        for (lpaths, rpaths) in locations.both.iter_mut() {
-            lpaths.sort_unstable();
-            rpaths.sort_unstable();
-        }
+    /// What to do with right-hand files that have a copy on the left-hand
+    /// side. "symlink" replaces each of them with a symbolic link to the
+    /// left-hand copy that sorts first, which works even when the sides
+    /// are on different devices. "reflink" replaces each of them with a
+    /// copy-on-write clone of that copy instead. Files that changed after
+    /// they were hashed are skipped. Files that only match after
+    /// normalizing them aren't copies, so it can't be used with
+    /// normalizing. Defaults to only reporting them.
+    #[arg(
+        long,
+        value_name = "ACTION",
+        conflicts_with_all = ["normalize_encoding", "normalize_whitespace", "normalize"],
+    )]
+    action: Option<Action>,
 
-        // Sort 'both' locations by their first lpath. The vectors are
-        // guaranteed to be non-empty, otherwise this wouldn't be a 'both'
-        // location.
-        locations
-            .both
-            .sort_unstable_by(|(lpaths_l, _), (lpaths_r, _)| {
-                std::cmp::Ord::cmp(&lpaths_l[0], &lpaths_r[0])
-            });
+    /// Replace right-hand files that have a copy on the left-hand side
+    /// with links to it. "soft" is the same as --action symlink, for
+    /// filesystems where hard links aren't appropriate. "reflink" is the
+    /// same as --action reflink. Like --action, it can't be used with
+    /// normalizing.
+    #[arg(
+        long,
+        value_name = "KIND",
+        conflicts_with_all = ["action", "normalize_encoding", "normalize_whitespace", "normalize"],
+    )]
+    link_duplicates: Option<LinkKind>,
 
-        for (lpaths, rpaths) in locations.both {
-            println!("<=>");
-            for lpath in lpaths {
-                println!("  <= '{}'", lpath.display());
-            }
-            for rpath in rpaths {
-                println!("  => '{}'", rpath.display());
-            }
-        }
-    }
+    /// Whether the symbolic links made by --action symlink and
+    /// --link-duplicates soft point to the kept copy by a relative or an
+    /// absolute path. Relative links keep working when both sides are moved
+    /// or mounted elsewhere together; absolute links keep working when the
+    /// right-hand side is moved on its own. Defaults to relative.
+    #[arg(long, value_name = "STYLE", default_value = "relative")]
+    symlink_style: SymlinkStyle,
 
-    Ok(())
-}
+    /// Browse the groups of duplicates in an interactive terminal UI,
+    /// marking files to delete or to replace with a symbolic link to the
+    /// copy that's kept, and make those changes on the way out. Standard
+    /// output has to be a terminal. Like --action, it can't be used with
+    /// normalizing.
+    #[cfg(feature = "tui")]
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "action", "link_duplicates", "move_duplicates_to", "pager", "left_manifest",
+            "right_manifest", "normalize_encoding", "normalize_whitespace", "normalize",
+        ],
+    )]
+    tui: bool,
 
-fn add_to_result_hash_map(
-    map: &mut HashMap<Sha256Sum, Vec<path::PathBuf>>,
-    hash: Sha256Sum,
-    path: path::PathBuf,
-) {
-    map.entry(hash)
-        .or_insert_with(|| Vec::with_capacity(1))
-        .push(path);
-}
+    /// Report what --action, --link-duplicates or --move-duplicates-to
+    /// would do, and how many bytes that would reclaim, without changing
+    /// anything. The same checks are made, so files that would be left
+    /// alone are reported too. Moved files are reclaimed once the holding
+    /// directory is emptied.
+    #[arg(long)]
+    dry_run: bool,
 
-fn enqueue_initial_work_from_args(args: &Args, work_sender: &Sender<Work>) {
-    enqueue_initial_work_for_side(
-        args.left.iter(),
-        |path: &path::Path| -> PathLocation { PathLocation::new_left(path) },
-        work_sender,
-    );
-    enqueue_initial_work_for_side(
-        args.right.iter(),
-        |path: &path::Path| -> PathLocation { PathLocation::new_right(path) },
-        work_sender,
-    );
-}
+    /// Write the hash, size and path of every file that's hashed to this
+    /// file, in a compact binary format for offline analysis. It starts
+    /// with the 8 bytes "FDHASHv1". Each file then takes 32 bytes of
+    /// SHA256 hash, its size as 8 bytes, the length of its path as 4
+    /// bytes, both little endian, and the path's bytes.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "left_listing", "right_listing", "side", "stats_only", "prefilter", "left_manifest",
+            "right_manifest",
+        ],
+    )]
+    record_all_hashes: Option<path::PathBuf>,
 
-fn enqueue_initial_work_for_side<'a, I, F>(
-    arg_paths: I,
-    path_location_factory: F,
-    work_sender: &Sender<Work>,
-) where
-    I: IntoIterator<Item = &'a OsString>,
-    F: Fn(&path::Path) -> PathLocation,
-{
-    for arg_path in arg_paths.into_iter() {
-        let path = path::Path::new(&arg_path);
+    /// Write the hash of every file that's hashed to this file, in the
+    /// format sha256sum uses, so it can be checked with `sha256sum -c` or
+    /// reused by other tools. Paths are written as they were found.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["left_listing", "right_listing", "side", "stats_only", "prefilter"],
+    )]
+    emit_manifest: Option<path::PathBuf>,
 
-        if path.is_symlink() {
-            eprintln!(
-                "WARN: Symlinks are not supported: '{}'",
-                &arg_path.to_string_lossy()
-            );
-            continue;
-        }
+    /// Only write the files on this side to the --emit-manifest file, with
+    /// paths relative to the side's root, like `index build` writes.
+    #[arg(long, value_name = "SIDE", requires = "emit_manifest")]
+    emit_manifest_side: Option<Side>,
 
-        let metadata = match path.metadata() {
-            Err(e) => {
-                eprintln!(
-                    "WARN: unable to process path on command line: '{}': {}",
-                    path.display(),
-                    e
-                );
-                continue;
-            }
-            Ok(metadata) => metadata,
-        };
+    /// Move right-hand files that have a copy on the left-hand side into
+    /// this directory, at the same path relative to it as they had
+    /// relative to their root, so they can be looked over before being
+    /// deleted. Files that changed after they were hashed, or whose new
+    /// path is already taken, are left alone. Like --action, it can't be
+    /// used with normalizing.
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["action", "link_duplicates", "normalize_encoding", "normalize_whitespace", "normalize"]
+    )]
+    move_duplicates_to: Option<path::PathBuf>,
 
-        if metadata.is_dir() {
-            let work = Work::Directory {
-                path: path_location_factory(path),
-                work_sender: work_sender.clone(),
-            };
-            work_sender
-                .send(work)
-                .expect("Unable to enqueue initial Directory work into work channel");
-        } else {
-            assert!(
-                metadata.is_file(),
-                "Exptected path '{}' to be a file on this path, but it wasn't.",
-                path.display()
-            );
+    /// Print how much space each way of handling the files present in
+    /// both sides would reclaim: deleting the right-hand copies, replacing
+    /// them with hard links or reflinks, or keeping only the newest copy of
+    /// each file. Nothing is changed.
+    #[arg(long)]
+    simulate_strategies: bool,
 
-            let work = Work::File {
-                path: path_location_factory(path),
-            };
-            work_sender
-                .send(work)
-                .expect("Unable to enqueue initial File work into work channel");
+    /// Record how long walking, hashing, lining the results up and
+    /// printing them take, and what each worker thread spends its time
+    /// on, to this file. It's in the Chrome trace event format, which
+    /// chrome://tracing, Perfetto and speedscope can open.
+    #[arg(long, value_name = "FILE")]
+    profile_out: Option<path::PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable text.
+    Text,
+    /// JSON Lines, streamed as results are known.
+    Jsonl,
+    /// Comma-separated values, with a header row.
+    Csv,
+    /// Tab-separated values, with a header row.
+    Tsv,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum HashDisplay {
+    /// All 64 hex digits.
+    Full,
+    /// The first 12 hex digits.
+    Short,
+    /// Leave hashes out.
+    None,
+}
+
+// How many hex digits of a hash "short" shows. Like abbreviated git
+// commit ids, that's plenty to tell apart the files in one report.
+const SHORT_HASH_LEN: usize = 12;
+
+impl HashDisplay {
+    // The hash as it should be shown in text output, followed by the
+    // separator, or nothing at all when hashes are left out.
+    fn prefix(self, hash: &Sha256Sum, separator: &str) -> String {
+        let hex = hex::encode(hash);
+        match self {
+            HashDisplay::Full => format!("{}{}", hex, separator),
+            HashDisplay::Short => format!("{}{}", &hex[..SHORT_HASH_LEN], separator),
+            HashDisplay::None => String::new(),
         }
     }
 }
 
-fn start_worker_threads(
-    work_receiver: Receiver<Work>,
-    results_sender: Sender<WorkResult>,
-) -> Vec<JoinHandle<()>> {
-    let num_threads: usize = thread::available_parallelism()
-        .unwrap_or(NonZeroUsize::new(2).unwrap())
-        .into();
+#[test]
+fn hash_display_shortens_or_omits() {
+    let hash = [0xab; 32];
+    assert_eq!(
+        HashDisplay::Full.prefix(&hash, " : "),
+        format!("{} : ", "ab".repeat(32))
+    );
+    assert_eq!(HashDisplay::Short.prefix(&hash, " "), "abababababab ");
+    assert_eq!(HashDisplay::None.prefix(&hash, " "), "");
+}
 
-    let mut results = Vec::with_capacity(num_threads);
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum PagerMode {
+    /// Page when standard output is a terminal.
+    Auto,
+    /// Always page.
+    Always,
+    /// Never page.
+    Never,
+}
 
-    for _ in 0..num_threads {
-        let thread_work_receiver = work_receiver.clone();
-        let thread_results_sender = results_sender.clone();
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Action {
+    /// Replace right-hand duplicates with relative symbolic links.
+    Symlink,
+    /// Replace right-hand duplicates with copy-on-write clones.
+    Reflink,
+}
 
-        results.push(thread::spawn(move || {
-            for work in thread_work_receiver.iter() {
-                match work {
-                    Work::Directory { path, work_sender } => {
-                        handle_dir_work(path, &work_sender, &thread_results_sender)
-                    }
-                    Work::File { path } => handle_file_work(path, &thread_results_sender),
-                };
-            }
-        }));
-    }
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum LinkKind {
+    /// Symbolic links.
+    Soft,
+    /// Copy-on-write clones, which share the kept copy's data but keep
+    /// their own permissions and modification time. Only on Linux and
+    /// macOS, on filesystems that support them, like Btrfs, XFS and APFS.
+    Reflink,
+}
 
-    results
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum SymlinkStyle {
+    /// Link to the kept copy by its path relative to the link.
+    Relative,
+    /// Link to the kept copy by its canonical absolute path.
+    Absolute,
 }
 
-fn handle_dir_work(
-    path: PathLocation,
-    work_sender: &Sender<Work>,
-    results_sender: &Sender<WorkResult>,
-) {
-    let read_dir = match fs::read_dir(path.path()) {
-        Err(e) => {
-            let r = WorkResult::from_err(path, e);
-            results_sender
-                .send(r)
-                .expect("Unable to enqueue result into result channel");
-            return;
-        }
-        Ok(read_dir) => read_dir,
-    };
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Compare the left-hand side with the right-hand side, or find the
+    /// duplicates within the left-hand side. This is what runs when no
+    /// command is given, and it takes the same options, which
+    /// `find-dups --help` lists.
+    // It's taken off the command line before it's parsed, so it's never
+    // parsed as a command itself.
+    Compare,
 
-    for entry in read_dir {
-        let entry = match entry {
-            Err(e) => {
-                let r = WorkResult::from_err(path.clone(), e);
-                results_sender
-                    .send(r)
-                    .expect("Unable to enqueue result into result channel");
-                continue;
-            }
-            Ok(entry) => entry,
-        };
+    /// Copy the unique content under a directory into a content-addressable
+    /// store, and write a manifest in sha256sum format mapping each file's
+    /// path, relative to the directory, to its content.
+    Ingest(IngestArgs),
 
-        let entry_path = entry.path();
-        if entry_path.is_symlink() {
-            let r = WorkResult::from_err(
-                PathLocation::new_same_side(&path, &entry_path),
-                io::Error::other("Symlinks are not supported. Ignoring."),
-            );
+    /// Check a directory restored from a content-addressable store against
+    /// the manifest written when it was ingested, reporting files that are
+    /// missing, unexpected, or have the wrong content. Optionally also
+    /// check the store's copies of the content.
+    Verify(VerifyArgs),
 
-            results_sender
-                .send(r)
-                .expect("Unable to enqueue result into result channel");
+    /// Measure, separately, how quickly a directory can be walked and how
+    /// quickly its files can be hashed, to tell whether a scan of it is
+    /// limited by metadata IO or by data IO.
+    Bench(BenchArgs),
 
-            continue;
-        } else if entry_path.is_dir() {
-            let w = Work::Directory {
-                path: PathLocation::new_same_side(&path, &entry_path),
-                work_sender: work_sender.clone(),
-            };
-            work_sender
-                .send(w)
-                .expect("Unable to enqueue Directory into work channel");
-        } else {
-            assert!(
-                entry_path.is_file(),
-                "Expected path '{}' to be a file on this path, but it wasn't.",
-                entry_path.display()
-            );
-            let w = Work::File {
-                path: PathLocation::new_same_side(&path, &entry_path),
-            };
-            work_sender
-                .send(w)
-                .expect("Unable to enqueue File into work channel");
-        }
-    }
+    /// Build an index of the content under a big directory, like a photo
+    /// library, once, and then check new files against it without walking
+    /// the directory again.
+    Index {
+        #[command(subcommand)]
+        command: IndexCommand,
+    },
+
+    /// Copy files from a directory, like a camera's memory card, into a
+    /// library, at the same paths relative to each. Files that are already
+    /// at their new path are left alone.
+    Import(ImportArgs),
 }
 
-fn handle_file_work(path: PathLocation, results_sender: &Sender<WorkResult>) {
-    let r = fingerprint_one_file(path);
+#[derive(Clone, Subcommand)]
+enum IndexCommand {
+    /// Hash every file under a directory and write the index, a manifest in
+    /// sha256sum format mapping each file's path, relative to the
+    /// directory, to its content.
+    Build(IndexBuildArgs),
 
-    results_sender
-        .send(r)
-        .expect("Unable to enqueue result into result channel");
+    /// Hash the given files and report, for each one, whether its content
+    /// is already in the index and under which paths.
+    Query(IndexQueryArgs),
 }
 
-fn fingerprint_one_file(path: PathLocation) -> WorkResult {
-    let mut file = match fs::File::open(path.path()) {
-        Err(e) => return WorkResult::from_err(path, e),
-        Ok(f) => f,
-    };
+#[derive(Clone, clap::Args)]
+struct ImportArgs {
+    /// Index of the library, written by `index build`.
+    #[arg(long, value_name = "FILE")]
+    index: Option<path::PathBuf>,
 
-    let mut hasher = Sha256::new();
+    /// Directory to copy files from.
+    #[arg(long, value_name = "DIR")]
+    from: path::PathBuf,
 
-    match io::copy(&mut file, &mut hasher) {
-        Err(e) => WorkResult::from_err(path, e),
-        Ok(_) => WorkResult::from_hash(path, hasher.finalize()),
-    }
-}
+    /// Directory to copy files to. Created if it doesn't exist.
+    #[arg(long, value_name = "DIR")]
+    to: path::PathBuf,
 
-struct Locations {
-    left: Vec<path::PathBuf>,
-    both: Vec<(Vec<path::PathBuf>, Vec<path::PathBuf>)>,
-    right: Vec<path::PathBuf>,
+    /// Don't copy files whose content is already in the index, or that
+    /// has already been copied in this import, and report them instead.
+    #[arg(long, requires = "index")]
+    skip_duplicates: bool,
 }
 
-fn split_into_locations(
-    mut left: HashMap<Sha256Sum, Vec<path::PathBuf>>,
-    mut right: HashMap<Sha256Sum, Vec<path::PathBuf>>,
-) -> Locations {
-    // When extract_if is stabalized, I think this can be replaced by that.
-    // https://github.com/rust-lang/rust/issues/59618
-    let keys_in_both: HashSet<Sha256Sum> = left
-        .keys()
-        .filter_map(|k| {
-            if right.contains_key(k) {
-                Some(*k)
-            } else {
-                None
-            }
-        })
-        .collect();
+#[derive(Clone, clap::Args)]
+struct IndexBuildArgs {
+    /// Directory to index.
+    #[arg(long)]
+    path: path::PathBuf,
 
-    let both_results: Vec<(Vec<path::PathBuf>, Vec<path::PathBuf>)> = keys_in_both
-        .iter()
-        .map(|k| {
-            // The key was present in both, so unwrapping the Option from
-            // .remove shouldn't panic.
-            let from_left = left.remove(k).unwrap();
-            let from_right = right.remove(k).unwrap();
-            (from_left, from_right)
-        })
-        .collect();
+    /// File to write the index to.
+    #[arg(long, value_name = "FILE")]
+    index: path::PathBuf,
+}
 
-    // The items present in both have already been removed, so consuming the
-    // values to create the results should yield only left/right paths.
-    let left_results: Vec<path::PathBuf> = left.into_values().flatten().collect();
-    let right_results: Vec<path::PathBuf> = right.into_values().flatten().collect();
+#[derive(Clone, clap::Args)]
+struct IndexQueryArgs {
+    /// Index written by `index build`, or any manifest in sha256sum format.
+    #[arg(long, value_name = "FILE")]
+    index: path::PathBuf,
 
-    Locations {
-        left: left_results,
-        both: both_results,
-        right: right_results,
-    }
+    /// Files or directories to check against the index. Can be repeated.
+    #[arg(long, short = 'l', required = true)]
+    left: Vec<OsString>,
 }
 
-#[test]
-fn split_nothing_right_only_left() {
-    let some_sha256_sum1: Sha256Sum = [1u8; 32];
-    let some_sha256_sum2: Sha256Sum = [2u8; 32];
+#[derive(Clone, clap::Args)]
+struct IngestArgs {
+    /// Directory to ingest.
+    #[arg(long)]
+    path: path::PathBuf,
+
+    /// Directory of the content-addressable store. Created if it doesn't
+    /// exist. Content is stored in files named after its SHA256 hash.
+    #[arg(long)]
+    store: path::PathBuf,
 
-    let mut left: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
-    left.insert(some_sha256_sum1, vec!["lpath1".into()]);
-    left.insert(some_sha256_sum2, vec!["lpath2".into()]);
+    /// File to write the manifest to. Defaults to standard output.
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<path::PathBuf>,
+}
 
-    let right: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
+#[derive(Clone, clap::Args)]
+struct VerifyArgs {
+    /// Directory to check.
+    #[arg(long)]
+    path: path::PathBuf,
 
-    let results: Locations = split_into_locations(left, right);
+    /// Manifest, in sha256sum format, to check the directory against.
+    #[arg(long, value_name = "FILE")]
+    manifest: path::PathBuf,
 
-    assert_eq!(results.left.len(), 2);
-    assert!(results.both.is_empty());
-    assert!(results.right.is_empty());
+    /// Content-addressable store to also check. Every object the manifest
+    /// refers to must be present and match its hash.
+    #[arg(long)]
+    store: Option<path::PathBuf>,
 }
 
-#[test]
-fn split_nothing_left_only_right() {
-    let some_sha256_sum1: Sha256Sum = [1u8; 32];
-    let some_sha256_sum2: Sha256Sum = [2u8; 32];
+#[derive(Clone, clap::Args)]
+struct BenchArgs {
+    /// Directory to measure.
+    #[arg(long)]
+    path: path::PathBuf,
+}
+
+// Prints the schema of the command that was run, which is the last one on
+// the command line.
+fn print_help_json(matches: &clap::ArgMatches) {
+    let mut command = Args::command();
+    command.build();
+
+    let (mut command, mut matches) = (&command, matches);
+    while let Some((name, sub_matches)) = matches.subcommand() {
+        command = command
+            .find_subcommand(name)
+            .expect("Matched subcommand is missing from the command");
+        matches = sub_matches;
+    }
 
-    let left: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
+    println!("{}", command_schema(command));
+}
+
+fn command_schema(command: &clap::Command) -> serde_json::Value {
+    let help = |help: Option<&clap::builder::StyledStr>| help.map(|help| help.to_string());
 
-    let mut right: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
-    right.insert(some_sha256_sum1, vec!["rpath1".into()]);
-    right.insert(some_sha256_sum2, vec!["rpath2".into()]);
+    let args: Vec<serde_json::Value> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .map(|arg| {
+            let possible_values: Vec<serde_json::Value> = arg
+                .get_possible_values()
+                .iter()
+                .filter(|value| !value.is_hide_set())
+                .map(|value| {
+                    serde_json::json!({
+                        "name": value.get_name(),
+                        "help": help(value.get_help()),
+                    })
+                })
+                .collect();
 
-    let results: Locations = split_into_locations(left, right);
+            serde_json::json!({
+                "id": arg.get_id().as_str(),
+                "long": arg.get_long(),
+                "short": arg.get_short().map(String::from),
+                "help": help(arg.get_long_help().or(arg.get_help())),
+                "value_names": arg
+                    .get_value_names()
+                    .map(|names| names.iter().map(|name| name.as_str()).collect::<Vec<_>>()),
+                "takes_value": arg.get_action().takes_values(),
+                "multiple": matches!(arg.get_action(), clap::ArgAction::Append),
+                "required": arg.is_required_set(),
+                "global": arg.is_global_set(),
+                "default": arg
+                    .get_default_values()
+                    .iter()
+                    .map(|value| value.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "possible_values": possible_values,
+            })
+        })
+        .collect();
 
-    assert!(results.left.is_empty());
-    assert!(results.both.is_empty());
-    assert_eq!(results.right.len(), 2);
+    serde_json::json!({
+        "name": command.get_name(),
+        "about": help(command.get_long_about().or(command.get_about())),
+        "args": args,
+        "subcommands": command
+            .get_subcommands()
+            .map(command_schema)
+            .collect::<Vec<_>>(),
+    })
 }
 
 #[test]
-fn split_mix_has_expected_values() {
-    let some_sha256_sum_l: Sha256Sum = [1u8; 32];
-    let some_sha256_sum_r: Sha256Sum = [2u8; 32];
-    let some_sha256_sum_b: Sha256Sum = [4u8; 32];
+fn command_schema_describes_args_and_subcommands() {
+    let mut command = Args::command();
+    command.build();
+    let schema = command_schema(&command);
 
-    let mut left: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
-    left.insert(
-        some_sha256_sum_l,
-        vec!["lpath1_a".into(), "lpath2_a".into()],
-    );
-    left.insert(some_sha256_sum_b.clone(), vec!["bpath1_l".into()]);
+    let arg = |schema: &serde_json::Value, id: &str| {
+        schema["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|arg| arg["id"] == id)
+            .cloned()
+            .unwrap()
+    };
 
-    let mut right: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
-    right.insert(some_sha256_sum_r, vec!["rpath1".into()]);
-    right.insert(
-        some_sha256_sum_b.clone(),
-        vec!["bpath1_r".into(), "bpath2_r".into()],
-    );
+    let format = arg(&schema, "format");
+    assert_eq!(format["long"], "format");
+    assert_eq!(format["takes_value"], true);
+    assert!(format["possible_values"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|value| value["name"] == "jsonl"));
+    assert_eq!(arg(&schema, "left")["multiple"], true);
 
-    let mut results: Locations = split_into_locations(left, right);
+    let verify = schema["subcommands"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|subcommand| subcommand["name"] == "verify")
+        .unwrap();
+    assert_eq!(arg(verify, "manifest")["required"], true);
+}
+
+// Removes "compare" from the start of the command line, so that it parses
+// the same as the command line without it.
+fn without_compare_command(mut command_line: Vec<OsString>) -> Vec<OsString> {
+    if command_line.get(1).is_some_and(|arg| arg == "compare") {
+        command_line.remove(1);
+    }
+    command_line
+}
+
+#[test]
+fn compare_command_is_the_default() {
+    let args = |command_line: &[&str]| {
+        without_compare_command(command_line.iter().map(OsString::from).collect())
+    };
 
-    results.left.sort_unstable();
     assert_eq!(
-        results.left,
-        vec![
-            path::PathBuf::from("lpath1_a"),
-            path::PathBuf::from("lpath2_a")
-        ]
+        args(&["find-dups", "compare", "-l", "a"]),
+        args(&["find-dups", "-l", "a"])
     );
     assert_eq!(
-        results.both,
-        vec![(
-            vec![path::PathBuf::from("bpath1_l")],
-            vec![
-                path::PathBuf::from("bpath1_r"),
-                path::PathBuf::from("bpath2_r")
-            ]
-        )]
+        args(&["find-dups", "-l", "compare"]),
+        vec![OsString::from("find-dups"), "-l".into(), "compare".into()]
     );
-    assert_eq!(results.right, vec![path::PathBuf::from("rpath1")]);
 }
 
-impl WorkResult {
-    fn from_err(path: PathLocation, err: io::Error) -> WorkResult {
-        WorkResult {
-            path,
-            result: Err(err),
-        }
+#[test]
+fn changing_files_conflicts_with_normalizing() {
+    let parses = |extra: &[&str]| {
+        let mut command_line = vec!["find-dups", "-l", "a", "-r", "b"];
+        command_line.extend(extra);
+        Args::command().try_get_matches_from(command_line).is_ok()
+    };
+
+    assert!(parses(&["--move-duplicates-to", "held"]));
+    assert!(!parses(&[
+        "--move-duplicates-to",
+        "held",
+        "--normalize-whitespace"
+    ]));
+    assert!(!parses(&[
+        "--move-duplicates-to",
+        "held",
+        "--normalize-encoding"
+    ]));
+    assert!(!parses(&[
+        "--link-duplicates",
+        "reflink",
+        "--normalize-whitespace"
+    ]));
+    assert!(!parses(&["--action", "symlink", "--normalize-encoding"]));
+}
+
+fn main() -> io::Result<()> {
+    let matches =
+        Args::command().get_matches_from(without_compare_command(std::env::args_os().collect()));
+    // The command's own required options may be missing, so this is
+    // checked before the matches are turned into Args.
+    if matches.get_flag("help_json") {
+        print_help_json(&matches);
+        return Ok(());
     }
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    fn from_hash<H: Into<Sha256Sum>>(path: PathLocation, hash: H) -> WorkResult {
-        WorkResult {
-            path,
-            result: Ok(hash.into()),
-        }
+    match args.link_duplicates {
+        Some(LinkKind::Soft) => args.action = Some(Action::Symlink),
+        Some(LinkKind::Reflink) => args.action = Some(Action::Reflink),
+        None => {}
+    }
+
+    let changes_files = args.action.is_some() || args.move_duplicates_to.is_some();
+    #[cfg(feature = "tui")]
+    let changes_files = changes_files || args.tui;
+    if args.dry_run && !changes_files {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--dry-run needs --action, --link-duplicates or --move-duplicates-to",
+            )
+            .exit();
+    }
+
+    #[cfg(feature = "tui")]
+    if args.tui && !io::IsTerminal::is_terminal(&io::stdout()) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--tui needs standard output to be a terminal",
+            )
+            .exit();
+    }
+
+    if !args.cross_check.is_empty()
+        && args
+            .cross_check
+            .iter()
+            .all(|algorithm| *algorithm == args.cross_check[0])
+    {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::InvalidValue,
+                "--cross-check needs at least two different algorithms, like sha256,blake3",
+            )
+            .exit();
     }
-}
 
-impl fmt::Display for WorkResult {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.result {
-            Ok(hash) => write!(f, "OK: {} : {}", self.path, hex::encode(hash)),
-            Err(err) => write!(f, "ERROR: {} : {}", self.path, err),
+    if args.background {
+        // This needs to happen before any worker threads are started, as
+        // some platforms track priority per thread and new threads inherit
+        // it from the thread that spawned them.
+        if let Err(e) = enter_background_mode() {
+            eprintln!("WARN: unable to lower process priority: {}", e);
         }
     }
-}
 
-impl PathLocation {
-    fn new_left<P: AsRef<path::Path>>(path: P) -> PathLocation {
-        PathLocation::Left(path.as_ref().to_path_buf())
+    if args.format != OutputFormat::Text {
+        check_text_only_flags(&args);
+    } else if !args.progress {
+        start_pager(args.pager);
     }
 
-    fn new_right<P: AsRef<path::Path>>(path: P) -> PathLocation {
-        PathLocation::Right(path.as_ref().to_path_buf())
+    let num_threads = args
+        .threads
+        .map_or_else(default_num_threads, NonZeroUsize::get);
+
+    if let Some(command) = &args.command {
+        return match command {
+            Command::Compare => unreachable!("'compare' is removed before parsing"),
+            Command::Ingest(ingest_args) => ingest(ingest_args, num_threads, args.all_errors),
+            Command::Verify(verify_args) => verify(verify_args, num_threads, args.all_errors),
+            Command::Bench(bench_args) => bench(bench_args, num_threads),
+            Command::Index { command } => match command {
+                IndexCommand::Build(build_args) => {
+                    build_index(build_args, num_threads, args.all_errors)
+                }
+                IndexCommand::Query(query_args) => {
+                    query_index(query_args, num_threads, args.all_errors)
+                }
+            },
+            Command::Import(import_args) => import(import_args, num_threads, args.all_errors),
+        };
     }
 
-    fn new_same_side<P: AsRef<path::Path>>(other: &PathLocation, path: P) -> PathLocation {
-        match other {
-            PathLocation::Left(_) => PathLocation::Left(path.as_ref().to_path_buf()),
-            PathLocation::Right(_) => PathLocation::Right(path.as_ref().to_path_buf()),
+    if let Some(sides) = &args.sides {
+        for root in read_sides_file(sides)? {
+            if let Some(readers) = root.max_readers {
+                args.max_readers_per_root.push(ReaderLimit {
+                    root: Some(path::PathBuf::from(&root.path)),
+                    readers,
+                });
+            }
+            match root.side {
+                Side::Left => args.left.push(root.path),
+                Side::Right => args.right.push(root.path),
+            }
         }
     }
 
-    fn path(&self) -> &path::Path {
-        match self {
-            PathLocation::Left(path) => path,
-            PathLocation::Right(path) => path,
+    // Each is labeled with the flag it was given with, as --swap moves it
+    // to the other side along with its roots.
+    let excluded_paths: HashMap<path::PathBuf, &'static str> = side_excludes_arg(
+        "--left-exclude",
+        &args.left_exclude,
+        &args.left,
+        &args.right,
+    )
+    .chain(side_excludes_arg(
+        "--right-exclude",
+        &args.right_exclude,
+        &args.right,
+        &args.left,
+    ))
+    .collect();
+
+    if args.swap {
+        std::mem::swap(&mut args.left, &mut args.right);
+        std::mem::swap(&mut args.left_listing, &mut args.right_listing);
+        std::mem::swap(&mut args.left_manifest, &mut args.right_manifest);
+    }
+
+    let streams: HashSet<path::PathBuf> = strip_stream_prefixes(&mut args.left)
+        .into_iter()
+        .chain(strip_stream_prefixes(&mut args.right))
+        .collect();
+    if !streams.is_empty() && args.prefilter {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "'fifo:' roots can't be read twice, so they can't be used with --prefilter",
+            )
+            .exit();
+    }
+
+    let profile = args.profile_out.as_ref().map(|profile_path| {
+        let profile = Arc::new(Profile::default());
+        let _ = PROFILE.set((profile_path.clone(), Arc::clone(&profile)));
+        profile
+    });
+
+    let scan_progress = (args.progress || (args.control_fd.is_some() && !args.prefilter))
+        .then(|| Arc::new(ScanProgress::default()));
+    let scan_control = args.control_fd.map(|_| Arc::new(ScanControl::default()));
+    let walk_options = Arc::new(WalkOptions {
+        follow_symlinks: args.follow_symlinks,
+        progress: scan_progress.clone(),
+        control: scan_control.clone(),
+        throttle: (!args.no_error_backoff).then(|| Arc::new(Throttle::default())),
+        profile: profile.clone(),
+        streams,
+        exclude: patterns_arg("--exclude", &args.exclude),
+        excluded_paths,
+        outputs: outputs_below_roots(&args),
+        include: patterns_arg("--include", &args.include),
+        respect_gitignore: args.respect_gitignore,
+        max_depth: args.max_depth,
+        paths: match &args.paths_file {
+            None => None,
+            Some(paths_file) => {
+                let relative = read_paths_file(paths_file)?;
+                let roots: Vec<OsString> = args.left.iter().chain(&args.right).cloned().collect();
+                Some(Arc::new(PathList::new(&roots, &relative)))
+            }
+        },
+        skipped: Arc::default(),
+    });
+
+    if args.stats_only {
+        return print_stats(&args, &walk_options);
+    }
+
+    if !args.left_listing.is_empty() || !args.right_listing.is_empty() {
+        return compare_structure(&args, &walk_options);
+    }
+
+    exit_if_roots_are_invalid(&args, &walk_options);
+
+    let hash_options = Arc::new(HashOptions {
+        normalize_encoding: args.normalize_encoding,
+        normalize_whitespace: args.normalize_whitespace.then_some(args.indentation),
+        structured_formats: args.normalize.clone(),
+        partial: false,
+    });
+
+    if !args.side.is_empty() {
+        return compare_named_sides(&args, &hash_options, &walk_options, num_threads);
+    }
+
+    if let Some((lpath, rpath)) = two_file_comparison(&args) {
+        let outcome = compare_two_files(&args, &lpath, &rpath, &hash_options)?;
+        finish(outcome);
+    }
+
+    if is_single_tree(&args) {
+        check_two_sided_flags(&args);
+    }
+
+    let scanned = scan_sides(&args, &walk_options, &hash_options, num_threads)?;
+    if !args.cross_check.is_empty() {
+        report_cross_check(&args, &scanned, num_threads);
+    }
+    if is_single_tree(&args) {
+        report_duplicates_within(&args, scanned, &profile)
+    } else {
+        report_comparison(&args, &walk_options, scanned, &profile, num_threads)
+    }
+}
+
+// Checks every root before any are scanned, reporting all the problems
+// found and exiting if there are any.
+fn exit_if_roots_are_invalid(args: &Args, walk_options: &WalkOptions) {
+    let root_problems: Vec<String> = validate_roots(&args.left, walk_options)
+        .into_iter()
+        .map(|problem| format!("left-hand root {}", problem))
+        .chain(
+            validate_roots(&args.right, walk_options)
+                .into_iter()
+                .map(|problem| format!("right-hand root {}", problem)),
+        )
+        .collect();
+    if !root_problems.is_empty() {
+        for problem in &root_problems {
+            eprintln!("ERROR: {}", problem);
         }
+        eprintln!(
+            "ERROR: {} root(s) can't be scanned. Nothing was compared.",
+            root_problems.len()
+        );
+        std::process::exit(EXIT_INVALID_ROOTS);
     }
 }
 
-impl fmt::Display for PathLocation {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            PathLocation::Left(path) => write!(f, "<= '{}'", path.display()),
-            PathLocation::Right(path) => write!(f, "=> '{}'", path.display()),
+// What scanning both sides found, for the reports made from it.
+struct Scanned {
+    left: DigestMap<Vec<path::PathBuf>>,
+    right: DigestMap<Vec<path::PathBuf>>,
+    dir_counts: HashMap<path::PathBuf, (usize, usize)>,
+    left_by_rel_path: HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    right_by_rel_path: HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    // Only kept when something needs more than each file's hash.
+    hashed_files: HashMap<path::PathBuf, HashedFile>,
+    left_sizes: HashMap<path::PathBuf, u64>,
+    file_ids: HashMap<path::PathBuf, FileId>,
+    files_compared: usize,
+    bytes_compared: u64,
+    started: time::SystemTime,
+    start: time::Instant,
+}
+
+// With only one side, the files within it are compared to each other.
+fn is_single_tree(args: &Args) -> bool {
+    args.right.is_empty() && args.right_manifest.is_none()
+}
+
+// Walks and hashes both sides, along with any manifests, writing the
+// records asked for as the results come in and reporting the errors and
+// skipped paths once they're all in.
+fn scan_sides(
+    args: &Args,
+    walk_options: &Arc<WalkOptions>,
+    hash_options: &Arc<HashOptions>,
+    num_threads: usize,
+) -> io::Result<Scanned> {
+    let scan_progress = &walk_options.progress;
+    let scan_control = &walk_options.control;
+    let profile = &walk_options.profile;
+
+    // The structural walk runs alongside hashing so that it doesn't delay
+    // it. It only touches metadata, so it finishes long before hashing.
+    let early_structure = args.early_structure.then(|| {
+        let args = args.clone();
+        let walk_options = Arc::clone(walk_options);
+        thread::spawn(move || print_early_structure(&args, &walk_options))
+    });
+
+    let control_pipe = match (args.control_fd, &scan_control) {
+        (Some(fd), Some(scan_control)) => Some(start_control(fd, scan_control, scan_progress)?),
+        _ => None,
+    };
+
+    let mut hash_record = match &args.record_all_hashes {
+        None => None,
+        Some(record_path) => {
+            let mut writer = io::BufWriter::new(fs::File::create(record_path)?);
+            writer.write_all(HASH_RECORD_MAGIC)?;
+            Some(writer)
         }
+    };
+
+    let mut manifest: Option<Vec<(path::PathBuf, Sha256Sum)>> =
+        args.emit_manifest.as_ref().map(|_| Vec::new());
+
+    let manifest_results = read_manifests(args, scan_progress)?;
+
+    let started = time::SystemTime::now();
+    let start = time::Instant::now();
+    if let Some(profile) = &profile {
+        profile.phase("scan");
     }
+
+    let (worker_threads, results): (Vec<thread::JoinHandle<()>>, Box<dyn Iterator<Item = _>>) =
+        if args.prefilter {
+            let results = scan_with_prefilter(
+                &args.left,
+                &args.right,
+                &args.max_readers_per_root,
+                hash_options,
+                walk_options,
+                num_threads,
+            );
+            (Vec::new(), Box::new(results.into_iter()))
+        } else {
+            let (worker_threads, results_receiver) = start_scan(
+                &args.left,
+                &args.right,
+                &args.max_readers_per_root,
+                hash_options,
+                walk_options,
+                num_threads,
+            );
+            let results = manifest_results.into_iter().chain(results_receiver);
+            (worker_threads, Box::new(results))
+        };
+
+    let progress_bar = scan_progress
+        .clone()
+        .filter(|_| args.progress)
+        .map(show_progress);
+
+    let mut scanned = Scanned {
+        left: DigestMap::default(),
+        right: DigestMap::default(),
+        dir_counts: HashMap::new(),
+        left_by_rel_path: HashMap::new(),
+        right_by_rel_path: HashMap::new(),
+        hashed_files: HashMap::new(),
+        left_sizes: HashMap::new(),
+        file_ids: HashMap::new(),
+        files_compared: 0,
+        bytes_compared: 0,
+        started,
+        start,
+    };
+    let keep_hashed_files = args.action.is_some()
+        || args.move_duplicates_to.is_some()
+        || is_single_tree(args)
+        || matches!(args.format, OutputFormat::Csv | OutputFormat::Tsv);
+    #[cfg(feature = "tui")]
+    let keep_hashed_files = keep_hashed_files || args.tui;
+
+    let mut unreadable_dirs: usize = 0;
+    let mut error_log = ErrorLog::new(args.all_errors);
+
+    for work_result in results {
+        if let Some(scan_progress) = &scan_progress {
+            match &work_result.result {
+                Ok(hashed) => scan_progress.add_hashed(hashed.size.unwrap_or(0)),
+                Err(_) if !work_result.is_dir => scan_progress.add_hashed(0),
+                Err(_) => {}
+            }
+        }
+
+        if let Err(e) = &work_result.result {
+            if args.skip_unreadable_dirs_silently
+                && work_result.is_dir
+                && e.kind() == io::ErrorKind::PermissionDenied
+            {
+                unreadable_dirs += 1;
+            } else if args.format == OutputFormat::Jsonl {
+                print_json_record(serde_json::json!({
+                    "type": "error",
+                    "side": side_name(&work_result.path),
+                    "path": work_result.path.path().to_string_lossy(),
+                    "error": e.to_string(),
+                }));
+            } else {
+                error_log.report(&work_result);
+            }
+            continue;
+        }
+
+        let hashed = work_result.result.unwrap();
+        scanned.files_compared += 1;
+        scanned.bytes_compared += hashed.size.unwrap_or(0);
+
+        if args.format == OutputFormat::Jsonl {
+            print_json_record(serde_json::json!({
+                "type": "file",
+                "side": side_name(&work_result.path),
+                "path": work_result.path.path().to_string_lossy(),
+                "hash": hex::encode(hashed.hash),
+                "size": hashed.size,
+                "mtime": hashed.modified.map(|m| format_modified(Some(m))),
+            }));
+        }
+
+        if let Some(writer) = &mut hash_record {
+            write_hash_record(writer, work_result.path.path(), &hashed)?;
+        }
+
+        if args.count_parity {
+            add_to_dir_counts(&mut scanned.dir_counts, args, &work_result.path);
+        }
+
+        let rel_path = relative_path(roots_for(args, &work_result.path), work_result.path.path())
+            .to_path_buf();
+        let key = identity_key(args.identity, &hashed.hash, &rel_path);
+
+        if let Some(manifest) = &mut manifest {
+            match (args.emit_manifest_side, &work_result.path) {
+                (None, path) => manifest.push((path.path().to_path_buf(), hashed.hash)),
+                (Some(Side::Left), PathLocation::Left(_))
+                | (Some(Side::Right), PathLocation::Right(_)) => {
+                    manifest.push((rel_path.clone(), hashed.hash))
+                }
+                _ => {}
+            }
+        }
+
+        scanned.add(
+            args,
+            work_result.path,
+            rel_path,
+            key,
+            hashed,
+            keep_hashed_files,
+        );
+    }
+
+    join_worker_threads(worker_threads);
+    if let Some(profile) = &profile {
+        profile.phase("aggregate");
+    }
+
+    if let Some(progress_bar) = progress_bar {
+        progress_bar.finish_and_clear();
+    }
+
+    if let Some(control_pipe) = &control_pipe {
+        control_pipe.send_status(true);
+        if control_pipe.control.state() == ControlState::Cancelled {
+            drop(hash_record);
+            remove_incomplete_records(args);
+            eprintln!("Cancelled. The comparison is incomplete, so nothing is reported.");
+            write_profile();
+            std::process::exit(EXIT_CANCELLED);
+        }
+    }
+
+    if let Some(mut writer) = hash_record {
+        writer.flush()?;
+    }
+
+    if let (Some(manifest_path), Some(manifest)) = (&args.emit_manifest, manifest) {
+        write_emitted_manifest(manifest_path, manifest)?;
+    }
+
+    if let Some(early_structure) = early_structure {
+        if let Err(e) = early_structure.join() {
+            panic::resume_unwind(e);
+        }
+    }
+
+    error_log.summarize();
+    report_skipped(args, &walk_options.skipped.take());
+
+    if unreadable_dirs > 0 {
+        eprintln!(
+            "WARN: {} directories could not be read due to missing permissions and were skipped. The comparison does not cover them.",
+            unreadable_dirs
+        );
+        eprintln!("WARN: To include them, re-run with elevated privileges:");
+        eprintln!("WARN:   {}", elevated_command_line());
+    }
+
+    Ok(scanned)
+}
+
+impl Scanned {
+    // Adds a file that was hashed, under its key for the --identity asked
+    // for.
+    fn add(
+        &mut self,
+        args: &Args,
+        path: PathLocation,
+        rel_path: path::PathBuf,
+        key: Sha256Sum,
+        hashed: HashedFile,
+        keep_hashed_files: bool,
+    ) {
+        if let Some(file_id) = hashed.file_id {
+            self.file_ids.insert(path.path().to_path_buf(), file_id);
+        }
+
+        match path {
+            PathLocation::Left(path) => {
+                match hashed.size {
+                    Some(size) if args.simulate_strategies => {
+                        self.left_sizes.insert(path.clone(), size);
+                    }
+                    _ => {}
+                }
+                if keep_hashed_files {
+                    self.hashed_files.insert(path.clone(), hashed);
+                }
+                add_to_rel_path_map(&mut self.left_by_rel_path, rel_path, path.clone(), hashed);
+                add_to_result_hash_map(&mut self.left, key, path)
+            }
+            PathLocation::Right(path) => {
+                if keep_hashed_files {
+                    self.hashed_files.insert(path.clone(), hashed);
+                }
+                add_to_rel_path_map(&mut self.right_by_rel_path, rel_path, path.clone(), hashed);
+                add_to_result_hash_map(&mut self.right, key, path)
+            }
+        }
+    }
+}
+
+// Reads the --left-manifest and --right-manifest files. The files in them
+// are taken as already hashed. Their size and modification time aren't
+// known, so they're left out of anything that needs them.
+fn read_manifests(
+    args: &Args,
+    scan_progress: &Option<Arc<ScanProgress>>,
+) -> io::Result<Vec<WorkResult>> {
+    let mut manifest_results: Vec<WorkResult> = Vec::new();
+    let manifests = [
+        (&args.left_manifest, Side::Left),
+        (&args.right_manifest, Side::Right),
+    ];
+    for (manifest_path, side) in manifests {
+        let manifest_path = match manifest_path {
+            None => continue,
+            Some(manifest_path) => manifest_path,
+        };
+        for (path, hash) in read_manifest(manifest_path)? {
+            if let Some(scan_progress) = scan_progress {
+                scan_progress.add_file(0);
+            }
+            manifest_results.push(WorkResult {
+                path: match side {
+                    Side::Left => PathLocation::Left(path),
+                    Side::Right => PathLocation::Right(path),
+                },
+                result: Ok(HashedFile {
+                    hash,
+                    size: None,
+                    modified: None,
+                    file_id: None,
+                }),
+                is_dir: false,
+            });
+        }
+    }
+    Ok(manifest_results)
+}
+
+#[test]
+fn trees_compare_against_manifests() {
+    let root = TempDir::new("against");
+    let tree = root.join("tree");
+    fs::create_dir_all(&tree).unwrap();
+    fs::write(tree.join("same"), b"same").unwrap();
+    fs::write(tree.join("changed"), b"changed since").unwrap();
+
+    let mut manifest = Vec::new();
+    for (name, contents) in [("same", "same"), ("changed", "changed"), ("missing", "x")] {
+        let hash: Sha256Sum = Sha256::digest(contents).into();
+        write_manifest_line(&mut manifest, &hash, path::Path::new(name)).unwrap();
+    }
+    let manifest_path = root.join("manifest");
+    fs::write(&manifest_path, manifest).unwrap();
+
+    let args = Args::try_parse_from([
+        "find-dups".into(),
+        "--left-manifest".into(),
+        manifest_path.into_os_string(),
+        "-r".into(),
+        tree.clone().into_os_string(),
+    ])
+    .unwrap();
+    let scanned = scan_sides(
+        &args,
+        &Arc::new(WalkOptions::default()),
+        &Arc::new(HashOptions::default()),
+        2,
+    )
+    .unwrap();
+
+    // Only the tree's files have sizes to count.
+    assert_eq!(scanned.bytes_compared, 4 + 13);
+    assert_eq!(
+        scanned.left_by_rel_path[path::Path::new("same")].1.size,
+        None
+    );
+    assert!(
+        find_bit_rot_candidates(&scanned.left_by_rel_path, &scanned.right_by_rel_path).is_empty()
+    );
+    let conflicts = find_conflicts(&scanned.left_by_rel_path, &scanned.right_by_rel_path);
+    let conflicts: Vec<&path::Path> = conflicts.iter().map(|c| c.rel_path).collect();
+    assert_eq!(conflicts, [path::Path::new("changed")]);
+
+    let mut locations = split_into_locations(scanned.left, scanned.right);
+    locations.sort();
+    assert_eq!(locations.right, [tree.join("changed")]);
+    assert_eq!(
+        locations.left,
+        [
+            path::PathBuf::from("changed"),
+            path::PathBuf::from("missing")
+        ]
+    );
+    assert_eq!(
+        locations.both,
+        [(vec![path::PathBuf::from("same")], vec![tree.join("same")])]
+    );
+}
+
+#[test]
+fn manifests_compare_against_each_other() {
+    let root = TempDir::new("manifests");
+
+    let write = |name: &str, entries: &[(&str, &str)]| {
+        let mut manifest = Vec::new();
+        for (path, contents) in entries {
+            let hash: Sha256Sum = Sha256::digest(contents).into();
+            write_manifest_line(&mut manifest, &hash, path::Path::new(path)).unwrap();
+        }
+        fs::write(root.join(name), manifest).unwrap();
+        root.join(name).into_os_string()
+    };
+    let left = write("left", &[("same", "same"), ("changed", "before")]);
+    let right = write("right", &[("same", "same"), ("changed", "after")]);
+
+    let args = Args::try_parse_from([
+        "find-dups".into(),
+        "--left-manifest".into(),
+        left,
+        "--right-manifest".into(),
+        right,
+    ])
+    .unwrap();
+    let scanned = scan_sides(
+        &args,
+        &Arc::new(WalkOptions::default()),
+        &Arc::new(HashOptions::default()),
+        2,
+    )
+    .unwrap();
+
+    // Neither side has sizes, so nothing looks like bit rot.
+    assert_eq!(scanned.files_compared, 4);
+    assert_eq!(scanned.bytes_compared, 0);
+    assert!(
+        find_bit_rot_candidates(&scanned.left_by_rel_path, &scanned.right_by_rel_path).is_empty()
+    );
+    let conflicts = find_conflicts(&scanned.left_by_rel_path, &scanned.right_by_rel_path);
+    let conflicts: Vec<&path::Path> = conflicts.iter().map(|c| c.rel_path).collect();
+    assert_eq!(conflicts, [path::Path::new("changed")]);
+}
+
+// Writes the --emit-manifest file, sorted by path.
+fn write_emitted_manifest(
+    manifest_path: &path::Path,
+    mut manifest: Vec<(path::PathBuf, Sha256Sum)>,
+) -> io::Result<()> {
+    manifest.sort_unstable();
+    let mut out = io::BufWriter::new(fs::File::create(manifest_path)?);
+    for (path, hash) in manifest.iter() {
+        write_manifest_line(&mut out, hash, path)?;
+    }
+    out.flush()
+}
+
+// Hashes a sample of the files again with each --cross-check algorithm and
+// reports the files that they group differently.
+fn report_cross_check(args: &Args, scanned: &Scanned, num_threads: usize) {
+    let sample = sample_for_cross_check(&scanned.left, &scanned.right);
+    let (checked, disagreements) = cross_check(&sample, &args.cross_check, num_threads);
+
+    for disagreement in &disagreements {
+        eprintln!(
+            "WARN: {} gives '{}' the same digest as '{}', but {} doesn't.",
+            disagreement.grouped_by.name(),
+            disagreement.path.display(),
+            disagreement.other.display(),
+            disagreement.split_by.name()
+        );
+    }
+
+    if disagreements.is_empty() {
+        let names: Vec<&str> = args.cross_check.iter().map(|a| a.name()).collect();
+        eprintln!(
+            "Cross-checked {} files: {} agree.",
+            checked,
+            names.join(" and ")
+        );
+    } else {
+        eprintln!(
+            "Cross-check failed: {} disagreements found.",
+            disagreements.len()
+        );
+    }
+}
+
+// Reports the groups of duplicates within a single tree, or browses them
+// with --tui.
+fn report_duplicates_within(
+    args: &Args,
+    scanned: Scanned,
+    profile: &Option<Arc<Profile>>,
+) -> io::Result<()> {
+    let Scanned {
+        left,
+        hashed_files,
+        file_ids,
+        files_compared,
+        bytes_compared,
+        started,
+        start,
+        ..
+    } = scanned;
+
+    let groups = duplicates_within(left, &file_ids);
+    if let Some(profile) = &profile {
+        profile.phase("output");
+    }
+    let outcome = if files_compared == 0 {
+        Outcome::NoFiles
+    } else if groups.is_empty() {
+        Outcome::NoDuplicates
+    } else {
+        Outcome::Differences
+    };
+
+    #[cfg(feature = "tui")]
+    if args.tui {
+        let groups = groups
+            .iter()
+            .map(|paths| tui::Group::within(paths))
+            .collect();
+        tui::browse_and_apply(groups, &hashed_files, args.symlink_style, args.dry_run)?;
+        finish(outcome);
+    }
+
+    print_duplicates_within(args, &groups, &hashed_files);
+    if args.format == OutputFormat::Text && args.footer {
+        let _ = FOOTER.set((started, start, files_compared, bytes_compared));
+    }
+    finish(outcome);
+}
+
+// Reports how the two sides compare, then makes the changes asked for to
+// the files on both.
+fn report_comparison(
+    args: &Args,
+    walk_options: &WalkOptions,
+    scanned: Scanned,
+    profile: &Option<Arc<Profile>>,
+    num_threads: usize,
+) -> io::Result<()> {
+    let Scanned {
+        mut left,
+        mut right,
+        dir_counts,
+        left_by_rel_path,
+        right_by_rel_path,
+        hashed_files,
+        left_sizes,
+        file_ids,
+        files_compared,
+        bytes_compared,
+        started,
+        start,
+    } = scanned;
+
+    report_bit_rot(args, &left_by_rel_path, &right_by_rel_path);
+    report_case_collisions(args, &left_by_rel_path);
+
+    let structure_differs =
+        args.strict_structure && report_structure_differences(args, walk_options);
+    let conflicts_differ = report_conflicts(args, &left_by_rel_path, &right_by_rel_path);
+
+    if args.infer_direction {
+        report_direction(&left_by_rel_path, &right_by_rel_path);
+    }
+
+    if args.count_parity {
+        report_count_parity(dir_counts);
+    }
+
+    let empty_dirs_differ = args.report_empty_dirs && report_empty_dirs(args, walk_options);
+
+    #[cfg(feature = "images")]
+    let photo_clusters = args.cluster_photos.then(|| {
+        let photos = left
+            .values()
+            .flatten()
+            .map(PathLocation::new_left)
+            .chain(right.values().flatten().map(PathLocation::new_right))
+            .filter(|path| is_photo(path.path()))
+            .collect();
+        cluster_photos(fingerprint_photos(photos, num_threads), args.photo_distance)
+    });
+
+    if let Some(acknowledge_file) = &args.acknowledge_file {
+        let acks = Acknowledgements::read_from(acknowledge_file)?;
+        remove_acknowledged(&mut left, &mut right, &acks);
+    }
+
+    let mut locations = split_into_locations(left, right);
+    locations.sort();
+
+    // Links to the same file don't need to be compared byte for byte.
+    let hardlinked = take_hardlinked(&mut locations, &file_ids);
+
+    if args.paranoid {
+        let mut error_log = ErrorLog::new(args.all_errors);
+        for work_result in verify_both_byte_by_byte(&mut locations, num_threads) {
+            error_log.report(&work_result);
+        }
+        error_log.summarize();
+    }
+
+    if let Some(profile) = &profile {
+        profile.phase("output");
+    }
+
+    let outcome = if files_compared == 0 {
+        Outcome::NoFiles
+    } else if locations.left.is_empty()
+        && locations.right.is_empty()
+        && !empty_dirs_differ
+        && !structure_differs
+        && !conflicts_differ
+    {
+        Outcome::NoDifferences
+    } else {
+        Outcome::Differences
+    };
+
+    #[cfg(feature = "tui")]
+    if args.tui {
+        let groups = locations
+            .both
+            .iter()
+            .map(|(lpaths, rpaths)| tui::Group::from_sides(lpaths, rpaths))
+            .collect();
+        tui::browse_and_apply(groups, &hashed_files, args.symlink_style, args.dry_run)?;
+        finish(outcome);
+    }
+
+    match args.format {
+        OutputFormat::Text => {}
+        OutputFormat::Jsonl => {
+            print_json_locations(args, &locations, &hardlinked);
+            finish(outcome);
+        }
+        OutputFormat::Csv => {
+            print_table_locations(args, &locations, &hardlinked, &hashed_files, csv_field, ',');
+            finish(outcome);
+        }
+        OutputFormat::Tsv => {
+            print_table_locations(
+                args,
+                &locations,
+                &hardlinked,
+                &hashed_files,
+                tsv_field,
+                '\t',
+            );
+            finish(outcome);
+        }
+    }
+
+    print_text_locations(args, &locations, &hardlinked);
+
+    if args.simulate_strategies {
+        let simulation = simulate_strategies(&locations.both, &left_sizes, on_same_device);
+        println!(
+            "{:<16} {:>10} {:>16}",
+            "Strategy", "Files", "Bytes reclaimed"
+        );
+        for savings in simulation {
+            println!(
+                "{:<16} {:>10} {:>16}",
+                savings.strategy, savings.files, savings.bytes
+            );
+        }
+    }
+
+    apply_actions(args, &locations, &hashed_files);
+
+    #[cfg(feature = "images")]
+    for cluster in photo_clusters.into_iter().flatten() {
+        println!(
+            "<~> {} similar photos, suggested keeper first",
+            cluster.len()
+        );
+        for photo in cluster {
+            println!(
+                "  {} ({}x{}, {} bytes)",
+                photo.path, photo.width, photo.height, photo.size
+            );
+        }
+    }
+
+    if args.footer {
+        let _ = FOOTER.set((started, start, files_compared, bytes_compared));
+    }
+
+    finish(outcome)
+}
+
+// Reports the files with the same relative path, size, and modification
+// time on both sides, but different content.
+fn report_bit_rot(
+    args: &Args,
+    left_by_rel_path: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    right_by_rel_path: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) {
+    let bit_rot_candidates = find_bit_rot_candidates(left_by_rel_path, right_by_rel_path);
+    match args.format {
+        OutputFormat::Jsonl => {
+            for (lpath, rpath) in &bit_rot_candidates {
+                print_json_record(serde_json::json!({
+                    "type": "bit_rot",
+                    "left": lpath.to_string_lossy(),
+                    "right": rpath.to_string_lossy(),
+                }));
+            }
+        }
+        // Tables only have room for files, so possible corruption is
+        // reported alongside them rather than in them.
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            for (lpath, rpath) in &bit_rot_candidates {
+                eprintln!(
+                    "WARN: '{}' and '{}' have the same relative path, size, and modification time, but different content. One may be corrupted.",
+                    lpath.display(),
+                    rpath.display()
+                );
+            }
+        }
+        OutputFormat::Text => {}
+    }
+
+    if args.format == OutputFormat::Text && !bit_rot_candidates.is_empty() {
+        println!(
+            "!! {} file(s) have the same relative path, size, and modification time on both sides, but different content. They may be corrupted.",
+            bit_rot_candidates.len()
+        );
+        for (lpath, rpath) in bit_rot_candidates {
+            println!("!!");
+            println!("  <= '{}'", lpath.display());
+            println!("  => '{}'", rpath.display());
+        }
+    }
+}
+
+// Comparing is usually done before syncing the left-hand side to the
+// right, which fails for names that only differ by case if the right
+// is on a case-insensitive file system.
+fn report_case_collisions(
+    args: &Args,
+    left_by_rel_path: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) {
+    let case_collisions = find_case_collisions(left_by_rel_path);
+    match args.format {
+        OutputFormat::Jsonl => {
+            for paths in &case_collisions {
+                print_json_record(serde_json::json!({
+                    "type": "unportable_names",
+                    "paths": paths.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>(),
+                }));
+            }
+        }
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            for paths in &case_collisions {
+                let paths: Vec<String> =
+                    paths.iter().map(|p| format!("'{}'", p.display())).collect();
+                eprintln!(
+                    "WARN: {} differ only by case, and would collide on a case-insensitive file system.",
+                    paths.join(" and ")
+                );
+            }
+        }
+        OutputFormat::Text => {
+            if !case_collisions.is_empty() {
+                println!(
+                    "<Aa> unportable names: {} group(s) of left-hand paths differ only by case, and would collide on a case-insensitive file system like those Windows and macOS use.",
+                    case_collisions.len()
+                );
+            }
+            for paths in &case_collisions {
+                println!("<Aa>");
+                for path in paths {
+                    println!("  <= '{}'", path.display());
+                }
+            }
+        }
+    }
+}
+
+// Reports the files with the same relative path on both sides but
+// different content, with --conflicts or --strict-structure. Returns
+// whether there were any that --strict-structure counts as differences.
+fn report_conflicts(
+    args: &Args,
+    left_by_rel_path: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    right_by_rel_path: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) -> bool {
+    let conflicts = if args.conflicts || args.strict_structure {
+        find_conflicts(left_by_rel_path, right_by_rel_path)
+    } else {
+        Vec::new()
+    };
+    let conflicts_differ = args.strict_structure && !conflicts.is_empty();
+    for conflict in conflicts {
+        let (lpath, lhashed) = conflict.left;
+        let (rpath, rhashed) = conflict.right;
+        println!("<!> '{}'", conflict.rel_path.display());
+        println!(
+            "  <= '{}' {}{}",
+            lpath.display(),
+            args.hash_display.prefix(&lhashed.hash, " "),
+            format_modified(lhashed.modified)
+        );
+        println!(
+            "  => '{}' {}{}",
+            rpath.display(),
+            args.hash_display.prefix(&rhashed.hash, " "),
+            format_modified(rhashed.modified)
+        );
+    }
+
+    conflicts_differ
+}
+
+fn report_direction(
+    left_by_rel_path: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+    right_by_rel_path: &HashMap<path::PathBuf, (path::PathBuf, HashedFile)>,
+) {
+    let evidence = infer_direction(left_by_rel_path, right_by_rel_path);
+    match evidence.source() {
+        Some(Source::Left) => println!("<?> left appears to be the source, right its mirror"),
+        Some(Source::Right) => println!("<?> right appears to be the source, left its mirror"),
+        None => println!("<?> unable to tell which side is the source"),
+    }
+    println!(
+        "  newer copies of changed files: left: {}, right: {}",
+        evidence.newer_left, evidence.newer_right
+    );
+    println!(
+        "  files on one side only: left: {}, right: {}",
+        evidence.only_left, evidence.only_right
+    );
+}
+
+// Reports the directories with a different number of files on each side.
+fn report_count_parity(dir_counts: HashMap<path::PathBuf, (usize, usize)>) {
+    let mut mismatched: Vec<(path::PathBuf, (usize, usize))> = dir_counts
+        .into_iter()
+        .filter(|(_, (lcount, rcount))| lcount != rcount)
+        .collect();
+    mismatched.sort_unstable();
+
+    for (dir, (lcount, rcount)) in mismatched {
+        println!(
+            "<#> '{}' (left: {}, right: {})",
+            dir.display(),
+            lcount,
+            rcount
+        );
+    }
+}
+
+// Prints the files only on the left, only on the right, and with --show-both
+// the groups on both, as text.
+fn print_text_locations(
+    args: &Args,
+    locations: &Locations,
+    hardlinked: &[(Vec<path::PathBuf>, Vec<path::PathBuf>)],
+) {
+    if !args.omit_left {
+        for path in &locations.left {
+            println!("<= '{}'", path.display());
+        }
+    }
+
+    if !args.omit_right {
+        for path in &locations.right {
+            println!("=> '{}'", path.display());
+        }
+    }
+
+    if args.show_both {
+        for (lpaths, rpaths) in &locations.both {
+            let mirror = classify_mirrored(&args.left, lpaths, &args.right, rpaths);
+            println!(
+                "<=> mirrored: {}, stray left: {}, stray right: {}",
+                mirror.mirrored, mirror.stray_left, mirror.stray_right
+            );
+            for lpath in capped(lpaths, args.max_paths_per_group) {
+                println!("  <= '{}'", lpath.display());
+            }
+            for rpath in capped(rpaths, args.max_paths_per_group) {
+                println!("  => '{}'", rpath.display());
+            }
+            if let Some(max) = args.max_paths_per_group {
+                if lpaths.len() > max || rpaths.len() > max {
+                    println!(
+                        "  ... {} paths on left, {} on right; first {} of each shown",
+                        lpaths.len(),
+                        rpaths.len(),
+                        max
+                    );
+                }
+            }
+        }
+
+        for (lpaths, rpaths) in hardlinked {
+            println!("<=> hardlinked");
+            for lpath in capped(lpaths, args.max_paths_per_group) {
+                println!("  <= '{}'", lpath.display());
+            }
+            for rpath in capped(rpaths, args.max_paths_per_group) {
+                println!("  => '{}'", rpath.display());
+            }
+        }
+    }
+}
+
+// Makes the changes asked for with --action, --link-duplicates or
+// --move-duplicates-to to the files on both sides.
+fn apply_actions(
+    args: &Args,
+    locations: &Locations,
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+) {
+    match args.action {
+        Some(Action::Symlink) => symlink_duplicates(
+            &locations.both,
+            args.symlink_style,
+            hashed_files,
+            args.dry_run,
+        ),
+        Some(Action::Reflink) => reflink_duplicates(&locations.both, hashed_files, args.dry_run),
+        None => {}
+    }
+
+    if let Some(holding_dir) = &args.move_duplicates_to {
+        move_duplicates(
+            &locations.both,
+            &args.right,
+            holding_dir,
+            hashed_files,
+            args.dry_run,
+        );
+    }
+}
+
+// Parses the glob patterns given for a flag, exiting with a usage error if
+// any of them is invalid.
+fn patterns_arg(flag: &str, globs: &[String]) -> Patterns {
+    Patterns::new(globs).unwrap_or_else(|e| {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("invalid pattern for {}: {}", flag, e),
+            )
+            .exit()
+    })
+}
+
+// Checks the paths given for --left-exclude or --right-exclude, exiting
+// with a usage error if any of them can't be left out of just that side.
+// Returns each with the flag it was given with.
+fn side_excludes_arg<'a>(
+    flag: &'static str,
+    paths: &'a [path::PathBuf],
+    roots: &[OsString],
+    other_roots: &[OsString],
+) -> impl Iterator<Item = (path::PathBuf, &'static str)> + 'a {
+    for path in paths {
+        if let Err(e) = check_side_exclude(path, roots, other_roots) {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("invalid path for {}: '{}' {}", flag, path.display(), e),
+                )
+                .exit()
+        }
+    }
+    paths.iter().map(move |path| (path.clone(), flag))
+}
+
+// Finds this run's own output files that are below a root, so that the
+// walk leaves them out, and warns about each.
+fn outputs_below_roots(args: &Args) -> HashMap<path::PathBuf, &'static str> {
+    let outputs = [
+        ("--record-all-hashes", &args.record_all_hashes),
+        ("--emit-manifest", &args.emit_manifest),
+        ("--profile-out", &args.profile_out),
+    ];
+
+    let mut below_roots = HashMap::new();
+    for (flag, output) in outputs {
+        let Some(absolute_output) = output.as_ref().and_then(|o| path::absolute(o).ok()) else {
+            continue;
+        };
+        for root in args.left.iter().chain(&args.right) {
+            let rel_path = path::absolute(root)
+                .ok()
+                .and_then(|absolute_root| {
+                    absolute_output
+                        .strip_prefix(absolute_root)
+                        .ok()
+                        .map(path::Path::to_path_buf)
+                })
+                .filter(|rel_path| !rel_path.as_os_str().is_empty());
+            if let Some(rel_path) = rel_path {
+                eprintln!(
+                    "WARN: the {} file '{}' is below the root '{}', so it's left out.",
+                    flag,
+                    absolute_output.display(),
+                    path::Path::new(root).display()
+                );
+                below_roots.insert(path::Path::new(root).join(rel_path), flag);
+            }
+        }
+    }
+    below_roots
+}
+
+#[test]
+fn own_outputs_are_left_out_of_the_scan() {
+    let root = TempDir::new("outputs");
+    fs::write(root.join("a"), b"a").unwrap();
+    fs::write(root.join("record"), HASH_RECORD_MAGIC).unwrap();
+    let args = Args::try_parse_from([
+        OsString::from("find-dups"),
+        OsString::from("--left"),
+        root.to_path_buf().into(),
+        OsString::from("--record-all-hashes"),
+        root.join("record").into(),
+        OsString::from("--profile-out"),
+        std::env::temp_dir().join("elsewhere").into(),
+    ])
+    .unwrap();
+
+    let outputs = outputs_below_roots(&args);
+    assert_eq!(
+        outputs,
+        HashMap::from([(root.join("record"), "--record-all-hashes")])
+    );
+
+    let walk_options = Arc::new(WalkOptions {
+        outputs,
+        ..WalkOptions::default()
+    });
+    let mut files = Vec::new();
+    list_root(&root, &walk_options, &mut files, &mut Vec::new());
+    let names: Vec<&path::Path> = files.iter().map(|file| file.path.as_path()).collect();
+    assert_eq!(names, [path::Path::new("a")]);
+}
+
+// A path can only be left out of a side if it's below one of the side's
+// roots. The walk checks each path it finds, so one below a root of both
+// sides would be left out of both.
+fn check_side_exclude(
+    path: &path::Path,
+    roots: &[OsString],
+    other_roots: &[OsString],
+) -> Result<(), &'static str> {
+    let below = |roots: &[OsString]| {
+        roots
+            .iter()
+            .any(|root| path != root.as_os_str() && path.starts_with(root))
+    };
+
+    if !below(roots) {
+        Err("isn't below any of the side's roots")
+    } else if below(other_roots) {
+        Err("is also below a root of the other side")
+    } else {
+        Ok(())
+    }
+}
+
+#[test]
+fn side_excludes_must_be_below_only_their_side() {
+    let roots: Vec<OsString> = vec!["/backup1".into(), "/backup2".into()];
+    let other_roots: Vec<OsString> = vec!["/data".into(), "/backup2/nested".into()];
+    let check = |path: &str| check_side_exclude(path::Path::new(path), &roots, &other_roots);
+
+    assert_eq!(check("/backup1/trash"), Ok(()));
+    assert_eq!(check("/backup1/trash/"), Ok(()));
+    assert!(check("/backup1").is_err());
+    assert!(check("/backup10/trash").is_err());
+    assert!(check("/data/trash").is_err());
+    assert!(check("/backup2/nested/trash").is_err());
+}
+
+// Reads the relative paths listed in a --paths-file.
+fn read_paths_file(path: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("unable to read '{}': {}", path.display(), e),
+        )
+    })?;
+
+    let mut paths = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let rel = path::PathBuf::from(line);
+        if rel.has_root() {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "paths in '{}' must be relative to the roots: '{}'",
+                        path.display(),
+                        line
+                    ),
+                )
+                .exit();
+        }
+        paths.push(rel);
+    }
+
+    Ok(paths)
+}
+
+// How many errors of each kind are printed as they happen before the rest
+// are only counted.
+const ERRORS_SHOWN_PER_KIND: usize = 10;
+
+// Prints the errors from a scan as they happen, but only the first few of
+// each kind unless all of them are wanted. The rest are counted by the
+// directory they were in, and summarized at the end.
+struct ErrorLog {
+    show_all: bool,
+    shown: HashMap<io::ErrorKind, usize>,
+    suppressed: BTreeMap<io::ErrorKind, BTreeMap<path::PathBuf, usize>>,
+}
+
+#[derive(Debug, PartialEq)]
+struct SuppressedErrors<'a> {
+    kind: io::ErrorKind,
+    count: usize,
+    dirs: Vec<(&'a path::Path, usize)>,
+}
+
+impl ErrorLog {
+    fn new(show_all: bool) -> ErrorLog {
+        ErrorLog {
+            show_all,
+            shown: HashMap::new(),
+            suppressed: BTreeMap::new(),
+        }
+    }
+
+    fn report(&mut self, work_result: &WorkResult) {
+        let kind = match &work_result.result {
+            Ok(_) => return,
+            Err(e) => e.kind(),
+        };
+
+        let shown = self.shown.entry(kind).or_default();
+        if self.show_all || *shown < ERRORS_SHOWN_PER_KIND {
+            *shown += 1;
+            eprintln!("{}", work_result);
+            return;
+        }
+
+        let dir = work_result
+            .path
+            .path()
+            .parent()
+            .unwrap_or(work_result.path.path());
+        *self
+            .suppressed
+            .entry(kind)
+            .or_default()
+            .entry(dir.to_path_buf())
+            .or_default() += 1;
+    }
+
+    // The errors that weren't printed, by kind, with the directories that
+    // had the most of them first.
+    fn summary(&self) -> Vec<SuppressedErrors<'_>> {
+        self.suppressed
+            .iter()
+            .map(|(kind, dirs)| {
+                let mut dirs: Vec<(&path::Path, usize)> = dirs
+                    .iter()
+                    .map(|(dir, count)| (dir.as_path(), *count))
+                    .collect();
+                dirs.sort_by(|(_, lhs), (_, rhs)| rhs.cmp(lhs));
+                SuppressedErrors {
+                    kind: *kind,
+                    count: dirs.iter().map(|(_, count)| count).sum(),
+                    dirs,
+                }
+            })
+            .collect()
+    }
+
+    fn summarize(&self) {
+        const DIRS_SHOWN: usize = 5;
+
+        for suppressed in self.summary() {
+            eprintln!(
+                "WARN: {} more errors like '{}' weren't printed. Use --all-errors to print them. They were in:",
+                suppressed.count, suppressed.kind
+            );
+            for (dir, count) in suppressed.dirs.iter().take(DIRS_SHOWN) {
+                eprintln!("WARN:   '{}': {}", dir.display(), count);
+            }
+            if suppressed.dirs.len() > DIRS_SHOWN {
+                eprintln!(
+                    "WARN:   and {} other directories",
+                    suppressed.dirs.len() - DIRS_SHOWN
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn error_log_summarizes_errors_past_the_limit_by_directory() {
+    let mut error_log = ErrorLog::new(false);
+    let denied = |path: &str| WorkResult {
+        path: PathLocation::new_left(path),
+        result: Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        is_dir: false,
+    };
+
+    for i in 0..ERRORS_SHOWN_PER_KIND {
+        error_log.report(&denied(&format!("shown/{}", i)));
+    }
+    error_log.report(&denied("a/1"));
+    error_log.report(&denied("b/1"));
+    error_log.report(&denied("b/2"));
+
+    assert_eq!(
+        error_log.summary(),
+        vec![SuppressedErrors {
+            kind: io::ErrorKind::PermissionDenied,
+            count: 3,
+            dirs: vec![(path::Path::new("b"), 2), (path::Path::new("a"), 1)],
+        }]
+    );
+}
+
+// Prints how many entries each filter left out, and, with --show-skipped,
+// each of them.
+fn report_skipped(args: &Args, skipped: &[(String, PathLocation)]) {
+    if args.show_skipped {
+        for (filter, path) in skipped {
+            if args.format == OutputFormat::Jsonl {
+                print_json_record(serde_json::json!({
+                    "type": "skipped",
+                    "side": side_name(path),
+                    "path": path.path().to_string_lossy(),
+                    "filter": filter,
+                }));
+            } else {
+                eprintln!("SKIPPED: {} : {}", path, filter);
+            }
+        }
+    }
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for (filter, _) in skipped {
+        *counts.entry(filter).or_default() += 1;
+    }
+    for (filter, count) in counts {
+        eprintln!("Skipped {} entries: {}", count, filter);
+    }
+}
+
+// Draws a progress bar of the bytes hashed out of those found so far on
+// standard error, until it's finished. The total grows while the walk
+// finds more files, so the estimate of the time left is low until then.
+fn show_progress(scan_progress: Arc<ScanProgress>) -> ProgressBar {
+    let progress_bar = ProgressBar::new(0);
+    progress_bar.set_style(
+        ProgressStyle::with_template(
+            "{msg} [{wide_bar}] {bytes}/{total_bytes} {binary_bytes_per_sec}, ETA {eta}",
+        )
+        .expect("The progress template should be valid"),
+    );
+
+    let updated_bar = progress_bar.clone();
+    thread::spawn(move || {
+        while !updated_bar.is_finished() {
+            let load = |count: &AtomicU64| count.load(atomic::Ordering::Relaxed);
+            updated_bar.set_length(load(&scan_progress.bytes));
+            updated_bar.set_position(load(&scan_progress.hashed_bytes));
+            updated_bar.set_message(format!(
+                "{}/{} files",
+                load(&scan_progress.hashed_files),
+                load(&scan_progress.files)
+            ));
+            thread::sleep(time::Duration::from_millis(100));
+        }
+    });
+
+    progress_bar
+}
+
+// The reply side of a --control-fd descriptor, which reports the state of
+// the scan as one JSON object per line.
+#[derive(Clone)]
+struct ControlPipe {
+    writer: Arc<Mutex<fs::File>>,
+    control: Arc<ScanControl>,
+    progress: Option<Arc<ScanProgress>>,
+}
+
+impl ControlPipe {
+    fn send(&self, record: serde_json::Value) {
+        // The orchestrator going away shouldn't stop the scan.
+        let _ = writeln!(self.writer.lock().unwrap(), "{}", record);
+    }
+
+    fn send_status(&self, finished: bool) {
+        let state = match (finished, self.control.state()) {
+            (true, ControlState::Cancelled) => "cancelled",
+            (true, _) => "finished",
+            (false, ControlState::Running) => "running",
+            (false, ControlState::Paused) => "paused",
+            (false, ControlState::Cancelled) => "cancelling",
+        };
+        let mut record = serde_json::json!({ "state": state });
+        if let Some(progress) = &self.progress {
+            let load = |count: &AtomicU64| count.load(atomic::Ordering::Relaxed);
+            record["files"] = load(&progress.files).into();
+            record["bytes"] = load(&progress.bytes).into();
+            record["hashed_files"] = load(&progress.hashed_files).into();
+            record["hashed_bytes"] = load(&progress.hashed_bytes).into();
+        }
+        self.send(record);
+    }
+}
+
+// Reads commands from the descriptor, one per line, on a thread of its own
+// for as long as it stays open. Every command is answered with the state
+// of the scan after it.
+fn start_control(
+    fd: i32,
+    control: &Arc<ScanControl>,
+    progress: &Option<Arc<ScanProgress>>,
+) -> io::Result<ControlPipe> {
+    let reader = open_control_fd(fd)?;
+    let pipe = ControlPipe {
+        writer: Arc::new(Mutex::new(reader.try_clone()?)),
+        control: Arc::clone(control),
+        progress: progress.clone(),
+    };
+
+    let thread_pipe = pipe.clone();
+    thread::spawn(move || {
+        for line in io::BufReader::new(reader).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let state = match line.trim() {
+                "" => continue,
+                "status" => None,
+                "pause" => Some(ControlState::Paused),
+                "resume" => Some(ControlState::Running),
+                "cancel" => Some(ControlState::Cancelled),
+                command => {
+                    thread_pipe.send(serde_json::json!({
+                        "error": format!("unknown command '{}'", command),
+                    }));
+                    continue;
+                }
+            };
+            if let Some(state) = state {
+                thread_pipe.control.set(state);
+            }
+            thread_pipe.send_status(false);
+        }
+    });
+
+    Ok(pipe)
+}
+
+#[cfg(unix)]
+fn open_control_fd(fd: i32) -> io::Result<fs::File> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fcntl only looks the descriptor up, to make sure it's open
+    // before taking ownership of it. It was handed to the process to use,
+    // like one opened by the shell with `3<>`, and nothing else uses it.
+    unsafe {
+        if libc::fcntl(fd, libc::F_GETFD) == -1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--control-fd {} isn't open", fd),
+            ));
+        }
+        Ok(fs::File::from_raw_fd(fd))
+    }
+}
+
+#[cfg(not(unix))]
+fn open_control_fd(_fd: i32) -> io::Result<fs::File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--control-fd is only supported on Unix",
+    ))
+}
+
+enum Outcome {
+    Differences,
+    NoDifferences,
+    // Only one side was scanned, and no two files in it are the same.
+    NoDuplicates,
+    NoFiles,
+}
+
+// Returns the two files to compare when the sides are a single file each
+// and only the files present in one or both sides were asked for. That
+// case gets a report of its own.
+fn two_file_comparison(args: &Args) -> Option<(path::PathBuf, path::PathBuf)> {
+    let only_locations = args.format == OutputFormat::Text
+        && args.control_fd.is_none()
+        && !args.early_structure
+        && !args.count_parity
+        && !args.report_empty_dirs
+        && !args.strict_structure
+        && !args.conflicts
+        && !args.simulate_strategies
+        && args.action.is_none()
+        && args.move_duplicates_to.is_none()
+        && args.acknowledge_file.is_none()
+        && args.record_all_hashes.is_none()
+        && args.emit_manifest.is_none()
+        && args.cross_check.is_empty()
+        && args.identity == Identity::Content
+        && !args.footer;
+    #[cfg(feature = "images")]
+    let only_locations = only_locations && !args.cluster_photos;
+    #[cfg(feature = "tui")]
+    let only_locations = only_locations && !args.tui;
+
+    match (args.left.as_slice(), args.right.as_slice()) {
+        ([lpath], [rpath]) if only_locations => {
+            let is_file = |path: &OsString| fs::metadata(path).is_ok_and(|m| m.is_file());
+            (is_file(lpath) && is_file(rpath)).then(|| (lpath.into(), rpath.into()))
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn two_files_are_compared_directly() {
+    let root = TempDir::new("two-files");
+    for (name, contents) in [("a", "same"), ("b", "same"), ("c", "diff"), ("d", "longer")] {
+        fs::write(root.join(name), contents).unwrap();
+    }
+    let args = |extra: &[&str], lname: &str, rname: &str| {
+        let mut command_line: Vec<OsString> = vec!["find-dups".into()];
+        command_line.extend(extra.iter().map(OsString::from));
+        command_line.extend(["-l".into(), root.join(lname).into()]);
+        command_line.extend(["-r".into(), root.join(rname).into()]);
+        Args::try_parse_from(command_line).unwrap()
+    };
+    let compare = |lname: &str, rname: &str| {
+        let args = args(&[], lname, rname);
+        let (lpath, rpath) = two_file_comparison(&args).unwrap();
+        compare_two_files(&args, &lpath, &rpath, &Arc::new(HashOptions::default())).unwrap()
+    };
+
+    assert!(matches!(compare("a", "b"), Outcome::NoDifferences));
+    assert!(matches!(compare("a", "c"), Outcome::Differences));
+    assert!(matches!(compare("a", "d"), Outcome::Differences));
+    // Anything more than the locations needs the full comparison.
+    assert!(two_file_comparison(&args(&["--conflicts"], "a", "b")).is_none());
+    // Names and relative paths are only compared by the full comparison.
+    assert!(two_file_comparison(&args(&["--identity", "content+name"], "a", "b")).is_none());
+    assert!(two_file_comparison(&args(&["--identity", "content+relpath"], "a", "b")).is_none());
+    // The footer is only written by the full comparison.
+    assert!(two_file_comparison(&args(&["--footer"], "a", "b")).is_none());
+
+    // Which the full comparison tells apart.
+    let scanned = scan_sides(
+        &args(&["--identity", "content+name"], "a", "b"),
+        &Arc::new(WalkOptions::default()),
+        &Arc::new(HashOptions::default()),
+        2,
+    )
+    .unwrap();
+    assert!(scanned
+        .left
+        .keys()
+        .all(|hash| !scanned.right.contains_key(hash)));
+    assert!(two_file_comparison(&args(&[], "a", ".")).is_none());
+}
+
+// Compares two files like cmp does, reading both at the same time. Unless
+// they're normalized first, files of different sizes can't be identical,
+// so they aren't read at all.
+fn compare_two_files(
+    args: &Args,
+    lpath: &path::Path,
+    rpath: &path::Path,
+    hash_options: &Arc<HashOptions>,
+) -> io::Result<Outcome> {
+    let normalized = hash_options.normalize_encoding
+        || hash_options.normalize_whitespace.is_some()
+        || !hash_options.structured_formats.is_empty();
+    let lsize = fs::metadata(lpath)?.len();
+    let rsize = fs::metadata(rpath)?.len();
+
+    if !normalized && lsize != rsize {
+        println!("different: sizes differ");
+        println!("  <= '{}' : {} bytes", lpath.display(), lsize);
+        println!("  => '{}' : {} bytes", rpath.display(), rsize);
+        return Ok(Outcome::Differences);
+    }
+
+    let roots = [lpath.as_os_str().to_owned(), rpath.as_os_str().to_owned()];
+    let (worker_threads, results_receiver) = start_file_scan(
+        vec![
+            PathLocation::new_left(lpath),
+            PathLocation::new_right(rpath),
+        ],
+        &roots,
+        &args.max_readers_per_root,
+        hash_options,
+        2,
+    );
+    let mut lhashed = None;
+    let mut rhashed = None;
+    for work_result in results_receiver.iter() {
+        let hashed = work_result.result?;
+        match work_result.path {
+            PathLocation::Left(_) => lhashed = Some(hashed),
+            PathLocation::Right(_) => rhashed = Some(hashed),
+        }
+    }
+    join_worker_threads(worker_threads);
+    let lhashed = lhashed.expect("The left-hand file should have been hashed");
+    let rhashed = rhashed.expect("The right-hand file should have been hashed");
+
+    let mut identical = lhashed.hash == rhashed.hash;
+    if identical && args.paranoid {
+        let mut locations = Locations {
+            left: Vec::new(),
+            both: vec![(vec![lpath.to_path_buf()], vec![rpath.to_path_buf()])],
+            right: Vec::new(),
+        };
+        if let Some(work_result) = verify_both_byte_by_byte(&mut locations, 1).pop() {
+            let e = work_result.result.err().unwrap();
+            return Err(io::Error::new(
+                e.kind(),
+                format!("unable to verify {}: {}", work_result.path, e),
+            ));
+        }
+        identical = !locations.both.is_empty();
+    }
+
+    if identical {
+        println!(
+            "identical: {}{} bytes",
+            args.hash_display.prefix(&lhashed.hash, " : "),
+            lhashed.size.unwrap_or(0)
+        );
+        println!("  <= '{}'", lpath.display());
+        println!("  => '{}'", rpath.display());
+        Ok(Outcome::NoDifferences)
+    } else {
+        println!("different:");
+        println!(
+            "  <= '{}' : {}{} bytes",
+            lpath.display(),
+            args.hash_display.prefix(&lhashed.hash, " : "),
+            lhashed.size.unwrap_or(0)
+        );
+        println!(
+            "  => '{}' : {}{} bytes",
+            rpath.display(),
+            args.hash_display.prefix(&rhashed.hash, " : "),
+            rhashed.size.unwrap_or(0)
+        );
+        Ok(Outcome::Differences)
+    }
+}
+
+// Removes the --record-all-hashes file of a cancelled scan. Only some of
+// the files are in it, but nothing in it says so. The --emit-manifest file
+// is only written once the scan is done, so there's none to remove.
+fn remove_incomplete_records(args: &Args) {
+    if let Some(record_path) = &args.record_all_hashes {
+        if let Err(e) = fs::remove_file(record_path) {
+            eprintln!(
+                "WARN: unable to remove the incomplete hash record '{}': {}",
+                record_path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[test]
+fn cancelled_scans_leave_no_hash_record() {
+    let root = TempDir::new("cancelled");
+    let record_path = root.join("record");
+    fs::write(&record_path, HASH_RECORD_MAGIC).unwrap();
+    let args = Args::try_parse_from([
+        OsString::from("find-dups"),
+        OsString::from("--left"),
+        root.to_path_buf().into(),
+        OsString::from("--record-all-hashes"),
+        record_path.clone().into(),
+    ])
+    .unwrap();
+
+    remove_incomplete_records(&args);
+    assert!(!record_path.exists());
+}
+
+// Says so when there was nothing to report, as empty output looks like
+// something went wrong, and exits with a code that tells the cases apart.
+fn finish(outcome: Outcome) -> ! {
+    write_profile();
+    let code = match outcome {
+        Outcome::Differences => 0,
+        Outcome::NoDifferences => {
+            eprintln!("No differences found. Every file has a copy with the same content on the other side.");
+            EXIT_NO_DIFFERENCES
+        }
+        Outcome::NoDuplicates => {
+            eprintln!("No duplicates found. Every file has content of its own.");
+            EXIT_NO_DIFFERENCES
+        }
+        Outcome::NoFiles => {
+            eprintln!("No files were found to compare.");
+            EXIT_NO_FILES
+        }
+    };
+    print_footer();
+    std::process::exit(code)
+}
+
+// When the scan for --footer started, as wall clock time and as an
+// instant to measure how long it took from, and how many files and bytes
+// it compared. It's printed on the way out, after the result.
+static FOOTER: OnceLock<(time::SystemTime, time::Instant, usize, u64)> = OnceLock::new();
+
+// Prints when the scan started and finished, and how long it took, so that
+// a saved report records that too.
+fn print_footer() {
+    if let Some((started, start, files, bytes)) = FOOTER.get() {
+        let elapsed = start.elapsed();
+        eprintln!("Started:  {}", format_modified(Some(*started)));
+        eprintln!("Finished: {}", format_modified(Some(*started + elapsed)));
+        eprintln!(
+            "Took {:.1}s to compare {} files, {}, at {}/s",
+            elapsed.as_secs_f64(),
+            files,
+            BinaryBytes(*bytes),
+            BinaryBytes((*bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64)
+        );
+    }
+}
+
+// The profile being recorded for --profile-out, if any, and where to write
+// it. It's written on the way out, which can be from anywhere.
+static PROFILE: OnceLock<(path::PathBuf, Arc<Profile>)> = OnceLock::new();
+
+fn write_profile() {
+    if let Some((profile_path, profile)) = PROFILE.get() {
+        let written =
+            fs::File::create(profile_path).and_then(|file| profile.write(io::BufWriter::new(file)));
+        if let Err(e) = written {
+            eprintln!(
+                "WARN: unable to write the profile to '{}': {}",
+                profile_path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Prints the results of a comparison as JSON Lines, leaving out the same
+// locations the text output would.
+fn print_json_locations(
+    args: &Args,
+    locations: &Locations,
+    hardlinked: &[(Vec<path::PathBuf>, Vec<path::PathBuf>)],
+) {
+    if !args.omit_left {
+        for path in &locations.left {
+            print_json_record(serde_json::json!({
+                "type": "left_only",
+                "path": path.to_string_lossy(),
+            }));
+        }
+    }
+
+    if !args.omit_right {
+        for path in &locations.right {
+            print_json_record(serde_json::json!({
+                "type": "right_only",
+                "path": path.to_string_lossy(),
+            }));
+        }
+    }
+
+    if args.show_both {
+        for (lpaths, rpaths) in &locations.both {
+            let mirror = classify_mirrored(&args.left, lpaths, &args.right, rpaths);
+            print_json_record(serde_json::json!({
+                "type": "both",
+                "left": capped(lpaths, args.max_paths_per_group)
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "right": capped(rpaths, args.max_paths_per_group)
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "left_count": lpaths.len(),
+                "right_count": rpaths.len(),
+                "mirrored": mirror.mirrored,
+                "stray_left": mirror.stray_left,
+                "stray_right": mirror.stray_right,
+            }));
+        }
+
+        for (lpaths, rpaths) in hardlinked {
+            print_json_record(serde_json::json!({
+                "type": "hardlinked",
+                "left": capped(lpaths, args.max_paths_per_group)
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "right": capped(rpaths, args.max_paths_per_group)
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "left_count": lpaths.len(),
+                "right_count": rpaths.len(),
+            }));
+        }
+    }
+}
+
+// Prints the groups of files with the same content within a single side,
+// in whichever format was asked for.
+fn print_duplicates_within(
+    args: &Args,
+    groups: &[Vec<path::PathBuf>],
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+) {
+    let separator = match args.format {
+        OutputFormat::Csv => ',',
+        OutputFormat::Tsv => '\t',
+        OutputFormat::Text | OutputFormat::Jsonl => ' ',
+    };
+    if matches!(args.format, OutputFormat::Csv | OutputFormat::Tsv) {
+        println!(
+            "{}",
+            ["group", "path", "hash", "size", "mtime"].join(&separator.to_string())
+        );
+    }
+
+    for (index, paths) in groups.iter().enumerate() {
+        let hashed = hashed_files.get(&paths[0]);
+        let hash = hashed.map(|h| hex::encode(h.hash)).unwrap_or_default();
+        let size = hashed.and_then(|h| h.size);
+
+        match args.format {
+            OutputFormat::Text => {
+                match size {
+                    Some(size) => println!("<*> {} copies, {} bytes each", paths.len(), size),
+                    None => println!("<*> {} copies", paths.len()),
+                }
+                for path in capped(paths, args.max_paths_per_group) {
+                    println!("  <= '{}'", path.display());
+                }
+                if let Some(max) = args.max_paths_per_group {
+                    if paths.len() > max {
+                        println!("  ... {} paths; first {} shown", paths.len(), max);
+                    }
+                }
+            }
+            OutputFormat::Jsonl => print_json_record(serde_json::json!({
+                "type": "duplicates",
+                "paths": capped(paths, args.max_paths_per_group)
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "count": paths.len(),
+                "hash": hash,
+                "size": size,
+            })),
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                let field = if args.format == OutputFormat::Csv {
+                    csv_field
+                } else {
+                    tsv_field
+                };
+                for path in capped(paths, args.max_paths_per_group) {
+                    let modified = hashed_files
+                        .get(path)
+                        .and_then(|h| h.modified)
+                        .map(|m| format_modified(Some(m)))
+                        .unwrap_or_default();
+                    let columns = [
+                        (index + 1).to_string(),
+                        field(&path.to_string_lossy()).into_owned(),
+                        hash.clone(),
+                        size.map(|size| size.to_string()).unwrap_or_default(),
+                        modified,
+                    ];
+                    println!("{}", columns.join(&separator.to_string()));
+                }
+            }
+        }
+    }
+}
+
+// Prints a row for each file in the results, leaving out the same
+// locations the text output would.
+fn print_table_locations(
+    args: &Args,
+    locations: &Locations,
+    hardlinked: &[(Vec<path::PathBuf>, Vec<path::PathBuf>)],
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+    field: fn(&str) -> std::borrow::Cow<'_, str>,
+    separator: char,
+) {
+    let print_row = |location: &str, side: &str, path: &path::Path| {
+        let hashed = hashed_files.get(path);
+        let columns = [
+            location.to_string(),
+            side.to_string(),
+            field(&path.to_string_lossy()).into_owned(),
+            hashed.map(|h| hex::encode(h.hash)).unwrap_or_default(),
+            hashed
+                .and_then(|h| h.size)
+                .map(|size| size.to_string())
+                .unwrap_or_default(),
+            hashed
+                .and_then(|h| h.modified)
+                .map(|m| format_modified(Some(m)))
+                .unwrap_or_default(),
+        ];
+        println!("{}", columns.join(&separator.to_string()));
+    };
+
+    println!(
+        "{}",
+        ["location", "side", "path", "hash", "size", "mtime"].join(&separator.to_string())
+    );
+
+    if !args.omit_left {
+        for path in &locations.left {
+            print_row("left_only", "left", path);
+        }
+    }
+
+    if !args.omit_right {
+        for path in &locations.right {
+            print_row("right_only", "right", path);
+        }
+    }
+
+    if args.show_both {
+        for (lpaths, rpaths) in &locations.both {
+            for lpath in capped(lpaths, args.max_paths_per_group) {
+                print_row("both", "left", lpath);
+            }
+            for rpath in capped(rpaths, args.max_paths_per_group) {
+                print_row("both", "right", rpath);
+            }
+        }
+
+        for (lpaths, rpaths) in hardlinked {
+            for lpath in capped(lpaths, args.max_paths_per_group) {
+                print_row("hardlinked", "left", lpath);
+            }
+            for rpath in capped(rpaths, args.max_paths_per_group) {
+                print_row("hardlinked", "right", rpath);
+            }
+        }
+    }
+}
+
+// The first max paths, or all of them without a maximum.
+fn capped(paths: &[path::PathBuf], max: Option<usize>) -> &[path::PathBuf] {
+    &paths[..max.map_or(paths.len(), |max| max.min(paths.len()))]
+}
+
+#[test]
+fn groups_are_capped_to_max_paths() {
+    let paths: Vec<path::PathBuf> = (0..5).map(|i| path::PathBuf::from(i.to_string())).collect();
+
+    assert_eq!(capped(&paths, None), &paths[..]);
+    assert_eq!(capped(&paths, Some(2)), &paths[..2]);
+    assert_eq!(capped(&paths, Some(10)), &paths[..]);
+
+    // Counting copies has to stay linear for huge groups of copies that
+    // all share one relative path.
+    let lpaths = vec![path::PathBuf::from("l/x"); 100_000];
+    let rpaths = vec![path::PathBuf::from("r/x"); 100_000];
+    let mirror = classify_mirrored(&["l".into()], &lpaths, &["r".into()], &rpaths);
+    assert_eq!(
+        (mirror.mirrored, mirror.stray_left, mirror.stray_right),
+        (100_000, 0, 0)
+    );
+}
+
+// Quotes a CSV field as RFC 4180 describes, if it needs it.
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+// TSV fields can't be quoted, so tabs, line breaks, and backslashes are
+// escaped with backslashes instead, the way most tools that read TSV
+// expect.
+fn tsv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains(['\t', '\n', '\r', '\\']) {
+        s.replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+            .into()
+    } else {
+        s.into()
+    }
+}
+
+#[test]
+fn table_fields_are_escaped() {
+    assert_eq!(csv_field("plain/path"), "plain/path");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    assert_eq!(tsv_field("plain/path"), "plain/path");
+    assert_eq!(tsv_field("a\tb\nc\\d"), "a\\tb\\nc\\\\d");
+}
+
+// The reports that are only printed as text would be mixed into the other
+// formats' output, so they can only be asked for with --format text.
+fn check_text_only_flags(args: &Args) {
+    let text_only = [
+        ("--left-listing", !args.left_listing.is_empty()),
+        ("--right-listing", !args.right_listing.is_empty()),
+        ("--early-structure", args.early_structure),
+        ("--stats-only", args.stats_only),
+        ("--count-parity", args.count_parity),
+        ("--report-empty-dirs", args.report_empty_dirs),
+        ("--strict-structure", args.strict_structure),
+        ("--side", !args.side.is_empty()),
+        ("--conflicts", args.conflicts),
+        ("--infer-direction", args.infer_direction),
+        ("--action", args.action.is_some()),
+        ("--link-duplicates", args.link_duplicates.is_some()),
+        ("--move-duplicates-to", args.move_duplicates_to.is_some()),
+        ("--simulate-strategies", args.simulate_strategies),
+        #[cfg(feature = "images")]
+        ("--cluster-photos", args.cluster_photos),
+        #[cfg(feature = "tui")]
+        ("--tui", args.tui),
+    ];
+
+    if let Some((flag, _)) = text_only.iter().find(|(_, used)| *used) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("{} can only be used with --format text", flag),
+            )
+            .exit();
+    }
+}
+
+// Exits with a usage error if any flag that needs a right-hand side was
+// given. Within a single tree, only the groups of duplicates are reported.
+fn check_two_sided_flags(args: &Args) {
+    if let Some(flag) = two_sided_flag(args) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("{} needs a right-hand side to compare with", flag),
+            )
+            .exit();
+    }
+}
+
+fn two_sided_flag(args: &Args) -> Option<&'static str> {
+    let two_sided = [
+        ("--action", args.action.is_some()),
+        ("--link-duplicates", args.link_duplicates.is_some()),
+        ("--move-duplicates-to", args.move_duplicates_to.is_some()),
+        ("--paranoid", args.paranoid),
+        ("--conflicts", args.conflicts),
+        ("--count-parity", args.count_parity),
+        ("--acknowledge-file", args.acknowledge_file.is_some()),
+        ("--simulate-strategies", args.simulate_strategies),
+        ("--strict-structure", args.strict_structure),
+        ("--report-empty-dirs", args.report_empty_dirs),
+        ("--infer-direction", args.infer_direction),
+        #[cfg(feature = "images")]
+        ("--cluster-photos", args.cluster_photos),
+    ];
+
+    two_sided
+        .into_iter()
+        .find(|(_, used)| *used)
+        .map(|(flag, _)| flag)
+}
+
+#[test]
+fn single_trees_reject_two_sided_flags() {
+    let args = |extra: &[&str]| {
+        Args::try_parse_from(["find-dups", "--path", "tree"].iter().chain(extra)).unwrap()
+    };
+
+    assert_eq!(two_sided_flag(&args(&[])), None);
+    assert_eq!(two_sided_flag(&args(&["--footer"])), None);
+    assert_eq!(
+        two_sided_flag(&args(&["--action", "symlink", "--dry-run"])),
+        Some("--action")
+    );
+    assert_eq!(
+        two_sided_flag(&args(&["--paranoid", "--conflicts"])),
+        Some("--paranoid")
+    );
+    assert_eq!(
+        two_sided_flag(&args(&["--count-parity"])),
+        Some("--count-parity")
+    );
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Side {
+    Left,
+    Right,
+}
+
+// A root given with --side, and the name of the side it's on.
+#[derive(Clone, Debug, PartialEq)]
+struct NamedRoot {
+    name: String,
+    path: OsString,
+}
+
+impl FromStr for NamedRoot {
+    type Err = String;
+
+    // Splits on the first '=', so that paths can contain one but names
+    // can't.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(NamedRoot {
+                name: name.to_string(),
+                path: path.into(),
+            }),
+            _ => Err(format!("expected NAME=PATH, got '{}'", s)),
+        }
+    }
+}
+
+#[test]
+fn named_root_parses_name_and_path() {
+    assert_eq!(
+        "nas=/mnt/a=b".parse::<NamedRoot>(),
+        Ok(NamedRoot {
+            name: "nas".to_string(),
+            path: "/mnt/a=b".into(),
+        })
+    );
+    assert!("/mnt/nas".parse::<NamedRoot>().is_err());
+}
+
+// How many distinct contents, and how many bytes of them, are on exactly
+// one set of sides. Sides are given by their index.
+#[derive(Debug, PartialEq)]
+struct SideSubset {
+    sides: BTreeSet<usize>,
+    contents: usize,
+    bytes: u64,
+}
+
+// Groups each content by the sides it's on. The sets on the most sides
+// come first, then they're in the order the sides were given.
+fn cross_tabulate(contents: &DigestMap<(BTreeSet<usize>, u64)>) -> Vec<SideSubset> {
+    let mut subsets: BTreeMap<&BTreeSet<usize>, (usize, u64)> = BTreeMap::new();
+    for (sides, size) in contents.values() {
+        let subset = subsets.entry(sides).or_default();
+        subset.0 += 1;
+        subset.1 += size;
+    }
+
+    let mut subsets: Vec<SideSubset> = subsets
+        .into_iter()
+        .map(|(sides, (contents, bytes))| SideSubset {
+            sides: sides.clone(),
+            contents,
+            bytes,
+        })
+        .collect();
+    subsets.sort_by(|lhs, rhs| {
+        rhs.sides
+            .len()
+            .cmp(&lhs.sides.len())
+            .then_with(|| lhs.sides.cmp(&rhs.sides))
+    });
+    subsets
+}
+
+#[test]
+fn cross_tabulate_groups_by_sides() {
+    let mut contents: DigestMap<(BTreeSet<usize>, u64)> = DigestMap::default();
+    contents.insert([1; 32], (BTreeSet::from([0, 1, 2]), 10));
+    contents.insert([2; 32], (BTreeSet::from([2]), 5));
+    contents.insert([3; 32], (BTreeSet::from([0, 2]), 1));
+    contents.insert([4; 32], (BTreeSet::from([0, 2]), 2));
+    contents.insert([5; 32], (BTreeSet::from([0]), 7));
+
+    let subset = |sides: &[usize], contents: usize, bytes: u64| SideSubset {
+        sides: sides.iter().copied().collect(),
+        contents,
+        bytes,
+    };
+    assert_eq!(
+        cross_tabulate(&contents),
+        vec![
+            subset(&[0, 1, 2], 1, 10),
+            subset(&[0, 2], 2, 3),
+            subset(&[0], 1, 7),
+            subset(&[2], 1, 5),
+        ]
+    );
+}
+
+// Hashes every --side root and prints a table of how much content is on
+// each set of sides, so that it's clear what would be lost without any one
+// of them.
+fn compare_named_sides(
+    args: &Args,
+    hash_options: &Arc<HashOptions>,
+    walk_options: &Arc<WalkOptions>,
+    num_threads: usize,
+) -> io::Result<()> {
+    let mut names: Vec<&str> = Vec::new();
+    for root in &args.side {
+        if !names.contains(&root.name.as_str()) {
+            names.push(&root.name);
+        }
+    }
+    if names.len() < 2 {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::TooFewValues,
+                "--side needs at least two differently named sides",
+            )
+            .exit();
+    }
+
+    let roots: Vec<OsString> = args.side.iter().map(|root| root.path.clone()).collect();
+    let root_problems = validate_roots(&roots, walk_options);
+    if !root_problems.is_empty() {
+        for problem in &root_problems {
+            eprintln!("ERROR: root {}", problem);
+        }
+        eprintln!(
+            "ERROR: {} root(s) can't be scanned. Nothing was compared.",
+            root_problems.len()
+        );
+        std::process::exit(EXIT_INVALID_ROOTS);
+    }
+
+    // A file is on the side of the closest root it's under, like relative
+    // paths are.
+    let side_of = |path: &path::Path| {
+        args.side
+            .iter()
+            .filter(|root| path.starts_with(&root.path))
+            .max_by_key(|root| path::Path::new(&root.path).components().count())
+            .and_then(|root| names.iter().position(|name| *name == root.name))
+    };
+
+    let (worker_threads, results_receiver) = start_scan(
+        &roots,
+        &[],
+        &args.max_readers_per_root,
+        hash_options,
+        walk_options,
+        num_threads,
+    );
+
+    let mut contents: DigestMap<(BTreeSet<usize>, u64)> = DigestMap::default();
+    let mut error_log = ErrorLog::new(args.all_errors);
+    for work_result in results_receiver {
+        let hashed = match &work_result.result {
+            Err(_) => {
+                error_log.report(&work_result);
+                continue;
+            }
+            Ok(hashed) => hashed,
+        };
+        if let Some(side) = side_of(work_result.path.path()) {
+            let entry = contents
+                .entry(hashed.hash)
+                .or_insert_with(|| (BTreeSet::new(), hashed.size.unwrap_or(0)));
+            entry.0.insert(side);
+        }
+    }
+    join_worker_threads(worker_threads);
+    error_log.summarize();
+    report_skipped(args, &walk_options.skipped.take());
+
+    println!("{:<40} {:>10} {:>16}", "Sides", "Contents", "Bytes");
+    let subsets = cross_tabulate(&contents);
+    for subset in &subsets {
+        let sides: Vec<&str> = subset.sides.iter().map(|side| names[*side]).collect();
+        println!(
+            "{:<40} {:>10} {:>16}",
+            sides.join("+"),
+            subset.contents,
+            subset.bytes
+        );
+    }
+
+    finish(if contents.is_empty() {
+        Outcome::NoFiles
+    } else if subsets.len() == 1 && subsets[0].sides.len() == names.len() {
+        Outcome::NoDifferences
+    } else {
+        Outcome::Differences
+    })
+}
+
+// A root named in a --sides file.
+#[derive(Debug, PartialEq)]
+struct SideRoot {
+    name: String,
+    path: OsString,
+    side: Side,
+    max_readers: Option<NonZeroUsize>,
+}
+
+fn read_sides_file(path: &path::Path) -> io::Result<Vec<SideRoot>> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("unable to read '{}': {}", path.display(), e),
+        )
+    })?;
+    parse_sides(&contents).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid sides file '{}': {}", path.display(), e),
+        )
+    })
+}
+
+fn parse_sides(contents: &str) -> Result<Vec<SideRoot>, String> {
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e: toml::de::Error| e.to_string())?;
+
+    if let Some(key) = table.keys().find(|key| *key != "root") {
+        return Err(format!("unknown key '{}'", key));
+    }
+
+    let tables = match table.get("root") {
+        None => return Ok(Vec::new()),
+        Some(toml::Value::Array(tables)) => tables,
+        Some(_) => return Err("'root' must be an array of tables: [[root]]".to_string()),
+    };
+
+    let mut roots: Vec<SideRoot> = Vec::new();
+    for (i, root) in tables.iter().enumerate() {
+        let root = root
+            .as_table()
+            .ok_or_else(|| format!("root {} is not a table", i + 1))?;
+        let string = |key: &str| -> Result<&str, String> {
+            root.get(key)
+                .ok_or_else(|| format!("root {} has no '{}'", i + 1, key))?
+                .as_str()
+                .ok_or_else(|| format!("'{}' of root {} is not a string", key, i + 1))
+        };
+
+        let name = string("name")?;
+        if roots.iter().any(|other| other.name == name) {
+            return Err(format!("more than one root is named '{}'", name));
+        }
+
+        let side = match string("side")? {
+            "left" => Side::Left,
+            "right" => Side::Right,
+            other => {
+                return Err(format!(
+                    "side of root '{}' must be \"left\" or \"right\", not \"{}\"",
+                    name, other
+                ))
+            }
+        };
+
+        let max_readers = match root.get("max_readers") {
+            None => None,
+            Some(value) => Some(
+                value
+                    .as_integer()
+                    .and_then(|readers| usize::try_from(readers).ok())
+                    .and_then(NonZeroUsize::new)
+                    .ok_or_else(|| {
+                        format!("max_readers of root '{}' must be a positive integer", name)
+                    })?,
+            ),
+        };
+
+        if let Some(key) = root
+            .keys()
+            .find(|key| !["name", "path", "side", "max_readers"].contains(&key.as_str()))
+        {
+            return Err(format!("unknown key '{}' in root '{}'", key, name));
+        }
+
+        roots.push(SideRoot {
+            name: name.to_string(),
+            path: string("path")?.into(),
+            side,
+            max_readers,
+        });
+    }
+
+    Ok(roots)
+}
+
+#[test]
+fn parse_sides_reads_roots() {
+    let roots = parse_sides(
+        r#"
+        [[root]]
+        name = "laptop"
+        path = "/home/me/photos"
+        side = "left"
+
+        [[root]]
+        name = "usb"
+        path = "/mnt/usb/photos"
+        side = "right"
+        max_readers = 1
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        roots,
+        vec![
+            SideRoot {
+                name: "laptop".to_string(),
+                path: "/home/me/photos".into(),
+                side: Side::Left,
+                max_readers: None,
+            },
+            SideRoot {
+                name: "usb".to_string(),
+                path: "/mnt/usb/photos".into(),
+                side: Side::Right,
+                max_readers: NonZeroUsize::new(1),
+            },
+        ]
+    );
+
+    let duplicate = "[[root]]\nname = \"a\"\npath = \"x\"\nside = \"left\"\n".repeat(2);
+    assert!(parse_sides(&duplicate).is_err());
+    assert!(parse_sides("[[root]]\nname = \"a\"\npath = \"x\"\nside = \"up\"").is_err());
+    assert!(parse_sides("[[root]]\nname = \"a\"\nside = \"left\"").is_err());
+}
+
+// Standard output is line buffered, so each record is written out as soon
+// as it's printed.
+fn print_json_record(record: serde_json::Value) {
+    println!("{}", record);
+}
+
+const STREAM_PREFIX: &str = "fifo:";
+
+// Strips "fifo:" from the roots that start with it, returning those roots'
+// paths.
+fn strip_stream_prefixes(roots: &mut [OsString]) -> Vec<path::PathBuf> {
+    let mut streams = Vec::new();
+    for root in roots {
+        let bytes = root.as_encoded_bytes();
+        if let Some(stripped) = bytes.strip_prefix(STREAM_PREFIX.as_bytes()) {
+            // SAFETY: the prefix is ASCII, so what follows it starts where
+            // a valid OsStr can.
+            let stripped = unsafe { std::ffi::OsStr::from_encoded_bytes_unchecked(stripped) };
+            *root = stripped.to_os_string();
+            streams.push(path::PathBuf::from(&root));
+        }
+    }
+    streams
+}
+
+#[test]
+fn strip_stream_prefixes_only_strips_fifo_roots() {
+    let mut roots: Vec<OsString> = vec!["fifo:/tmp/stream".into(), "dir/fifo:x".into()];
+
+    assert_eq!(
+        strip_stream_prefixes(&mut roots),
+        vec![path::PathBuf::from("/tmp/stream")]
+    );
+    assert_eq!(
+        roots,
+        vec![OsString::from("/tmp/stream"), "dir/fifo:x".into()]
+    );
+}
+
+const HASH_RECORD_MAGIC: &[u8; 8] = b"FDHASHv1";
+
+// Writes one --record-all-hashes record. Paths are written as the platform
+// encodes them, which is their raw bytes on Unix.
+fn write_hash_record(
+    writer: &mut impl io::Write,
+    path: &path::Path,
+    hashed: &HashedFile,
+) -> io::Result<()> {
+    let path_bytes = path.as_os_str().as_encoded_bytes();
+    let path_len = u32::try_from(path_bytes.len())
+        .map_err(|_| io::Error::other(format!("path too long: '{}'", path.display())))?;
+
+    writer.write_all(&hashed.hash)?;
+    writer.write_all(&hashed.size.unwrap_or(0).to_le_bytes())?;
+    writer.write_all(&path_len.to_le_bytes())?;
+    writer.write_all(path_bytes)
+}
+
+#[test]
+fn write_hash_record_lays_out_fields() {
+    let hashed = HashedFile {
+        hash: [7; 32],
+        size: Some(0x0102),
+        modified: None,
+        file_id: None,
+    };
+    let mut record = Vec::new();
+    write_hash_record(&mut record, path::Path::new("a/b"), &hashed).unwrap();
+
+    let mut expected = vec![7; 32];
+    expected.extend([2, 1, 0, 0, 0, 0, 0, 0]);
+    expected.extend([3, 0, 0, 0]);
+    expected.extend(b"a/b");
+    assert_eq!(record, expected);
+}
+
+fn side_name(path: &PathLocation) -> &'static str {
+    match path {
+        PathLocation::Left(_) => "left",
+        PathLocation::Right(_) => "right",
+    }
+}
+
+fn ingest(ingest_args: &IngestArgs, num_threads: usize, all_errors: bool) -> io::Result<()> {
+    let objects_dir = ingest_args.store.join("objects");
+    let tmp_dir = ingest_args.store.join("tmp");
+    fs::create_dir_all(&objects_dir)?;
+    fs::create_dir_all(&tmp_dir)?;
+
+    // The store is addressed by the hash of the contents as they are, so
+    // none of the normalizations apply here.
+    let hash_options = Arc::new(HashOptions::default());
+
+    let roots = [ingest_args.path.clone().into_os_string()];
+    let (files, errors) = hash_roots(&roots, &hash_options, num_threads, all_errors);
+
+    // Only one copy of each content needs to be stored.
+    let mut unique: HashMap<Sha256Sum, &path::Path> = HashMap::new();
+    for (path, hashed) in files.iter() {
+        unique.entry(hashed.hash).or_insert(path);
+    }
+    let unique: Vec<(Sha256Sum, &path::Path)> = unique.into_iter().collect();
+
+    let chunk_size = unique.len().div_ceil(num_threads).max(1);
+    let stored: Vec<io::Result<Option<u64>>> = thread::scope(|scope| {
+        let threads: Vec<_> = unique
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let (objects_dir, tmp_dir) = (&objects_dir, &tmp_dir);
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(hash, path)| store_object(objects_dir, tmp_dir, path, hash))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    });
+
+    let mut failed: HashSet<Sha256Sum> = HashSet::new();
+    let (mut new_objects, mut new_bytes) = (0usize, 0u64);
+    for ((hash, path), result) in unique.iter().zip(stored) {
+        match result {
+            Err(e) => {
+                eprintln!("ERROR: unable to store '{}': {}", path.display(), e);
+                failed.insert(*hash);
+            }
+            Ok(Some(size)) => {
+                new_objects += 1;
+                new_bytes += size;
+            }
+            Ok(None) => (),
+        }
+    }
+
+    let mut manifest: Vec<(path::PathBuf, Sha256Sum)> = files
+        .iter()
+        .filter(|(_, hashed)| !failed.contains(&hashed.hash))
+        .map(|(path, hashed)| {
+            let rel_path = relative_path(&roots, path).to_path_buf();
+            (rel_path, hashed.hash)
+        })
+        .collect();
+    manifest.sort_unstable();
+
+    let mut out: Box<dyn io::Write> = match &ingest_args.manifest {
+        Some(manifest_path) => Box::new(io::BufWriter::new(fs::File::create(manifest_path)?)),
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    };
+    for (rel_path, hash) in manifest.iter() {
+        write_manifest_line(&mut out, hash, rel_path)?;
+    }
+    out.flush()?;
+
+    eprintln!(
+        "Ingested {} files: {} new objects ({} bytes), {} already stored, {} errors.",
+        manifest.len(),
+        new_objects,
+        new_bytes,
+        unique.len() - new_objects - failed.len(),
+        errors + failed.len()
+    );
+
+    Ok(())
+}
+
+// Hashes every file under the roots, returning the files that were hashed
+// and how many couldn't be. Errors are reported as they happen.
+fn hash_roots(
+    roots: &[OsString],
+    hash_options: &Arc<HashOptions>,
+    num_threads: usize,
+    all_errors: bool,
+) -> (Vec<(path::PathBuf, HashedFile)>, usize) {
+    let (worker_threads, results_receiver) = start_scan(
+        roots,
+        &[],
+        &[],
+        hash_options,
+        &Arc::new(WalkOptions::default()),
+        num_threads,
+    );
+
+    let mut files: Vec<(path::PathBuf, HashedFile)> = Vec::new();
+    let mut errors: usize = 0;
+    let mut error_log = ErrorLog::new(all_errors);
+    for work_result in results_receiver.iter() {
+        match work_result.result {
+            Err(_) => {
+                error_log.report(&work_result);
+                errors += 1;
+            }
+            Ok(hashed) => files.push((work_result.path.path().to_path_buf(), hashed)),
+        }
+    }
+
+    join_worker_threads(worker_threads);
+    error_log.summarize();
+
+    (files, errors)
+}
+
+fn verify(verify_args: &VerifyArgs, num_threads: usize, all_errors: bool) -> io::Result<()> {
+    let manifest = read_manifest(&verify_args.manifest)?;
+
+    let hash_options = Arc::new(HashOptions::default());
+
+    let roots = [verify_args.path.clone().into_os_string()];
+    let (worker_threads, results_receiver) = start_scan(
+        &roots,
+        &[],
+        &[],
+        &hash_options,
+        &Arc::new(WalkOptions::default()),
+        num_threads,
+    );
+
+    // Checking the store doesn't depend on the walk, so it's done at the
+    // same time.
+    let store_problems = thread::scope(|scope| {
+        let store_check = verify_args.store.as_ref().map(|store| {
+            scope.spawn(|| verify_objects(&store.join("objects"), &manifest, num_threads))
+        });
+
+        let mut problems: usize = 0;
+        let mut found: HashMap<path::PathBuf, Sha256Sum> = HashMap::new();
+        let mut error_log = ErrorLog::new(all_errors);
+        for work_result in results_receiver.iter() {
+            match work_result.result {
+                Err(_) => {
+                    error_log.report(&work_result);
+                    problems += 1;
+                }
+                Ok(hashed) => {
+                    let rel_path = relative_path(&roots, work_result.path.path()).to_path_buf();
+                    found.insert(rel_path, hashed.hash);
+                }
+            }
+        }
+
+        join_worker_threads(worker_threads);
+        error_log.summarize();
+
+        let expected: BTreeMap<&path::Path, &Sha256Sum> = manifest
+            .iter()
+            .map(|(rel_path, hash)| (rel_path.as_path(), hash))
+            .collect();
+
+        for (rel_path, expected_hash) in expected.iter() {
+            match found.get(*rel_path) {
+                None => {
+                    println!("missing: '{}'", rel_path.display());
+                    problems += 1;
+                }
+                Some(hash) if hash != *expected_hash => {
+                    println!(
+                        "corrupted: '{}' (expected {}, found {})",
+                        rel_path.display(),
+                        hex::encode(expected_hash),
+                        hex::encode(hash)
+                    );
+                    problems += 1;
+                }
+                Some(_) => (),
+            }
+        }
+
+        let mut unexpected: Vec<&path::PathBuf> = found
+            .keys()
+            .filter(|rel_path| !expected.contains_key(rel_path.as_path()))
+            .collect();
+        unexpected.sort_unstable();
+        for rel_path in unexpected {
+            println!("unexpected: '{}'", rel_path.display());
+            problems += 1;
+        }
+
+        problems
+            + store_check
+                .map(|t| t.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .unwrap_or(0)
+    });
+
+    if store_problems > 0 {
+        eprintln!("Verification failed: {} problems found.", store_problems);
+        std::process::exit(1);
+    }
+
+    eprintln!("Verified {} files.", manifest.len());
+    Ok(())
+}
+
+// Checks that every object the manifest refers to is in the store and that
+// its content still matches its name. Returns the number of problems.
+fn verify_objects(
+    objects_dir: &path::Path,
+    manifest: &[(path::PathBuf, Sha256Sum)],
+    num_threads: usize,
+) -> usize {
+    let mut hashes: Vec<&Sha256Sum> = manifest.iter().map(|(_, hash)| hash).collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    let chunk_size = hashes.len().div_ceil(num_threads).max(1);
+    let problems: Vec<String> = thread::scope(|scope| {
+        let threads: Vec<_> = hashes
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .filter_map(|hash| {
+                            let object_path = object_path(objects_dir, hash);
+                            let file = match fs::File::open(&object_path) {
+                                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                                    return Some(format!("missing object: {}", hex::encode(hash)))
+                                }
+                                Err(e) => {
+                                    return Some(format!(
+                                        "unreadable object: {}: {}",
+                                        hex::encode(hash),
+                                        e
+                                    ))
+                                }
+                                Ok(file) => file,
+                            };
+
+                            match hash_contents(file, &HashOptions::default()) {
+                                Err(e) => {
+                                    Some(format!("unreadable object: {}: {}", hex::encode(hash), e))
+                                }
+                                Ok(actual) if actual != **hash => Some(format!(
+                                    "corrupted object: {} (found {})",
+                                    hex::encode(hash),
+                                    hex::encode(actual)
+                                )),
+                                Ok(_) => None,
+                            }
+                        })
+                        .collect::<Vec<String>>()
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    });
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+
+    problems.len()
+}
+
+fn bench(bench_args: &BenchArgs, num_threads: usize) -> io::Result<()> {
+    let walk_start = time::Instant::now();
+    let mut files = Vec::new();
+    list_root(
+        &bench_args.path,
+        &WalkOptions::default(),
+        &mut files,
+        &mut Vec::new(),
+    );
+    let walk_time = walk_start.elapsed();
+
+    println!(
+        "walk: {} files in {:.2}s ({:.0} files/s)",
+        files.len(),
+        walk_time.as_secs_f64(),
+        files.len() as f64 / walk_time.as_secs_f64().max(f64::EPSILON)
+    );
+
+    // The files found by the walk are hashed from a plain list, so that
+    // this only measures reading their contents. The walk has already
+    // warmed the metadata caches, which this would otherwise pay for.
+    let paths: Vec<path::PathBuf> = files
+        .iter()
+        .map(|file| {
+            // A root that is a file is listed by its file name alone.
+            if bench_args.path.is_file() {
+                bench_args.path.clone()
+            } else {
+                bench_args.path.join(&file.path)
+            }
+        })
+        .collect();
+
+    let hash_start = time::Instant::now();
+    let (hashed_files, hashed_bytes) = hash_paths(paths, num_threads);
+    let hash_time = hash_start.elapsed();
+
+    println!(
+        "hash: {} files, {} bytes in {:.2}s ({:.1} MiB/s)",
+        hashed_files,
+        hashed_bytes,
+        hash_time.as_secs_f64(),
+        hashed_bytes as f64 / (1024.0 * 1024.0) / hash_time.as_secs_f64().max(f64::EPSILON)
+    );
+
+    if walk_time > hash_time {
+        println!("Walking took longer than hashing, so scans of this tree are limited by metadata IO. Reading contents faster won't speed them up.");
+    } else {
+        println!("Hashing took longer than walking, so scans of this tree are limited by data IO. On spinning disks, --max-readers-per-root can help by avoiding seeks.");
+    }
+
+    Ok(())
+}
+
+// Hashes each file on num_threads threads, returning how many were hashed
+// and how many bytes they had. Files that can't be hashed are reported and
+// left out.
+fn hash_paths(paths: Vec<path::PathBuf>, num_threads: usize) -> (usize, u64) {
+    let (path_sender, path_receiver) = unbounded();
+    for path in paths {
+        path_sender.send(path).unwrap();
+    }
+    drop(path_sender);
+
+    let hash_options = HashOptions::default();
+    thread::scope(|scope| {
+        let threads: Vec<_> = (0..num_threads)
+            .map(|_| {
+                let path_receiver = path_receiver.clone();
+                let hash_options = &hash_options;
+                scope.spawn(move || {
+                    let mut hashed_files = 0usize;
+                    let mut hashed_bytes = 0u64;
+                    for path in path_receiver.iter() {
+                        let result = fs::File::open(&path).and_then(|file| {
+                            let size = file.metadata()?.len();
+                            hash_contents(io::BufReader::new(file), hash_options)?;
+                            Ok(size)
+                        });
+                        match result {
+                            Err(e) => eprintln!("WARN: unable to hash '{}': {}", path.display(), e),
+                            Ok(size) => {
+                                hashed_files += 1;
+                                hashed_bytes += size;
+                            }
+                        }
+                    }
+                    (hashed_files, hashed_bytes)
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .fold((0, 0), |(files, bytes), (f, b)| (files + f, bytes + b))
+    })
+}
+
+#[test]
+fn bench_hashes_each_listed_file() {
+    let root = TempDir::new("bench");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("a"), b"12345").unwrap();
+    fs::write(root.join("sub/b"), b"123").unwrap();
+
+    let mut files = Vec::new();
+    list_root(&root, &WalkOptions::default(), &mut files, &mut Vec::new());
+    let mut paths: Vec<path::PathBuf> = files.iter().map(|file| root.join(&file.path)).collect();
+    paths.push(root.join("missing"));
+    let hashed = hash_paths(paths, 2);
+
+    assert_eq!(files.len(), 2);
+    assert_eq!(hashed, (2, 8));
+}
+
+fn build_index(
+    build_args: &IndexBuildArgs,
+    num_threads: usize,
+    all_errors: bool,
+) -> io::Result<()> {
+    // Like the manifests ingest writes, the index is of the contents as
+    // they are.
+    let hash_options = Arc::new(HashOptions::default());
+    let roots = [build_args.path.clone().into_os_string()];
+    let (files, errors) = hash_roots(&roots, &hash_options, num_threads, all_errors);
+
+    let mut index: Vec<(path::PathBuf, Sha256Sum)> = files
+        .iter()
+        .map(|(path, hashed)| (relative_path(&roots, path).to_path_buf(), hashed.hash))
+        .collect();
+    index.sort_unstable();
+
+    let mut out = io::BufWriter::new(fs::File::create(&build_args.index)?);
+    for (rel_path, hash) in index.iter() {
+        write_manifest_line(&mut out, hash, rel_path)?;
+    }
+    out.flush()?;
+
+    eprintln!("Indexed {} files, {} errors.", index.len(), errors);
+    Ok(())
+}
+
+#[test]
+fn index_build_records_relative_paths_and_hashes() {
+    let root = TempDir::new("index");
+    fs::create_dir_all(root.join("library/sub")).unwrap();
+    fs::write(root.join("library/a"), b"a").unwrap();
+    fs::write(root.join("library/sub/b"), b"b").unwrap();
+
+    let build_args = IndexBuildArgs {
+        path: root.join("library"),
+        index: root.join("index"),
+    };
+    build_index(&build_args, 2, false).unwrap();
+    let index = read_manifest(&build_args.index).unwrap();
+
+    assert_eq!(
+        index,
+        vec![
+            (path::PathBuf::from("a"), Sha256::digest(b"a").into()),
+            (["sub", "b"].iter().collect(), Sha256::digest(b"b").into()),
+        ]
+    );
+}
+
+fn query_index(
+    query_args: &IndexQueryArgs,
+    num_threads: usize,
+    all_errors: bool,
+) -> io::Result<()> {
+    let mut indexed: HashMap<Sha256Sum, Vec<path::PathBuf>> = HashMap::new();
+    for (rel_path, hash) in read_manifest(&query_args.index)? {
+        indexed.entry(hash).or_default().push(rel_path);
+    }
+
+    let hash_options = Arc::new(HashOptions::default());
+    let (mut files, _) = hash_roots(&query_args.left, &hash_options, num_threads, all_errors);
+    files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut present: usize = 0;
+    for (path, hashed) in files.iter() {
+        match indexed.get(&hashed.hash) {
+            None => println!("new: '{}'", path.display()),
+            Some(indexed_paths) => {
+                present += 1;
+                println!("present: '{}'", path.display());
+                for indexed_path in indexed_paths {
+                    println!("  as '{}'", indexed_path.display());
+                }
+            }
+        }
+    }
+
+    eprintln!(
+        "{} of {} files are already in the index.",
+        present,
+        files.len()
+    );
+    Ok(())
+}
+
+fn import(import_args: &ImportArgs, num_threads: usize, all_errors: bool) -> io::Result<()> {
+    let mut indexed: HashMap<Sha256Sum, path::PathBuf> = HashMap::new();
+    if let Some(index) = &import_args.index {
+        for (rel_path, hash) in read_manifest(index)? {
+            indexed.entry(hash).or_insert(rel_path);
+        }
+    }
+
+    let hash_options = Arc::new(HashOptions::default());
+    let roots = [import_args.from.clone().into_os_string()];
+    let (mut files, _) = hash_roots(&roots, &hash_options, num_threads, all_errors);
+    files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    // Copies made in this import are remembered by their source path.
+    let mut imported: HashMap<Sha256Sum, path::PathBuf> = HashMap::new();
+    let mut copied = ActionTally::default();
+    let mut skipped: usize = 0;
+    for (path, hashed) in files.iter() {
+        if import_args.skip_duplicates {
+            if let Some(rel_path) = indexed.get(&hashed.hash) {
+                skipped += 1;
+                println!(
+                    "skipped: '{}' is in the library as '{}'",
+                    path.display(),
+                    rel_path.display()
+                );
+                continue;
+            }
+            if let Some(first) = imported.get(&hashed.hash) {
+                skipped += 1;
+                println!(
+                    "skipped: '{}' has the same content as '{}'",
+                    path.display(),
+                    first.display()
+                );
+                continue;
+            }
+        }
+
+        let destination = import_args.to.join(relative_path(&roots, path));
+        match copy_new_file(path, &destination, hashed) {
+            Ok(()) => {
+                copied.add(Some(hashed));
+                imported.insert(hashed.hash, path.clone());
+                println!(
+                    "copied: '{}' -> '{}'",
+                    path.display(),
+                    destination.display()
+                );
+            }
+            Err(e) => {
+                copied.failed += 1;
+                eprintln!("WARN: not copying '{}': {}", path.display(), e);
+            }
+        }
+    }
+
+    copied.summarize(
+        false,
+        "Copied",
+        "Would copy",
+        &format!("to '{}'", import_args.to.display()),
+    );
+    if import_args.skip_duplicates {
+        eprintln!("Skipped {} duplicate(s).", skipped);
+    }
+    Ok(())
+}
+
+#[test]
+fn import_skips_content_already_in_the_library() {
+    let root = TempDir::new("import");
+    fs::create_dir_all(root.join("card")).unwrap();
+    fs::create_dir_all(root.join("library")).unwrap();
+    fs::write(root.join("library/old"), b"old").unwrap();
+    fs::write(root.join("card/old copy"), b"old").unwrap();
+    fs::write(root.join("card/new"), b"new").unwrap();
+    fs::write(root.join("card/new copy"), b"new").unwrap();
+
+    let index = root.join("index");
+    let build_args = IndexBuildArgs {
+        path: root.join("library"),
+        index: index.clone(),
+    };
+    build_index(&build_args, 2, false).unwrap();
+    let import_args = ImportArgs {
+        index: Some(index),
+        from: root.join("card"),
+        to: root.join("library"),
+        skip_duplicates: true,
+    };
+    import(&import_args, 2, false).unwrap();
+
+    let mut library: Vec<String> = fs::read_dir(root.join("library"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    library.sort();
+    let new = fs::read(root.join("library/new")).unwrap();
+
+    assert_eq!(library, ["new", "old"]);
+    assert_eq!(new, b"new");
+}
+
+// Copies a file to a path that's not taken yet, keeping its modification
+// time. The copy is made under a temporary name and renamed into place, so
+// a partial copy is never left at the destination.
+fn copy_new_file(
+    path: &path::Path,
+    destination: &path::Path,
+    hashed: &HashedFile,
+) -> io::Result<()> {
+    ensure_unchanged(path, Some(hashed))?;
+
+    if fs::symlink_metadata(destination).is_ok() {
+        return Err(io::Error::other(format!(
+            "'{}' already exists",
+            destination.display()
+        )));
+    }
+    let file_name = match destination.file_name() {
+        Some(file_name) => file_name,
+        None => return Err(io::Error::other("it has no file name")),
+    };
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".find-dups-import");
+    let temp_path = destination.with_file_name(temp_name);
+
+    let copy = || -> io::Result<()> {
+        let mut source = fs::File::open(path)?;
+        let permissions = source.metadata()?.permissions();
+        let mut copy = fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        io::copy(&mut source, &mut copy)?;
+        if let Some(modified) = hashed.modified {
+            copy.set_modified(modified)?;
+        }
+        drop(copy);
+        fs::set_permissions(&temp_path, permissions)?;
+        fs::rename(&temp_path, destination)
+    };
+    let result = copy();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+fn read_manifest(manifest_path: &path::Path) -> io::Result<Vec<(path::PathBuf, Sha256Sum)>> {
+    let file = fs::File::open(manifest_path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("unable to open '{}': {}", manifest_path.display(), e),
+        )
+    })?;
+    parse_manifest(io::BufReader::new(file))
+}
+
+// Reads manifests in the format sha256sum writes, which is also what
+// write_manifest_line writes.
+fn parse_manifest<R: BufRead>(reader: R) -> io::Result<Vec<(path::PathBuf, Sha256Sum)>> {
+    let mut entries = Vec::new();
+
+    for (line_num, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "line {} of manifest is not in sha256sum format",
+                    line_num + 1
+                ),
+            )
+        };
+
+        let (escaped, line) = match line.strip_prefix(b"\\") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+
+        // The hash is followed by a space and then either another space or
+        // a '*' for files that were read in binary mode.
+        if line.len() < 66 || line[64] != b' ' || (line[65] != b' ' && line[65] != b'*') {
+            return Err(invalid());
+        }
+
+        let mut hash: Sha256Sum = [0u8; 32];
+        hex::decode_to_slice(&line[..64], &mut hash).map_err(|_| invalid())?;
+
+        let mut path_bytes: Vec<u8> = Vec::with_capacity(line.len() - 66);
+        let mut rest = line[66..].iter();
+        while let Some(&b) = rest.next() {
+            match (escaped, b) {
+                (true, b'\\') => match rest.next() {
+                    Some(b'\\') => path_bytes.push(b'\\'),
+                    Some(b'n') => path_bytes.push(b'\n'),
+                    _ => return Err(invalid()),
+                },
+                _ => path_bytes.push(b),
+            }
+        }
+
+        let rel_path: path::PathBuf = path_bytes
+            .split(|&b| b == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .map(os_string_from_bytes)
+            .collect();
+        entries.push((rel_path, hash));
+    }
+
+    Ok(entries)
+}
+
+#[test]
+fn manifest_round_trips() {
+    let entries: Vec<(path::PathBuf, Sha256Sum)> = vec![
+        (["dir", "a b.txt"].iter().collect(), [1u8; 32]),
+        (["back\\slash", "new\nline"].iter().collect(), [2u8; 32]),
+    ];
+
+    let mut written = Vec::new();
+    for (rel_path, hash) in entries.iter() {
+        write_manifest_line(&mut written, hash, rel_path).unwrap();
+    }
+
+    assert_eq!(parse_manifest(&written[..]).unwrap(), entries);
+
+    let binary_mode = format!("{} *./file\n", hex::encode([3u8; 32]));
+    assert_eq!(
+        parse_manifest(binary_mode.as_bytes()).unwrap(),
+        vec![(path::PathBuf::from("file"), [3u8; 32])]
+    );
+
+    assert!(parse_manifest("not a manifest\n".as_bytes()).is_err());
+}
+
+fn object_path(objects_dir: &path::Path, hash: &Sha256Sum) -> path::PathBuf {
+    let hex = hex::encode(hash);
+    objects_dir.join(&hex[..2]).join(&hex)
+}
+
+// Copies a file into the store unless its content is already there.
+// Returns the number of bytes stored, or None if it was already present.
+//
+// The file is hashed again while it's copied, in case it changed since it
+// was first hashed, and the copy is only moved into place once it's
+// complete. An object in the store is always complete and matches its
+// name.
+fn store_object(
+    objects_dir: &path::Path,
+    tmp_dir: &path::Path,
+    source: &path::Path,
+    hash: &Sha256Sum,
+) -> io::Result<Option<u64>> {
+    let object_path = object_path(objects_dir, hash);
+    if object_path.exists() {
+        return Ok(None);
+    }
+
+    let tmp_path = tmp_dir.join(format!("{}.{}", hex::encode(hash), std::process::id()));
+    let mut tmp_file = fs::File::create(&tmp_path)?;
+
+    let copied = (|| {
+        let mut hasher = Sha256::new();
+        let mut reader = fs::File::open(source)?;
+        let mut tee = TeeWriter(&mut tmp_file, &mut hasher);
+        let size = io::copy(&mut reader, &mut tee)?;
+        tmp_file.sync_all()?;
+
+        if <[u8; 32]>::from(hasher.finalize()) != *hash {
+            return Err(io::Error::other("file changed while it was being ingested"));
+        }
+
+        fs::create_dir_all(object_path.parent().expect("objects are in a subdirectory"))?;
+        fs::rename(&tmp_path, &object_path)?;
+        Ok(size)
+    })();
+
+    if copied.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    copied.map(Some)
+}
+
+struct TeeWriter<'a, A: io::Write, B: io::Write>(&'a mut A, &'a mut B);
+
+impl<A: io::Write, B: io::Write> io::Write for TeeWriter<'_, A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+// Writes a line in the format sha256sum uses: the hash, two spaces, and
+// the path, always with '/' separators. Like sha256sum, paths with a
+// backslash or newline in them have those escaped and the line is marked
+// with a leading backslash.
+fn write_manifest_line<W: io::Write>(
+    out: &mut W,
+    hash: &Sha256Sum,
+    rel_path: &path::Path,
+) -> io::Result<()> {
+    let mut path_bytes: Vec<u8> = Vec::new();
+    for component in rel_path.components() {
+        if component == path::Component::RootDir {
+            path_bytes.push(b'/');
+            continue;
+        }
+        if !path_bytes.is_empty() && path_bytes.last() != Some(&b'/') {
+            path_bytes.push(b'/');
+        }
+        path_bytes.extend_from_slice(&os_str_bytes(component.as_os_str()));
+    }
+
+    let needs_escaping = path_bytes.iter().any(|&b| b == b'\\' || b == b'\n');
+    if needs_escaping {
+        out.write_all(b"\\")?;
+    }
+    out.write_all(hex::encode(hash).as_bytes())?;
+    out.write_all(b"  ")?;
+    if needs_escaping {
+        for &b in path_bytes.iter() {
+            match b {
+                b'\\' => out.write_all(b"\\\\")?,
+                b'\n' => out.write_all(b"\\n")?,
+                _ => out.write_all(&[b])?,
+            }
+        }
+    } else {
+        out.write_all(&path_bytes)?;
+    }
+    out.write_all(b"\n")
+}
+
+#[cfg(unix)]
+fn os_str_bytes(s: &std::ffi::OsStr) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    std::borrow::Cow::Borrowed(s.as_bytes())
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).to_os_string()
+}
+
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+#[cfg(not(unix))]
+fn os_str_bytes(s: &std::ffi::OsStr) -> std::borrow::Cow<'_, [u8]> {
+    match s.to_string_lossy() {
+        std::borrow::Cow::Borrowed(s) => std::borrow::Cow::Borrowed(s.as_bytes()),
+        std::borrow::Cow::Owned(s) => std::borrow::Cow::Owned(s.into_bytes()),
+    }
+}
+
+#[test]
+fn manifest_lines_match_sha256sum() {
+    let hash: Sha256Sum = [0xab; 32];
+    let line = |rel_path: &path::Path| {
+        let mut out = Vec::new();
+        write_manifest_line(&mut out, &hash, rel_path).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    let hex = hex::encode(hash);
+    assert_eq!(
+        line(&["dir", "a b.txt"].iter().collect::<path::PathBuf>()),
+        format!("{}  dir/a b.txt\n", hex)
+    );
+    assert_eq!(
+        line(path::Path::new("two\nlines")),
+        format!("\\{}  two\\nlines\n", hex)
+    );
+    #[cfg(unix)]
+    assert_eq!(
+        line(path::Path::new("/abs/path")),
+        format!("{}  /abs/path\n", hex)
+    );
+}
+
+#[cfg(feature = "images")]
+struct Photo {
+    path: PathLocation,
+    width: u32,
+    height: u32,
+    size: u64,
+    dhash: u64,
+}
+
+#[cfg(feature = "images")]
+fn is_photo(path: &path::Path) -> bool {
+    const EXTENSIONS: &[&str] = &["bmp", "gif", "jpeg", "jpg", "png", "tif", "tiff", "webp"];
+
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+// Decoding images is far more expensive than anything else done after the
+// walk, so it's spread across threads.
+#[cfg(feature = "images")]
+fn fingerprint_photos(paths: Vec<PathLocation>, num_threads: usize) -> Vec<Photo> {
+    let chunk_size = paths.len().div_ceil(num_threads.max(1)).max(1);
+
+    thread::scope(|scope| {
+        let threads: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .filter_map(|path| match fingerprint_photo(path) {
+                            Err(e) => {
+                                eprintln!("WARN: unable to decode photo {}: {}", path, e);
+                                None
+                            }
+                            Ok(photo) => Some(photo),
+                        })
+                        .collect::<Vec<Photo>>()
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+#[cfg(feature = "images")]
+fn fingerprint_photo(path: &PathLocation) -> Result<Photo, Box<dyn std::error::Error>> {
+    let size = fs::metadata(path.path())?.len();
+    let image = image::ImageReader::open(path.path())?
+        .with_guessed_format()?
+        .decode()?;
+
+    Ok(Photo {
+        path: path.clone(),
+        width: image.width(),
+        height: image.height(),
+        size,
+        dhash: dhash(&image),
+    })
+}
+
+// A difference hash: shrink the image to 9x8 grayscale pixels and record
+// whether each pixel is brighter than its right-hand neighbor. Resizing,
+// recompression, and small edits barely change it.
+#[cfg(feature = "images")]
+fn dhash(image: &image::DynamicImage) -> u64 {
+    let small = image
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+// Groups photos whose hashes are within max_distance bits of each other,
+// directly or through a chain of similar photos. Only groups of two or
+// more are returned, each with the suggested keeper first.
+#[cfg(feature = "images")]
+fn cluster_photos(photos: Vec<Photo>, max_distance: u32) -> Vec<Vec<Photo>> {
+    let distance = |a: u64, b: u64| (a ^ b).count_ones();
+
+    // A BK-tree over the hashes finds the similar photos without
+    // comparing every pair. Each node is (photo index, children by their
+    // distance from the node).
+    let mut tree: Vec<(usize, HashMap<u32, usize>)> = Vec::with_capacity(photos.len());
+    let mut parents: Vec<usize> = (0..photos.len()).collect();
+
+    fn find(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    for (i, photo) in photos.iter().enumerate() {
+        let mut pending = if tree.is_empty() { vec![] } else { vec![0] };
+        while let Some(node) = pending.pop() {
+            let (other, children) = &tree[node];
+            let d = distance(photo.dhash, photos[*other].dhash);
+            if d <= max_distance {
+                let (a, b) = (find(&mut parents, i), find(&mut parents, *other));
+                parents[a] = b;
+            }
+            pending.extend(
+                children
+                    .iter()
+                    .filter(|(child_d, _)| child_d.abs_diff(d) <= max_distance)
+                    .map(|(_, child)| *child),
+            );
+        }
+
+        let new_node = tree.len();
+        tree.push((i, HashMap::new()));
+        let mut node = 0;
+        while node != new_node {
+            let d = distance(photo.dhash, photos[tree[node].0].dhash);
+            node = *tree[node].1.entry(d).or_insert(new_node);
+        }
+    }
+
+    let mut clusters: HashMap<usize, Vec<Photo>> = HashMap::new();
+    for (i, photo) in photos.into_iter().enumerate() {
+        clusters
+            .entry(find(&mut parents, i))
+            .or_default()
+            .push(photo);
+    }
+
+    let mut clusters: Vec<Vec<Photo>> = clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .collect();
+
+    for cluster in clusters.iter_mut() {
+        cluster.sort_by(|a, b| {
+            let pixels = |p: &Photo| p.width as u64 * p.height as u64;
+            pixels(b)
+                .cmp(&pixels(a))
+                .then(b.size.cmp(&a.size))
+                .then_with(|| a.path.path().cmp(b.path.path()))
+        });
+    }
+    clusters.sort_by(|a, b| a[0].path.path().cmp(b[0].path.path()));
+
+    clusters
+}
+
+#[cfg(feature = "images")]
+#[test]
+fn cluster_photos_groups_near_hashes() {
+    let photo = |name: &str, width: u32, dhash: u64| Photo {
+        path: PathLocation::new_left(name),
+        width,
+        height: 100,
+        size: 1000,
+        dhash,
+    };
+
+    let photos = vec![
+        photo("burst1", 100, 0xFFFF_0000_FFFF_0000),
+        photo("burst2", 300, 0xFFFF_0000_FFFF_0003),
+        photo("burst3", 200, 0xFFFF_0000_FFFF_000F),
+        photo("other", 100, 0x0000_FFFF_0000_FFFF),
+        photo("alone", 100, 0x0F0F_0F0F_0F0F_0F0F),
+    ];
+
+    let clusters = cluster_photos(photos, 2);
+    let names: Vec<Vec<&path::Path>> = clusters
+        .iter()
+        .map(|c| c.iter().map(|p| p.path.path()).collect())
+        .collect();
+
+    // burst1 and burst3 are 4 bits apart, but both are within 2 bits of
+    // burst2, so all three end up together with the largest one first.
+    assert_eq!(
+        names,
+        vec![vec![
+            path::Path::new("burst2"),
+            path::Path::new("burst3"),
+            path::Path::new("burst1")
+        ]]
+    );
+}
+
+#[cfg(feature = "images")]
+#[test]
+fn dhash_survives_resizing() {
+    let gradient = image::DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(90, 80, |x, y| {
+        image::Luma([((x * 7 + y * 3) % 256) as u8])
+    }));
+    let smaller = gradient.resize_exact(45, 40, image::imageops::FilterType::Lanczos3);
+    let flipped = gradient.fliph();
+
+    assert!((dhash(&gradient) ^ dhash(&smaller)).count_ones() <= 4);
+    assert!((dhash(&gradient) ^ dhash(&flipped)).count_ones() > 20);
+}
+
+fn roots_for<'a>(args: &'a Args, path: &PathLocation) -> &'a [OsString] {
+    match path {
+        PathLocation::Left(_) => &args.left,
+        PathLocation::Right(_) => &args.right,
+    }
+}
+
+fn add_to_dir_counts(
+    dir_counts: &mut HashMap<path::PathBuf, (usize, usize)>,
+    args: &Args,
+    path: &PathLocation,
+) {
+    let dir = relative_path(roots_for(args, path), path.path())
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(path::Path::new("."));
+
+    let counts = dir_counts.entry(dir.to_path_buf()).or_insert((0, 0));
+    match path {
+        PathLocation::Left(_) => counts.0 += 1,
+        PathLocation::Right(_) => counts.1 += 1,
+    }
+}
+
+// The current command line, as it would be run with elevated privileges.
+#[cfg(unix)]
+fn elevated_command_line() -> String {
+    let quoted: Vec<String> = std::env::args_os()
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect();
+    format!("sudo {}", quoted.join(" "))
+}
+
+#[cfg(windows)]
+fn elevated_command_line() -> String {
+    let args: Vec<String> = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .map(|arg| match arg.contains(' ') {
+            true => format!("\"{}\"", arg),
+            false => arg,
+        })
+        .collect();
+    format!("{} (from an elevated prompt)", args.join(" "))
+}
+
+#[cfg(unix)]
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn shell_quote_only_quotes_when_needed() {
+    assert_eq!(shell_quote("--left=/mnt/a"), "--left=/mnt/a");
+    assert_eq!(shell_quote("a b"), "'a b'");
+    assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    assert_eq!(shell_quote(""), "''");
+}
+
+// The pager that standard output is piped into, if any. It's waited for
+// before exiting, so that the shell doesn't get the terminal back while
+// the pager is still using it.
+#[cfg(unix)]
+static PAGER_PID: atomic::AtomicI32 = atomic::AtomicI32::new(0);
+
+// Pipes standard output through $PAGER, or less, from here on.
+#[cfg(unix)]
+fn start_pager(mode: PagerMode) {
+    use std::io::IsTerminal;
+    use std::os::fd::AsRawFd;
+
+    if !pages(mode, io::stdout().is_terminal()) {
+        return;
+    }
+
+    let pager = pager_command(std::env::var("PAGER").ok());
+    let mut command = std::process::Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(&pager)
+        .stdin(std::process::Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        // Print reports that fit on one screen without paging them, and
+        // pass colors through.
+        command.env("LESS", "FRX");
+    }
+
+    let mut child = match command.spawn() {
+        Err(e) => {
+            eprintln!("WARN: unable to start pager '{}': {}", pager, e);
+            return;
+        }
+        Ok(child) => child,
+    };
+    let pipe = child
+        .stdin
+        .take()
+        .expect("The pager's stdin should be piped");
+
+    // SAFETY: dup2 only replaces descriptor 1, which Rust's stdout writes
+    // to without caching anything about it. Nothing has been printed yet.
+    // The handlers only use functions that are safe to call from a signal
+    // handler or at exit.
+    unsafe {
+        if libc::dup2(pipe.as_raw_fd(), libc::STDOUT_FILENO) == -1 {
+            eprintln!(
+                "WARN: unable to start pager '{}': {}",
+                pager,
+                io::Error::last_os_error()
+            );
+            let _ = child.kill();
+            return;
+        }
+
+        PAGER_PID.store(child.id() as i32, atomic::Ordering::SeqCst);
+        libc::atexit(wait_for_pager);
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::signal(
+                signal,
+                wait_for_pager_then_die as *const () as libc::sighandler_t,
+            );
+        }
+        // Once the pager has been quit there's nobody left to read the
+        // rest, so stop quietly rather than failing to print it.
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+}
+
+// Closes the pager's input, so that it knows everything has been printed,
+// and waits for it to be quit.
+#[cfg(unix)]
+extern "C" fn wait_for_pager() {
+    let pid = PAGER_PID.swap(0, atomic::Ordering::SeqCst);
+    if pid != 0 {
+        // SAFETY: see start_pager.
+        unsafe {
+            libc::close(libc::STDOUT_FILENO);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+// The pager gets the same signals from the terminal, and is left to decide
+// whether to quit, like less does only once it's told to.
+#[cfg(unix)]
+extern "C" fn wait_for_pager_then_die(signal: libc::c_int) {
+    wait_for_pager();
+    // SAFETY: see start_pager.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+// Whether --pager pages, given where standard output goes.
+#[cfg(unix)]
+fn pages(mode: PagerMode, stdout_is_terminal: bool) -> bool {
+    match mode {
+        PagerMode::Auto => stdout_is_terminal,
+        PagerMode::Always => true,
+        PagerMode::Never => false,
+    }
+}
+
+// $PAGER, unless it's unset or blank, when less is used instead.
+#[cfg(unix)]
+fn pager_command(pager: Option<String>) -> String {
+    pager
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| "less".to_string())
+}
+
+#[cfg(unix)]
+#[test]
+fn pager_is_chosen_from_mode_and_environment() {
+    assert!(pages(PagerMode::Auto, true));
+    assert!(!pages(PagerMode::Auto, false));
+    assert!(pages(PagerMode::Always, false));
+    assert!(!pages(PagerMode::Never, true));
+
+    assert_eq!(pager_command(None), "less");
+    assert_eq!(pager_command(Some("  ".to_string())), "less");
+    assert_eq!(pager_command(Some("more -d".to_string())), "more -d");
+}
+
+#[cfg(not(unix))]
+fn start_pager(mode: PagerMode) {
+    if mode != PagerMode::Never {
+        eprintln!("WARN: --pager is only supported on Unix. Printing without it.");
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn enter_background_mode() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: setpriority and ioprio_set only affect the scheduling of the
+    // calling process and don't touch any memory we own.
+    unsafe {
+        if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn enter_background_mode() -> io::Result<()> {
+    // PRIO_DARWIN_BG lowers both CPU and IO priority.
+    //
+    // SAFETY: setpriority only affects the scheduling of the calling
+    // process and doesn't touch any memory we own.
+    if unsafe { libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn enter_background_mode() -> io::Result<()> {
+    // There's no portable way to lower IO priority, so only CPU priority
+    // is lowered here.
+    //
+    // SAFETY: setpriority only affects the scheduling of the calling
+    // process and doesn't touch any memory we own.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn enter_background_mode() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN,
+    };
+
+    // Background mode lowers both CPU and IO priority.
+    //
+    // SAFETY: GetCurrentProcess returns a pseudo-handle that doesn't need
+    // to be closed, and SetPriorityClass only affects scheduling.
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Formats a modification time as a UTC date and time, to the second.
+fn format_modified(modified: Option<time::SystemTime>) -> String {
+    let secs = match modified.map(|m| m.duration_since(time::UNIX_EPOCH)) {
+        Some(Ok(d)) => d.as_secs() as i64,
+        Some(Err(e)) => -(e.duration().as_secs() as i64) - 1,
+        None => return "unknown-time".to_string(),
+    };
+
+    // Converts days since the epoch to a civil date. See
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = secs.div_euclid(86400);
+    let time_of_day = secs.rem_euclid(86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
+
+#[test]
+fn format_modified_is_utc() {
+    let at = |secs: u64| Some(time::UNIX_EPOCH + time::Duration::from_secs(secs));
+
+    assert_eq!(format_modified(at(0)), "1970-01-01T00:00:00Z");
+    assert_eq!(format_modified(at(1_700_000_000)), "2023-11-14T22:13:20Z");
+    assert_eq!(format_modified(at(951_782_400)), "2000-02-29T00:00:00Z");
+    assert_eq!(format_modified(None), "unknown-time");
+}
+
+struct StrategySavings {
+    strategy: &'static str,
+    files: usize,
+    bytes: u64,
+}
+
+// Works out how many files, and how many bytes, each way of handling the
+// 'both' locations would reclaim. Hard links and reflinks can only point
+// at a copy on the same device, so right-hand files that aren't on the
+// same device as their left-hand copy don't count for those.
+fn simulate_strategies<F>(
+    both: &[(Vec<path::PathBuf>, Vec<path::PathBuf>)],
+    sizes: &HashMap<path::PathBuf, u64>,
+    same_device: F,
+) -> Vec<StrategySavings>
+where
+    F: Fn(&path::Path, &path::Path) -> bool,
+{
+    let mut delete_right = StrategySavings {
+        strategy: "delete-right",
+        files: 0,
+        bytes: 0,
+    };
+    let mut link_right = StrategySavings {
+        strategy: "hardlink",
+        files: 0,
+        bytes: 0,
+    };
+    let mut keep_newest = StrategySavings {
+        strategy: "keep-newest",
+        files: 0,
+        bytes: 0,
+    };
+
+    for (lpaths, rpaths) in both {
+        // Every path in a location has the same content, so the size of
+        // any one of them is the size of all of them.
+        let size = sizes.get(&lpaths[0]).copied().unwrap_or(0);
+
+        delete_right.files += rpaths.len();
+        delete_right.bytes += size * rpaths.len() as u64;
+
+        let linkable = rpaths
+            .iter()
+            .filter(|rpath| same_device(&lpaths[0], rpath))
+            .count();
+        link_right.files += linkable;
+        link_right.bytes += size * linkable as u64;
+
+        let extra_copies = lpaths.len() + rpaths.len() - 1;
+        keep_newest.files += extra_copies;
+        keep_newest.bytes += size * extra_copies as u64;
+    }
+
+    let reflink = StrategySavings {
+        strategy: "reflink",
+        files: link_right.files,
+        bytes: link_right.bytes,
+    };
+
+    vec![delete_right, link_right, reflink, keep_newest]
+}
+
+// Whether two files are on the same device, and so can be hard linked or
+// reflinked to each other. Files that can't be inspected aren't.
+#[cfg(unix)]
+fn on_same_device(lhs: &path::Path, rhs: &path::Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(lhs), fs::metadata(rhs)) {
+        (Ok(lhs), Ok(rhs)) => lhs.dev() == rhs.dev(),
+        _ => false,
+    }
+}
+
+// Windows only exposes the volume of a file through handles to it, so the
+// path prefix, like a drive letter, stands in for it.
+#[cfg(not(unix))]
+fn on_same_device(lhs: &path::Path, rhs: &path::Path) -> bool {
+    match (fs::canonicalize(lhs), fs::canonicalize(rhs)) {
+        (Ok(lhs), Ok(rhs)) => lhs.components().next() == rhs.components().next(),
+        _ => false,
+    }
+}
+
+#[test]
+fn simulate_strategies_counts_reclaimable_copies() {
+    let both = vec![
+        (
+            vec![path::PathBuf::from("l/a"), path::PathBuf::from("l/b")],
+            vec![path::PathBuf::from("r/a"), path::PathBuf::from("other/a")],
+        ),
+        (
+            vec![path::PathBuf::from("l/c")],
+            vec![path::PathBuf::from("r/c")],
+        ),
+    ];
+    let sizes = HashMap::from([
+        (path::PathBuf::from("l/a"), 100),
+        (path::PathBuf::from("l/c"), 10),
+    ]);
+
+    let simulation = simulate_strategies(&both, &sizes, |_, rpath| rpath.starts_with("r"));
+    let results: Vec<(&str, usize, u64)> = simulation
+        .iter()
+        .map(|savings| (savings.strategy, savings.files, savings.bytes))
+        .collect();
+
+    assert_eq!(
+        results,
+        vec![
+            ("delete-right", 3, 210),
+            ("hardlink", 2, 110),
+            ("reflink", 2, 110),
+            ("keep-newest", 4, 310),
+        ]
+    );
+}
+
+// How many files an action changed, or with --dry-run would have changed,
+// and how many it left alone.
+#[derive(Default)]
+struct ActionTally {
+    done: usize,
+    bytes: u64,
+    failed: usize,
+}
+
+impl ActionTally {
+    fn add(&mut self, hashed: Option<&HashedFile>) {
+        self.done += 1;
+        self.bytes += hashed.and_then(|hashed| hashed.size).unwrap_or(0);
+    }
+
+    // Prints a summary like "Replaced 2 file(s), 10 B, with symbolic links."
+    fn summarize(&self, dry_run: bool, done: &str, would: &str, how: &str) {
+        let (verb, left_alone) = if dry_run {
+            (would, "would be left alone")
+        } else {
+            (done, "were left alone")
+        };
+        eprintln!(
+            "{} {} file(s), {}, {}. {} file(s) {}.",
+            verb,
+            self.done,
+            BinaryBytes(self.bytes),
+            how,
+            self.failed,
+            left_alone
+        );
+    }
+}
+
+// Replaces each right-hand path in the 'both' locations with a symbolic
+// link to the first left-hand path of its location. Failures are reported
+// and don't stop the remaining replacements.
+fn symlink_duplicates(
+    both: &[(Vec<path::PathBuf>, Vec<path::PathBuf>)],
+    style: SymlinkStyle,
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+    dry_run: bool,
+) {
+    let mut tally = ActionTally::default();
+
+    for (lpaths, rpaths) in both {
+        let keeper = &lpaths[0];
+        for rpath in rpaths {
+            let hashed = hashed_files.get(rpath);
+            let keeper_hashed = hashed_files.get(keeper);
+            match replace_with_symlink(rpath, keeper, style, hashed, keeper_hashed, dry_run) {
+                Ok(target) => {
+                    tally.add(hashed);
+                    println!("~> '{}' -> '{}'", rpath.display(), target.display());
+                }
+                Err(e) => {
+                    tally.failed += 1;
+                    eprintln!("WARN: not replacing '{}': {}", rpath.display(), e);
+                }
+            }
+        }
+    }
+
+    tally.summarize(dry_run, "Replaced", "Would replace", "with symbolic links");
+}
+
+// Replaces path with a symbolic link to keeper, returning the link target.
+// The file is left alone if it, or keeper, no longer matches what was
+// hashed, so that it's never replaced by a link to other content. The link
+// is created next to the file under a temporary name and renamed over it,
+// so the file is never missing if something fails part way. With dry_run,
+// only the checks are made.
+fn replace_with_symlink(
+    path: &path::Path,
+    keeper: &path::Path,
+    style: SymlinkStyle,
+    hashed: Option<&HashedFile>,
+    keeper_hashed: Option<&HashedFile>,
+    dry_run: bool,
+) -> io::Result<path::PathBuf> {
+    ensure_unchanged(path, hashed)?;
+    ensure_keeper_unchanged(keeper, keeper_hashed)?;
+
+    let keeper = fs::canonicalize(keeper)?;
+    if fs::canonicalize(path)? == keeper {
+        return Err(io::Error::other("it is the copy being kept"));
+    }
+
+    let (dir, file_name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(file_name)) => (dir, file_name),
+        _ => return Err(io::Error::other("it has no parent directory")),
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        path::Path::new(".")
+    } else {
+        dir
+    };
+
+    let target = match style {
+        SymlinkStyle::Relative => relative_link_target(&fs::canonicalize(dir)?, &keeper),
+        SymlinkStyle::Absolute => keeper,
+    };
+    if dry_run {
+        return Ok(target);
+    }
+
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".find-dups-link");
+    let temp_path = dir.join(temp_name);
+
+    create_file_symlink(&target, &temp_path)?;
+    if let Err(e) = fs::rename(&temp_path, path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    Ok(target)
+}
+
+// Replaces each right-hand path in the 'both' locations with a copy-on-write
+// clone of the first left-hand path of its location. Failures are reported
+// and don't stop the remaining replacements.
+fn reflink_duplicates(
+    both: &[(Vec<path::PathBuf>, Vec<path::PathBuf>)],
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+    dry_run: bool,
+) {
+    let mut tally = ActionTally::default();
+
+    for (lpaths, rpaths) in both {
+        let keeper = &lpaths[0];
+        for rpath in rpaths {
+            let hashed = hashed_files.get(rpath);
+            let keeper_hashed = hashed_files.get(keeper);
+            match replace_with_clone(rpath, keeper, hashed, keeper_hashed, dry_run) {
+                Ok(()) => {
+                    tally.add(hashed);
+                    println!("=> '{}' -> '{}'", rpath.display(), keeper.display());
+                }
+                Err(e) => {
+                    tally.failed += 1;
+                    eprintln!("WARN: not replacing '{}': {}", rpath.display(), e);
+                }
+            }
+        }
+    }
+
+    tally.summarize(
+        dry_run,
+        "Replaced",
+        "Would replace",
+        "with copy-on-write clones",
+    );
+}
+
+// Replaces path with a copy-on-write clone of keeper that keeps path's
+// permissions and modification time. Like replace_with_symlink, the file is
+// left alone if it or keeper no longer matches what was hashed, and the
+// clone is made under a temporary name and renamed over it. With dry_run, only the checks
+// are made, so filesystems that can't clone aren't found out.
+fn replace_with_clone(
+    path: &path::Path,
+    keeper: &path::Path,
+    hashed: Option<&HashedFile>,
+    keeper_hashed: Option<&HashedFile>,
+    dry_run: bool,
+) -> io::Result<()> {
+    ensure_unchanged(path, hashed)?;
+    ensure_keeper_unchanged(keeper, keeper_hashed)?;
+
+    if fs::canonicalize(path)? == fs::canonicalize(keeper)? {
+        return Err(io::Error::other("it is the copy being kept"));
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name,
+        None => return Err(io::Error::other("it has no file name")),
+    };
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".find-dups-clone");
+    let temp_path = path.with_file_name(temp_name);
+
+    let metadata = fs::metadata(path)?;
+    let result = clone_file(keeper, &temp_path)
+        .and_then(|()| {
+            fs::File::options()
+                .write(true)
+                .open(&temp_path)?
+                .set_modified(metadata.modified()?)
+        })
+        .and_then(|()| fs::set_permissions(&temp_path, metadata.permissions()))
+        .and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+#[cfg(target_os = "linux")]
+fn clone_file(source: &path::Path, clone: &path::Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = fs::File::open(source)?;
+    let clone_file = fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(clone)?;
+
+    // SAFETY: both file descriptors are open for as long as the call runs,
+    // and FICLONE doesn't touch any memory we own.
+    if unsafe { libc::ioctl(clone_file.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        let _ = fs::remove_file(clone);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn clone_file(source: &path::Path, clone: &path::Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |path: &path::Path| {
+        std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::other("the path contains a NUL byte"))
+    };
+    let (source, clone) = (c_path(source)?, c_path(clone)?);
+
+    // SAFETY: both paths are NUL-terminated strings that outlive the call.
+    if unsafe { libc::clonefile(source.as_ptr(), clone.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn clone_file(_source: &path::Path, _clone: &path::Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "copy-on-write clones are only supported on Linux and macOS",
+    ))
+}
+
+// Moves each right-hand path in the 'both' locations into the holding
+// directory, at the same path relative to it as it had relative to its
+// root, so they can be looked over before they're deleted for good.
+// Failures are reported and don't stop the remaining moves.
+fn move_duplicates(
+    both: &[(Vec<path::PathBuf>, Vec<path::PathBuf>)],
+    right_roots: &[OsString],
+    holding_dir: &path::Path,
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+    dry_run: bool,
+) {
+    let mut tally = ActionTally::default();
+
+    for (lpaths, rpaths) in both {
+        for rpath in rpaths {
+            let destination = holding_dir.join(relative_path(right_roots, rpath));
+            // When the roots overlap, a right-hand path can be a left-hand
+            // copy too.
+            let hashed = hashed_files.get(rpath);
+            let result = if lpaths.contains(rpath) {
+                Err(io::Error::other("it is also a left-hand copy"))
+            } else {
+                move_file(rpath, &destination, hashed, dry_run)
+            };
+            match result {
+                Ok(()) => {
+                    tally.add(hashed);
+                    println!(">> '{}' -> '{}'", rpath.display(), destination.display());
+                }
+                Err(e) => {
+                    tally.failed += 1;
+                    eprintln!("WARN: not moving '{}': {}", rpath.display(), e);
+                }
+            }
+        }
+    }
+
+    tally.summarize(
+        dry_run,
+        "Moved",
+        "Would move",
+        &format!("to '{}'", holding_dir.display()),
+    );
+}
+
+// Moves a file, copying it when the destination is on another device. The
+// file is left alone if it no longer matches what was hashed, and nothing
+// already at the destination is replaced. With dry_run, only the checks are
+// made.
+fn move_file(
+    path: &path::Path,
+    destination: &path::Path,
+    hashed: Option<&HashedFile>,
+    dry_run: bool,
+) -> io::Result<()> {
+    ensure_unchanged(path, hashed)?;
+
+    if fs::symlink_metadata(destination).is_ok() {
+        return Err(io::Error::other(format!(
+            "'{}' already exists",
+            destination.display()
+        )));
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(path, destination) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if let Err(e) = fs::copy(path, destination).and_then(|_| fs::remove_file(path)) {
+                let _ = fs::remove_file(destination);
+                return Err(e);
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[test]
+fn dry_runs_leave_files_alone() {
+    let root = TempDir::new("dry-run");
+    fs::create_dir_all(root.join("left")).unwrap();
+    fs::create_dir_all(root.join("right")).unwrap();
+    let (keeper, copy) = (root.join("left/a"), root.join("right/a"));
+    fs::write(&keeper, b"contents").unwrap();
+    fs::write(&copy, b"contents").unwrap();
+
+    let mut hashed_files = HashMap::new();
+    for path in [&keeper, &copy] {
+        let metadata = fs::metadata(path).unwrap();
+        let hashed = HashedFile {
+            hash: [0; 32],
+            size: Some(metadata.len()),
+            modified: metadata.modified().ok(),
+            file_id: None,
+        };
+        hashed_files.insert(path.clone(), hashed);
+    }
+    let both = [(vec![keeper.clone()], vec![copy.clone()])];
+    let holding_dir = root.join("held");
+
+    symlink_duplicates(&both, SymlinkStyle::Relative, &hashed_files, true);
+    reflink_duplicates(&both, &hashed_files, true);
+    move_duplicates(
+        &both,
+        &[root.join("right").into()],
+        &holding_dir,
+        &hashed_files,
+        true,
+    );
+
+    assert!(fs::symlink_metadata(&copy).unwrap().is_file());
+    assert_eq!(fs::read(&copy).unwrap(), b"contents");
+    assert!(!holding_dir.exists());
+}
+
+// Fails unless path is still the regular file that was hashed, going by its
+// size and modification time.
+fn ensure_unchanged(path: &path::Path, hashed: Option<&HashedFile>) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_file() {
+        return Err(io::Error::other("it is no longer a regular file"));
+    }
+
+    match hashed {
+        Some(hashed)
+            if hashed.size == Some(metadata.len())
+                && hashed.modified == metadata.modified().ok() =>
+        {
+            Ok(())
+        }
+        _ => Err(io::Error::other("it changed after it was hashed")),
+    }
+}
+
+// Like ensure_unchanged, for the copy that's kept, saying which copy it is.
+fn ensure_keeper_unchanged(keeper: &path::Path, hashed: Option<&HashedFile>) -> io::Result<()> {
+    ensure_unchanged(keeper, hashed).map_err(|e| {
+        io::Error::other(format!(
+            "the copy being kept, '{}', can't be used: {}",
+            keeper.display(),
+            e
+        ))
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn duplicates_are_only_replaced_from_unchanged_keepers() {
+    let root = TempDir::new("keeper");
+    let (keeper, copy) = (root.join("keeper"), root.join("copy"));
+    fs::write(&keeper, b"contents").unwrap();
+    fs::write(&copy, b"contents").unwrap();
+
+    let hashed = |path: &path::Path| {
+        let metadata = fs::metadata(path).unwrap();
+        HashedFile {
+            hash: [0; 32],
+            size: Some(metadata.len()),
+            modified: metadata.modified().ok(),
+            file_id: None,
+        }
+    };
+    let (keeper_hashed, copy_hashed) = (hashed(&keeper), hashed(&copy));
+
+    fs::write(&keeper, b"changed!").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&keeper)
+        .unwrap()
+        .set_modified(time::SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let replaced = replace_with_symlink(
+        &copy,
+        &keeper,
+        SymlinkStyle::Relative,
+        Some(&copy_hashed),
+        Some(&keeper_hashed),
+        false,
+    );
+    assert!(replaced.is_err());
+    assert!(!copy.is_symlink());
+
+    fs::remove_file(&keeper).unwrap();
+    let replaced = replace_with_symlink(
+        &copy,
+        &keeper,
+        SymlinkStyle::Relative,
+        Some(&copy_hashed),
+        Some(&keeper_hashed),
+        false,
+    );
+    assert!(replaced.is_err());
+    assert_eq!(fs::read(&copy).unwrap(), b"contents");
+    let cloned = replace_with_clone(
+        &copy,
+        &keeper,
+        Some(&copy_hashed),
+        Some(&keeper_hashed),
+        false,
+    );
+    assert!(cloned.is_err());
+    assert_eq!(fs::read(&copy).unwrap(), b"contents");
+}
+
+#[cfg(unix)]
+fn create_file_symlink(target: &path::Path, link: &path::Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn create_file_symlink(target: &path::Path, link: &path::Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+// Returns the path of to relative to the directory from_dir. Both must be
+// absolute. When they share no root, like paths on different Windows
+// drives, to is returned as it is.
+fn relative_link_target(from_dir: &path::Path, to: &path::Path) -> path::PathBuf {
+    let mut from_components = from_dir.components().peekable();
+    let mut to_components = to.components().peekable();
+
+    if from_components.peek() != to_components.peek() {
+        return to.to_path_buf();
+    }
+
+    while from_components.peek().is_some() && from_components.peek() == to_components.peek() {
+        from_components.next();
+        to_components.next();
+    }
+
+    from_components
+        .map(|_| path::Component::ParentDir)
+        .chain(to_components)
+        .collect()
+}
+
+#[test]
+fn relative_link_target_walks_up_to_common_ancestor() {
+    assert_eq!(
+        relative_link_target(path::Path::new("/a/b/c"), path::Path::new("/a/d/e")),
+        path::Path::new("../../d/e")
+    );
+    assert_eq!(
+        relative_link_target(path::Path::new("/a"), path::Path::new("/a/b")),
+        path::Path::new("b")
+    );
+    assert_eq!(
+        relative_link_target(path::Path::new("/a/b"), path::Path::new("/c")),
+        path::Path::new("../../c")
+    );
+}
+
+// A file known only by its path relative to the root it was found under
+// and its size. Its contents have not been read.
+#[derive(Debug, PartialEq)]
+struct ListedFile {
+    path: path::PathBuf,
+    size: u64,
+}
+
+fn compare_structure(args: &Args, walk_options: &WalkOptions) -> io::Result<()> {
+    let left = list_side(&args.left, &args.left_listing, walk_options)?;
+    let right = list_side(&args.right, &args.right_listing, walk_options)?;
+
+    println!(
+        "NOTE: compared by relative path and size only. Modification times were ignored and file contents were not hashed."
+    );
+
+    for (path, lsize) in left.iter() {
+        match right.get(path) {
+            None if !args.omit_left => println!("<= '{}'", path.display()),
+            Some(rsize) if rsize != lsize => println!(
+                "<~> '{}' (left: {} bytes, right: {} bytes)",
+                path.display(),
+                lsize,
+                rsize
+            ),
+            Some(_) if args.show_both => println!("<=> '{}'", path.display()),
+            _ => (),
+        }
+    }
+
+    if !args.omit_right {
+        for path in right.keys().filter(|path| !left.contains_key(*path)) {
+            println!("=> '{}'", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+// Statistics about the files on one side, from a walk alone.
+#[derive(Debug, Default, PartialEq)]
+struct TreeStats {
+    files: usize,
+    bytes: u64,
+    // The number of files in each of SIZE_BUCKETS.
+    sizes: Vec<usize>,
+    // The number of files at each depth, where files directly in a root
+    // are at depth 1.
+    depths: BTreeMap<usize, usize>,
+    // The directories with the most bytes in them, including everything
+    // below them, largest first.
+    largest_dirs: Vec<(path::PathBuf, u64)>,
+}
+
+// The upper bound of each file size bucket, and its label.
+const SIZE_BUCKETS: [(u64, &str); 8] = [
+    (1, "empty"),
+    (4 << 10, "< 4 KiB"),
+    (64 << 10, "< 64 KiB"),
+    (1 << 20, "< 1 MiB"),
+    (16 << 20, "< 16 MiB"),
+    (256 << 20, "< 256 MiB"),
+    (4 << 30, "< 4 GiB"),
+    (u64::MAX, ">= 4 GiB"),
+];
+
+const LARGEST_DIRS_SHOWN: usize = 10;
+
+fn tree_stats(files: &BTreeMap<path::PathBuf, u64>) -> TreeStats {
+    let mut stats = TreeStats {
+        sizes: vec![0; SIZE_BUCKETS.len()],
+        ..TreeStats::default()
+    };
+    let mut dir_bytes: HashMap<&path::Path, u64> = HashMap::new();
+
+    for (path, size) in files {
+        stats.files += 1;
+        stats.bytes += size;
+
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|(limit, _)| size < limit)
+            .unwrap_or(SIZE_BUCKETS.len() - 1);
+        stats.sizes[bucket] += 1;
+
+        *stats.depths.entry(path.components().count()).or_default() += 1;
+
+        for dir in path.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() {
+                *dir_bytes.entry(dir).or_default() += size;
+            }
+        }
+    }
+
+    let mut largest_dirs: Vec<(&path::Path, u64)> = dir_bytes.into_iter().collect();
+    largest_dirs.sort_by(|(lpath, lbytes), (rpath, rbytes)| {
+        rbytes.cmp(lbytes).then_with(|| lpath.cmp(rpath))
+    });
+    stats.largest_dirs = largest_dirs
+        .into_iter()
+        .take(LARGEST_DIRS_SHOWN)
+        .map(|(dir, bytes)| (dir.to_path_buf(), bytes))
+        .collect();
+
+    stats
+}
+
+#[test]
+fn tree_stats_buckets_sizes_and_depths() {
+    let files = BTreeMap::from([
+        (path::PathBuf::from("empty"), 0),
+        (path::PathBuf::from("a/small"), 100),
+        (path::PathBuf::from("a/b/big"), 2 << 20),
+        (path::PathBuf::from("c/small"), 200),
+    ]);
+
+    let stats = tree_stats(&files);
+
+    assert_eq!(stats.files, 4);
+    assert_eq!(stats.bytes, (2 << 20) + 300);
+    assert_eq!(stats.sizes, vec![1, 2, 0, 0, 1, 0, 0, 0]);
+    assert_eq!(stats.depths, BTreeMap::from([(1, 1), (2, 2), (3, 1)]));
+    assert_eq!(
+        stats.largest_dirs,
+        vec![
+            (path::PathBuf::from("a"), (2 << 20) + 100),
+            (path::PathBuf::from("a/b"), 2 << 20),
+            (path::PathBuf::from("c"), 200),
+        ]
+    );
+}
+
+// Walks both sides without hashing anything and prints statistics about
+// each, to help pick filters before running a full comparison.
+fn print_stats(args: &Args, walk_options: &WalkOptions) -> io::Result<()> {
+    let sides = [
+        ("<=", "left", &args.left, &args.left_listing),
+        ("=>", "right", &args.right, &args.right_listing),
+    ];
+
+    for (prefix, name, roots, listings) in sides {
+        let stats = tree_stats(&list_side(roots, listings, walk_options)?);
+
+        println!(
+            "{} {}: {} files, {}",
+            prefix,
+            name,
+            stats.files,
+            BinaryBytes(stats.bytes)
+        );
+        println!("  sizes:");
+        for ((_, label), count) in SIZE_BUCKETS.iter().zip(&stats.sizes) {
+            println!("    {:<10} {:>10}", label, count);
+        }
+        println!("  depths:");
+        for (depth, count) in &stats.depths {
+            println!("    {:<10} {:>10}", depth, count);
+        }
+        println!("  largest directories:");
+        for (dir, bytes) in &stats.largest_dirs {
+            println!(
+                "    {:>10}  '{}'",
+                BinaryBytes(*bytes).to_string(),
+                dir.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Walks both sides at the same time, so that a slow side doesn't delay
+// the other, and prints the paths that only exist on one side.
+fn print_early_structure(args: &Args, walk_options: &WalkOptions) {
+    let (left_only, right_only) = early_structure(args, walk_options);
+
+    if !args.omit_left {
+        for path in left_only {
+            println!("<- '{}'", path.display());
+        }
+    }
+
+    if !args.omit_right {
+        for path in right_only {
+            println!("-> '{}'", path.display());
+        }
+    }
+}
+
+// Returns the files only on the left, then those only on the right, by
+// their relative paths.
+fn early_structure(
+    args: &Args,
+    walk_options: &WalkOptions,
+) -> (Vec<path::PathBuf>, Vec<path::PathBuf>) {
+    let (left, right) = thread::scope(|scope| {
+        let left = scope.spawn(|| list_side(&args.left, &[], walk_options));
+        let right = scope.spawn(|| list_side(&args.right, &[], walk_options));
+        (left.join(), right.join())
+    });
+
+    // list_side only fails when reading listings, and none were given.
+    let left = left
+        .unwrap_or_else(|e| panic::resume_unwind(e))
+        .expect("Walking without listings should not fail");
+    let right = right
+        .unwrap_or_else(|e| panic::resume_unwind(e))
+        .expect("Walking without listings should not fail");
+
+    let only = |here: &BTreeMap<path::PathBuf, u64>, there: &BTreeMap<path::PathBuf, u64>| {
+        here.keys()
+            .filter(|path| !there.contains_key(*path))
+            .cloned()
+            .collect()
+    };
+    (only(&left, &right), only(&right, &left))
+}
+
+#[test]
+fn early_structure_gives_files_only_on_one_side() {
+    let root = TempDir::new("early");
+    let (left, right) = (root.join("left"), root.join("right"));
+    fs::create_dir_all(left.join("sub")).unwrap();
+    fs::create_dir_all(&right).unwrap();
+    fs::write(left.join("both"), b"left").unwrap();
+    fs::write(right.join("both"), b"right").unwrap();
+    fs::write(left.join("sub/gone"), b"gone").unwrap();
+    fs::write(right.join("new"), b"new").unwrap();
+
+    let args = Args::try_parse_from([
+        OsString::from("find-dups"),
+        OsString::from("--early-structure"),
+        OsString::from("--left"),
+        left.clone().into(),
+        OsString::from("--right"),
+        right.clone().into(),
+    ])
+    .unwrap();
+    let differences = early_structure(&args, &WalkOptions::default());
+
+    assert_eq!(
+        differences,
+        (
+            vec![path::PathBuf::from("sub/gone")],
+            vec![path::PathBuf::from("new")]
+        )
+    );
+}
+
+// Returns the directories that have no files anywhere below them and
+// aren't on the other side at all, only giving the topmost of each such
+// tree. Those are the directories that copying the files alone would lose.
+fn missing_empty_dirs(
+    files: &BTreeMap<path::PathBuf, u64>,
+    dirs: &BTreeSet<path::PathBuf>,
+    other_dirs: &BTreeSet<path::PathBuf>,
+) -> Vec<path::PathBuf> {
+    let with_files: HashSet<&path::Path> = files
+        .keys()
+        .flat_map(|file| file.ancestors().skip(1))
+        .collect();
+
+    let missing: BTreeSet<&path::PathBuf> = dirs
+        .iter()
+        .filter(|dir| !with_files.contains(dir.as_path()) && !other_dirs.contains(*dir))
+        .collect();
+
+    missing
+        .iter()
+        .filter(|dir| {
+            dir.parent()
+                .is_none_or(|parent| !missing.contains(&parent.to_path_buf()))
+        })
+        .map(|dir| dir.to_path_buf())
+        .collect()
+}
+
+#[test]
+fn missing_empty_dirs_gives_topmost_only() {
+    let files = BTreeMap::from([(path::PathBuf::from("full/file"), 1)]);
+    let dirs: BTreeSet<path::PathBuf> = ["full", "full/empty", "gone", "gone/deeper", "kept"]
+        .into_iter()
+        .map(path::PathBuf::from)
+        .collect();
+    let other_dirs: BTreeSet<path::PathBuf> =
+        ["kept"].into_iter().map(path::PathBuf::from).collect();
+
+    assert_eq!(
+        missing_empty_dirs(&files, &dirs, &other_dirs),
+        vec![
+            path::PathBuf::from("full/empty"),
+            path::PathBuf::from("gone")
+        ]
+    );
+}
+
+// Walks both sides again, without reading any files, and prints the empty
+// directories that are only on one of them. Returns whether there were
+// any.
+fn report_empty_dirs(args: &Args, walk_options: &WalkOptions) -> bool {
+    let (left_files, left_dirs) = walk_side(&args.left, walk_options);
+    let (right_files, right_dirs) = walk_side(&args.right, walk_options);
+
+    let left_only = missing_empty_dirs(&left_files, &left_dirs, &right_dirs);
+    let right_only = missing_empty_dirs(&right_files, &right_dirs, &left_dirs);
+    for dir in &left_only {
+        println!("<= empty directory '{}'", dir.display());
+    }
+    for dir in &right_only {
+        println!("=> empty directory '{}'", dir.display());
+    }
+
+    !left_only.is_empty() || !right_only.is_empty()
+}
+
+// The differences in layout between two sides, ignoring content.
+#[derive(Debug, Default, PartialEq)]
+struct StructureDifferences {
+    // Files and directories at a relative path that is only on one side.
+    // Only the topmost entry of each such tree is given.
+    left_only: Vec<path::PathBuf>,
+    right_only: Vec<path::PathBuf>,
+    // Entries whose relative paths only differ in case, left then right.
+    case_only: Vec<(path::PathBuf, path::PathBuf)>,
+}
+
+impl StructureDifferences {
+    fn is_empty(&self) -> bool {
+        self.left_only.is_empty() && self.right_only.is_empty() && self.case_only.is_empty()
+    }
+}
+
+fn find_structure_differences(
+    left: &BTreeSet<path::PathBuf>,
+    right: &BTreeSet<path::PathBuf>,
+) -> StructureDifferences {
+    let topmost_only = |here: &BTreeSet<path::PathBuf>, there: &BTreeSet<path::PathBuf>| {
+        let only: BTreeSet<&path::PathBuf> = here.difference(there).collect();
+        only.iter()
+            .filter(|entry| {
+                entry
+                    .parent()
+                    .is_none_or(|parent| !only.contains(&parent.to_path_buf()))
+            })
+            .map(|entry| entry.to_path_buf())
+            .collect::<Vec<_>>()
+    };
+    let lowercase = |entry: &path::Path| entry.to_string_lossy().to_lowercase();
+
+    let mut right_only: BTreeMap<String, path::PathBuf> = topmost_only(right, left)
+        .into_iter()
+        .map(|entry| (lowercase(&entry), entry))
+        .collect();
+
+    let mut differences = StructureDifferences::default();
+    for entry in topmost_only(left, right) {
+        match right_only.remove(&lowercase(&entry)) {
+            Some(right_entry) => differences.case_only.push((entry, right_entry)),
+            None => differences.left_only.push(entry),
+        }
+    }
+    differences.right_only = right_only.into_values().collect();
+    differences.right_only.sort_unstable();
+    differences
+}
+
+#[test]
+fn structure_differences_pair_up_case() {
+    let entries = |paths: &[&str]| -> BTreeSet<path::PathBuf> {
+        paths.iter().map(path::PathBuf::from).collect()
+    };
+    let left = entries(&["Docs", "Docs/a", "same", "same/x", "gone", "gone/deep"]);
+    let right = entries(&["docs", "docs/a", "same", "same/x", "same/extra", "new"]);
+
+    assert_eq!(
+        find_structure_differences(&left, &right),
+        StructureDifferences {
+            left_only: vec![path::PathBuf::from("gone")],
+            right_only: vec![
+                path::PathBuf::from("new"),
+                path::PathBuf::from("same/extra")
+            ],
+            case_only: vec![(path::PathBuf::from("Docs"), path::PathBuf::from("docs"))],
+        }
+    );
+}
+
+// Walks both sides again, without reading any files, and prints every
+// difference in their layout. Returns whether there were any.
+fn report_structure_differences(args: &Args, walk_options: &WalkOptions) -> bool {
+    let entries = |roots: &[OsString]| {
+        let (files, dirs) = walk_side(roots, walk_options);
+        files.into_keys().chain(dirs).collect::<BTreeSet<_>>()
+    };
+    let differences = find_structure_differences(&entries(&args.left), &entries(&args.right));
+
+    for entry in &differences.left_only {
+        println!("<= only on left: '{}'", entry.display());
+    }
+    for entry in &differences.right_only {
+        println!("=> only on right: '{}'", entry.display());
+    }
+    for (lentry, rentry) in &differences.case_only {
+        println!(
+            "<=> case differs: '{}' '{}'",
+            lentry.display(),
+            rentry.display()
+        );
+    }
+
+    !differences.is_empty()
+}
+
+// Lists the files and directories under a side's roots by their relative
+// paths, without reading any files.
+fn walk_side(
+    roots: &[OsString],
+    walk_options: &WalkOptions,
+) -> (BTreeMap<path::PathBuf, u64>, BTreeSet<path::PathBuf>) {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for root in roots {
+        list_root(path::Path::new(root), walk_options, &mut files, &mut dirs);
+    }
+    (
+        files.into_iter().map(|f| (f.path, f.size)).collect(),
+        dirs.into_iter().collect(),
+    )
+}
+
+fn list_side(
+    roots: &[OsString],
+    listings: &[path::PathBuf],
+    walk_options: &WalkOptions,
+) -> io::Result<BTreeMap<path::PathBuf, u64>> {
+    let mut files = Vec::new();
+
+    for root in roots {
+        list_root(
+            path::Path::new(root),
+            walk_options,
+            &mut files,
+            &mut Vec::new(),
+        );
+    }
+
+    for listing in listings {
+        let file = fs::File::open(listing).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("unable to open '{}': {}", listing.display(), e),
+            )
+        })?;
+
+        let listed = parse_listing(io::BufReader::new(file))?;
+        if listed.is_empty() {
+            eprintln!(
+                "WARN: no files found in listing '{}'. Is it the output of `find -ls` or `dir /s`?",
+                listing.display()
+            );
+        }
+        files.extend(listed);
+    }
+
+    Ok(files.into_iter().map(|f| (f.path, f.size)).collect())
+}
+
+// A directory, or a symlink that may lead to one, still to be listed.
+struct ListedDir {
+    path: path::PathBuf,
+    ancestors: Vec<DirId>,
+    ignores: IgnoreFiles,
+    depth: usize,
+}
+
+// Walks a root collecting sizes without reading any file contents. This is
+// cheap enough compared to hashing that it's done on the main thread.
+// Symlinks and excluded entries are handled like the scanner handles them.
+// The directories below the root that were walked into are collected too,
+// relative to it.
+fn list_root(
+    root: &path::Path,
+    walk_options: &WalkOptions,
+    files: &mut Vec<ListedFile>,
+    walked_dirs: &mut Vec<path::PathBuf>,
+) {
+    let mut dirs: Vec<ListedDir> = vec![ListedDir {
+        path: root.to_path_buf(),
+        ancestors: Vec::new(),
+        ignores: IgnoreFiles::default(),
+        depth: 0,
+    }];
+
+    while let Some(ListedDir {
+        path: dir,
+        mut ancestors,
+        ignores,
+        depth,
+    }) = dirs.pop()
+    {
+        let metadata = match fs::symlink_metadata(&dir) {
+            Err(e) => {
+                eprintln!("WARN: unable to list '{}': {}", dir.display(), e);
+                continue;
+            }
+            Ok(metadata) => metadata,
+        };
+
+        let metadata = if !metadata.is_symlink() {
+            metadata
+        } else if walk_options.follow_symlinks {
+            match fs::metadata(&dir) {
+                Err(e) => {
+                    eprintln!("WARN: unable to list '{}': {}", dir.display(), e);
+                    continue;
+                }
+                Ok(metadata) => metadata,
+            }
+        } else {
+            eprintln!(
+                "WARN: Symlinks are not supported. Ignoring: '{}'",
+                dir.display()
+            );
+            continue;
+        };
+
+        let is_included = |path: &path::Path| {
+            walk_options.include.is_empty() || walk_options.include.matching(path).is_some()
+        };
+
+        if metadata.is_file() {
+            if dir != root && !is_included(&dir) {
+                continue;
+            }
+
+            // Only a root or a symlink can be a file here. Either way it's
+            // relative to its root like everywhere else, and a root is
+            // relative to its parent.
+            let path = match dir.strip_prefix(root) {
+                Ok(rel_path) if !rel_path.as_os_str().is_empty() => rel_path.to_path_buf(),
+                _ => dir
+                    .file_name()
+                    .map(path::PathBuf::from)
+                    .unwrap_or_else(|| dir.clone()),
+            };
+            files.push(ListedFile {
+                path,
+                size: metadata.len(),
+            });
+            continue;
+        }
+
+        if walk_options.follow_symlinks {
+            match dir_id(&dir) {
+                Err(e) => {
+                    eprintln!("WARN: unable to list '{}': {}", dir.display(), e);
+                    continue;
+                }
+                Ok(id) if ancestors.contains(&id) => {
+                    eprintln!(
+                        "WARN: Symlink loop: '{}' is one of its own parents. Ignoring.",
+                        dir.display()
+                    );
+                    continue;
+                }
+                Ok(id) => ancestors.push(id),
+            }
+        }
+
+        if walk_options
+            .max_depth
+            .is_some_and(|max_depth| depth >= max_depth.get())
+        {
+            continue;
+        }
+
+        if let Ok(rel_path) = dir.strip_prefix(root) {
+            if !rel_path.as_os_str().is_empty() {
+                walked_dirs.push(rel_path.to_path_buf());
+            }
+        }
+
+        let ignores = if walk_options.respect_gitignore {
+            ignores.entered(&dir)
+        } else {
+            ignores
+        };
+
+        let read_dir = match fs::read_dir(&dir) {
+            Err(e) => {
+                eprintln!("WARN: unable to list '{}': {}", dir.display(), e);
+                continue;
+            }
+            Ok(read_dir) => read_dir,
+        };
+
+        for entry in read_dir {
+            let (entry_path, file_type) = match entry.and_then(|e| Ok((e.path(), e.file_type()?))) {
+                Err(e) => {
+                    eprintln!("WARN: unable to list '{}': {}", dir.display(), e);
+                    continue;
+                }
+                Ok(entry) => entry,
+            };
+
+            if walk_options.exclude.matching(&entry_path).is_some()
+                || walk_options.excluded_paths.contains_key(&entry_path)
+                || walk_options.outputs.contains_key(&entry_path)
+            {
+                continue;
+            }
+
+            if walk_options
+                .paths
+                .as_ref()
+                .is_some_and(|paths| !paths.allows(&entry_path))
+            {
+                continue;
+            }
+
+            if walk_options.respect_gitignore
+                && ignores.matching(&entry_path, file_type.is_dir()).is_some()
+            {
+                continue;
+            }
+
+            if file_type.is_dir() || file_type.is_symlink() {
+                dirs.push(ListedDir {
+                    path: entry_path,
+                    ancestors: ancestors.clone(),
+                    ignores: ignores.clone(),
+                    depth: depth + 1,
+                });
+            } else if file_type.is_file() && is_included(&entry_path) {
+                match entry_path.metadata() {
+                    Err(e) => {
+                        eprintln!("WARN: unable to list '{}': {}", entry_path.display(), e)
+                    }
+                    Ok(metadata) => files.push(ListedFile {
+                        path: entry_path
+                            .strip_prefix(root)
+                            .unwrap_or(&entry_path)
+                            .to_path_buf(),
+                        size: metadata.len(),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+// Parses the output of either `find -ls` or `dir /s`, detected line by
+// line. Paths are made relative to the first directory in the listing,
+// which is the directory that the listing was started from. Dates are
+// skipped over, not parsed.
+fn parse_listing<R: BufRead>(reader: R) -> io::Result<Vec<ListedFile>> {
+    let mut files = Vec::new();
+    let mut root: Option<path::PathBuf> = None;
+    let mut dir_s_current_dir: Option<path::PathBuf> = None;
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(entry) = parse_find_ls_line(line) {
+            let path = split_listed_path(entry.path, '/');
+            let root = root.get_or_insert_with(|| {
+                if entry.is_dir {
+                    path.clone()
+                } else {
+                    split_listed_path(parent_of_listed_path(entry.path, '/'), '/')
+                }
+            });
+
+            if entry.is_file {
+                files.push(ListedFile {
+                    path: path.strip_prefix(&root).unwrap_or(&path).to_path_buf(),
+                    size: entry.size,
+                });
+            }
+        } else if let Some(dir) = parse_dir_s_header(line) {
+            let dir = split_listed_path(dir, '\\');
+            root.get_or_insert_with(|| dir.clone());
+            dir_s_current_dir = Some(dir);
+        } else if let (Some(dir), Some(entry)) = (&dir_s_current_dir, parse_dir_s_line(line)) {
+            if entry.is_file {
+                let dir = root
+                    .as_deref()
+                    .and_then(|root| dir.strip_prefix(root).ok())
+                    .unwrap_or(dir);
+                let mut path = dir.to_path_buf();
+                path.push(entry.path);
+                files.push(ListedFile {
+                    path,
+                    size: entry.size,
+                });
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+struct ListingEntry<'a> {
+    path: &'a str,
+    size: u64,
+    is_file: bool,
+    is_dir: bool,
+}
+
+// Splits off the next whitespace-separated field, returning it and the
+// rest of the line with leading whitespace removed.
+fn next_field(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if s.is_empty() {
+        return None;
+    }
+
+    match s.find(char::is_whitespace) {
+        Some(end) => Some((&s[..end], s[end..].trim_start())),
+        None => Some((s, "")),
+    }
+}
+
+// Turns a path from a listing into a relative PathBuf with the components
+// the listing had, no matter what the separator on this platform is.
+fn split_listed_path(path: &str, separator: char) -> path::PathBuf {
+    path.split(separator)
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect()
+}
+
+fn parent_of_listed_path(path: &str, separator: char) -> &str {
+    path.rfind(separator).map(|i| &path[..i]).unwrap_or("")
+}
+
+// `find -ls` lines look like:
+//
+//     1234567      4 -rw-r--r--   1 user     group        1234 Jan  5 12:34 ./some/file
+//
+// Only files and directories are returned. Devices list "major, minor" in
+// place of the size, which would throw off the fields after it, and
+// symlinks, pipes and sockets have no contents to compare.
+fn parse_find_ls_line(line: &str) -> Option<ListingEntry<'_>> {
+    let (inode, rest) = next_field(line)?;
+    let (blocks, rest) = next_field(rest)?;
+    let (mode, rest) = next_field(rest)?;
+
+    if !inode.bytes().all(|b| b.is_ascii_digit())
+        || !blocks.bytes().all(|b| b.is_ascii_digit())
+        || mode.len() < 10
+        || !"-d".contains(&mode[..1])
+    {
+        return None;
+    }
+
+    let (_links, rest) = next_field(rest)?;
+    let (_owner, rest) = next_field(rest)?;
+    let (_group, rest) = next_field(rest)?;
+    let (size, rest) = next_field(rest)?;
+    let (_month, rest) = next_field(rest)?;
+    let (_day, rest) = next_field(rest)?;
+    let (_time_or_year, path) = next_field(rest)?;
+
+    Some(ListingEntry {
+        path,
+        size: size.parse().unwrap_or(0),
+        is_file: mode.starts_with('-'),
+        is_dir: mode.starts_with('d'),
+    })
+}
+
+// `dir /s` starts each directory with a header line like:
+//
+//      Directory of C:\Users\someone
+//
+// The text before the path is localized, so only the path is looked for.
+fn parse_dir_s_header(line: &str) -> Option<&str> {
+    if line.trim_start().starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let bytes = line.as_bytes();
+    let start = (0..bytes.len().saturating_sub(2)).find(|&i| {
+        (bytes[i].is_ascii_alphabetic() && bytes[i + 1] == b':' && bytes[i + 2] == b'\\')
+            || (bytes[i] == b'\\' && bytes[i + 1] == b'\\')
+    })?;
+
+    Some(line[start..].trim_end_matches('\\'))
+}
+
+// `dir /s` entries look like one of:
+//
+//     01/05/2024  12:34 PM             1,234 some file.txt
+//     05.01.2024  12:34    <DIR>          some dir
+fn parse_dir_s_line(line: &str) -> Option<ListingEntry<'_>> {
+    let (date, mut rest) = next_field(line)?;
+    if !date.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (time, after_time) = next_field(rest)?;
+    if !time.contains(':') {
+        return None;
+    }
+    rest = after_time;
+
+    // Skip over an AM/PM marker, which may or may not be present, to get
+    // to either the size or the entry type.
+    loop {
+        let (field, after_field) = next_field(rest)?;
+
+        if field.starts_with('<') && field.ends_with('>') {
+            return Some(ListingEntry {
+                path: after_field,
+                size: 0,
+                is_file: false,
+                is_dir: field == "<DIR>",
+            });
+        }
+
+        let digits: String = field.chars().filter(|c| c.is_ascii_digit()).collect();
+        if !digits.is_empty()
+            && field
+                .chars()
+                .all(|c| c.is_ascii_digit() || ",.'\u{a0}".contains(c))
+        {
+            return Some(ListingEntry {
+                path: after_field,
+                size: digits.parse().ok()?,
+                is_file: true,
+                is_dir: false,
+            });
+        }
+
+        rest = after_field;
+    }
+}
+
+#[test]
+fn parse_listing_find_ls() {
+    let listing = "\
+  1000      4 drwxr-xr-x   3 user     group        4096 Jan  5 12:34 ./root
+  1001      4 -rw-r--r--   1 user     group          12 Jan  5 12:34 ./root/a file.txt
+  1002      0 lrwxrwxrwx   1 user     group           5 Jan  5 12:34 ./root/link -> a file.txt
+  1003      4 drwxr-xr-x   2 user     group        4096 Jan  5  2019 ./root/sub
+  1004      4 -rw-r--r--   1 user     group         345 Jan  5  2019 ./root/sub/b
+  1005      0 crw-rw-rw-   1 root     root       1,   3 Jan  5 12:34 ./root/null
+  1006      4 drwxr-xr-x   2 user     group        4096 Jan  5 12:34 ./rootless
+  1007      4 -rw-r--r--   1 user     group          67 Jan  5 12:34 ./rootless/c
+";
+
+    let files = parse_listing(listing.as_bytes()).unwrap();
+
+    assert_eq!(
+        files,
+        vec![
+            ListedFile {
+                path: "a file.txt".into(),
+                size: 12
+            },
+            ListedFile {
+                path: ["sub", "b"].iter().collect(),
+                size: 345
+            },
+            ListedFile {
+                path: ["rootless", "c"].iter().collect(),
+                size: 67
+            },
+        ]
+    );
+}
+
+#[test]
+fn parse_listing_dir_s() {
+    let listing = "\
+ Volume in drive C is OS
+ Volume Serial Number is 1234-ABCD
+
+ Directory of C:\\root
+
+01/05/2024  12:34 PM    <DIR>          .
+01/05/2024  12:34 PM    <DIR>          sub
+01/05/2024  12:34 PM             1,234 a file.txt
+               1 File(s)          1,234 bytes
+
+ Directory of C:\\root\\sub
+
+05.01.2024  12:34                  345 b
+               1 File(s)            345 bytes
+";
+
+    let files = parse_listing(listing.as_bytes()).unwrap();
+
+    assert_eq!(
+        files,
+        vec![
+            ListedFile {
+                path: "a file.txt".into(),
+                size: 1234
+            },
+            ListedFile {
+                path: ["sub", "b"].iter().collect(),
+                size: 345
+            },
+        ]
+    );
 }
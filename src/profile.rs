@@ -0,0 +1,215 @@
+// Copyright 2024, G. Christopher Warrington <code@cw.codes>
+//
+// find-dups is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License Version 3 as
+// published by the Free Software Foundation.
+//
+// find-dups is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public
+// License for more details.
+//
+// A copy of the GNU Affero General Public License Version 3 is included in
+// the file LICENSE in the root of the repository.
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Records how long each part of a run takes, and what each worker thread
+// spends its time on, so that slow runs can be diagnosed after the fact.
+// It's written in the Chrome trace event format, which chrome://tracing,
+// Perfetto and speedscope can all open.
+
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time;
+
+// The rows of the trace before the worker threads' own.
+const MAIN_THREAD: u32 = 0;
+const WALK_ROW: u32 = 1;
+const HASH_ROW: u32 = 2;
+
+pub struct Profile {
+    start: time::Instant,
+    next_thread: AtomicU32,
+    spans: Mutex<Vec<Span>>,
+    // The phase the main thread is in, and when it started.
+    phase: Mutex<Option<(&'static str, time::Instant)>>,
+}
+
+struct Span {
+    name: &'static str,
+    thread: u32,
+    start: time::Duration,
+    duration: time::Duration,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            start: time::Instant::now(),
+            next_thread: AtomicU32::new(HASH_ROW + 1),
+            spans: Mutex::default(),
+            phase: Mutex::default(),
+        }
+    }
+}
+
+impl Profile {
+    // Ends the main thread's current phase, if any, and starts the next.
+    pub fn phase(&self, name: &'static str) {
+        let now = time::Instant::now();
+        if let Some((ended, started)) = self.phase.lock().unwrap().replace((name, now)) {
+            self.record_between(MAIN_THREAD, ended, started, now);
+        }
+    }
+
+    // Numbers a worker thread, to record what it does under.
+    pub fn worker(&self) -> u32 {
+        self.next_thread.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Records something a worker thread did, from when it started until
+    // now. Directories are "walk" and files are "hash".
+    pub fn record(&self, thread: u32, name: &'static str, started: time::Instant) {
+        self.record_between(thread, name, started, time::Instant::now());
+    }
+
+    fn record_between(
+        &self,
+        thread: u32,
+        name: &'static str,
+        started: time::Instant,
+        ended: time::Instant,
+    ) {
+        let start = started.saturating_duration_since(self.start);
+        self.spans.lock().unwrap().push(Span {
+            name,
+            thread,
+            start,
+            duration: ended.saturating_duration_since(started),
+        });
+    }
+
+    // Writes the trace, ending the main thread's current phase. The walk
+    // and hash phases overlap, so each gets a row of its own, spanning
+    // from the first directory or file to the last. Each worker's row is
+    // named with how busy it was over the whole scan.
+    pub fn write<W: io::Write>(&self, mut out: W) -> io::Result<()> {
+        if let Some((ended, started)) = self.phase.lock().unwrap().take() {
+            self.record_between(MAIN_THREAD, ended, started, time::Instant::now());
+        }
+
+        let spans = self.spans.lock().unwrap();
+        let extent = |spans: &mut dyn Iterator<Item = &Span>| {
+            spans.fold(None, |extent, span| {
+                let end = span.start + span.duration;
+                match extent {
+                    None => Some((span.start, end)),
+                    Some((first, last)) => Some((span.start.min(first), end.max(last))),
+                }
+            })
+        };
+
+        let workers = spans.iter().filter(|span| span.thread > HASH_ROW);
+        let mut phases = Vec::new();
+        for (row, name) in [(WALK_ROW, "walk"), (HASH_ROW, "hash")] {
+            if let Some((start, end)) = extent(&mut workers.clone().filter(|s| s.name == name)) {
+                phases.push(Span {
+                    name,
+                    thread: row,
+                    start,
+                    duration: end - start,
+                });
+            }
+        }
+
+        let scan = extent(&mut workers.clone())
+            .map_or(time::Duration::ZERO, |(start, end)| end - start)
+            .as_secs_f64();
+        let mut busy: Vec<time::Duration> = Vec::new();
+        for span in workers {
+            let index = (span.thread - HASH_ROW - 1) as usize;
+            if busy.len() <= index {
+                busy.resize(index + 1, time::Duration::ZERO);
+            }
+            busy[index] += span.duration;
+        }
+
+        let thread_name = |thread: u32, name: String| {
+            serde_json::json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": thread,
+                "args": { "name": name },
+            })
+        };
+        let mut events = vec![
+            thread_name(MAIN_THREAD, "main".to_string()),
+            thread_name(WALK_ROW, "walk phase".to_string()),
+            thread_name(HASH_ROW, "hash phase".to_string()),
+        ];
+        for (index, busy) in busy.iter().enumerate() {
+            let utilization = if scan > 0.0 {
+                busy.as_secs_f64() / scan * 100.0
+            } else {
+                0.0
+            };
+            events.push(thread_name(
+                HASH_ROW + 1 + index as u32,
+                format!("worker {} ({:.0}% busy)", index + 1, utilization),
+            ));
+        }
+        for span in phases.iter().chain(spans.iter()) {
+            events.push(serde_json::json!({
+                "name": span.name,
+                "ph": "X",
+                "pid": 1,
+                "tid": span.thread,
+                "ts": span.start.as_micros() as u64,
+                "dur": span.duration.as_micros() as u64,
+            }));
+        }
+
+        serde_json::to_writer(
+            &mut out,
+            &serde_json::json!({
+                "traceEvents": events,
+                "displayTimeUnit": "ms",
+            }),
+        )?;
+        out.flush()
+    }
+}
+
+#[test]
+fn profile_is_a_chrome_trace() {
+    let profile = Profile::default();
+    profile.phase("scan");
+    let worker = profile.worker();
+    let started = time::Instant::now();
+    profile.record(worker, "walk", started);
+    profile.record(worker, "hash", started);
+    profile.phase("output");
+
+    let mut written = Vec::new();
+    profile.write(&mut written).unwrap();
+    let trace: serde_json::Value = serde_json::from_slice(&written).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+
+    let spans = |tid: u32| -> Vec<&str> {
+        events
+            .iter()
+            .filter(|event| event["ph"] == "X" && event["tid"] == tid)
+            .map(|event| event["name"].as_str().unwrap())
+            .collect()
+    };
+    assert_eq!(spans(MAIN_THREAD), ["scan", "output"]);
+    assert_eq!(spans(WALK_ROW), ["walk"]);
+    assert_eq!(spans(HASH_ROW), ["hash"]);
+    assert_eq!(spans(worker), ["walk", "hash"]);
+    assert!(events
+        .iter()
+        .any(|event| event["tid"] == worker && event["ph"] == "M"));
+}
@@ -0,0 +1,1996 @@
+// Copyright 2024, G. Christopher Warrington <code@cw.codes>
+//
+// find-dups is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License Version 3 as
+// published by the Free Software Foundation.
+//
+// find-dups is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public
+// License for more details.
+//
+// A copy of the GNU Affero General Public License Version 3 is included in
+// the file LICENSE in the root of the repository.
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::hasher::{
+    hash_contents, hash_partial_contents, hash_structured_contents, is_partially_hashed,
+    HashOptions, Sha256Sum, MAX_STRUCTURED_FILE_LEN,
+};
+use crate::profile::Profile;
+#[cfg(test)]
+use crate::test_dir::TempDir;
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::{HashMap, HashSet};
+use std::convert::AsRef;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::panic;
+use std::path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time;
+
+#[derive(Clone)]
+pub struct ReaderLimit {
+    pub root: Option<path::PathBuf>,
+    pub readers: NonZeroUsize,
+}
+
+// How directories are walked.
+#[derive(Clone, Default)]
+pub struct WalkOptions {
+    // Follow symbolic links to files and directories instead of reporting
+    // them as errors. Links to a directory that is already being walked
+    // are reported as loops instead of being followed.
+    pub follow_symlinks: bool,
+    // Counts the files found, when set.
+    pub progress: Option<Arc<ScanProgress>>,
+    // Files and directories to leave out, along with everything in them.
+    pub exclude: Patterns,
+    // Files and directories below the roots of one side to leave out,
+    // along with everything in them, with the flag that named each. They're
+    // never below the other side's.
+    pub excluded_paths: HashMap<path::PathBuf, &'static str>,
+    // This run's own output files that are below a root, at the paths the
+    // walk finds them at, with the flag that named each. They'd change
+    // while they were being hashed.
+    pub outputs: HashMap<path::PathBuf, &'static str>,
+    // When not empty, the only files to hash. Every directory is still
+    // walked to find them. Files given as roots are always hashed.
+    pub include: Patterns,
+    // Leave out what .gitignore and .ignore files in the roots, and the
+    // directories below them, ignore, along with VCS metadata.
+    pub respect_gitignore: bool,
+    // When set, the only files and directories to compare.
+    pub paths: Option<Arc<PathList>>,
+    // How many levels of directories below each root to walk into, when
+    // set. Files directly in a root are at depth 1.
+    pub max_depth: Option<NonZeroUsize>,
+    // Pauses or cancels the scan, when set.
+    pub control: Option<Arc<ScanControl>>,
+    // Slows reading down while files keep failing to be read, when set.
+    pub throttle: Option<Arc<Throttle>>,
+    // Records how long each directory and file takes, when set.
+    pub profile: Option<Arc<Profile>>,
+    // Roots that are pipes or character devices, which are read to the end
+    // and hashed as a single file.
+    pub streams: HashSet<path::PathBuf>,
+    // Collects what the walk leaves out on purpose.
+    pub skipped: Arc<Skipped>,
+}
+
+// Glob patterns, like "*.tmp" or "node_modules", that are matched against
+// both the file name and the whole path of each entry, so that patterns
+// for names and for paths can be mixed.
+#[derive(Clone, Default)]
+pub struct Patterns {
+    globs: Vec<String>,
+    set: GlobSet,
+}
+
+impl Patterns {
+    pub fn new(globs: &[String]) -> Result<Patterns, String> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(Glob::new(glob).map_err(|e| e.to_string())?);
+        }
+
+        Ok(Patterns {
+            globs: globs.to_vec(),
+            set: builder.build().map_err(|e| e.to_string())?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.globs.is_empty()
+    }
+
+    // The first pattern that matches the path, if any.
+    pub fn matching(&self, path: &path::Path) -> Option<&str> {
+        let mut matches = self.set.matches(path);
+        if let Some(file_name) = path.file_name() {
+            matches.extend(self.set.matches(file_name));
+        }
+        matches.into_iter().min().map(|i| self.globs[i].as_str())
+    }
+}
+
+#[test]
+fn patterns_match_names_and_paths() {
+    let patterns = Patterns::new(&[
+        "node_modules".to_string(),
+        "*.tmp".to_string(),
+        "**/cache/*.bin".to_string(),
+    ])
+    .unwrap();
+
+    let matching = |path: &str| patterns.matching(path::Path::new(path));
+    assert_eq!(matching("src/node_modules"), Some("node_modules"));
+    assert_eq!(matching("a/b.tmp"), Some("*.tmp"));
+    assert_eq!(matching("/home/me/cache/x.bin"), Some("**/cache/*.bin"));
+    assert_eq!(matching("a/node_modules.txt"), None);
+    assert_eq!(matching("a/x.bin"), None);
+
+    assert!(Patterns::new(&["[".to_string()]).is_err());
+}
+
+// The only paths to compare, given relative to every root. Everything
+// below a listed directory is compared too. Only the directories that lead
+// to a listed path are walked.
+#[derive(Default)]
+pub struct PathList {
+    paths: HashSet<path::PathBuf>,
+    parents: HashSet<path::PathBuf>,
+}
+
+impl PathList {
+    pub fn new(roots: &[OsString], relative: &[path::PathBuf]) -> PathList {
+        let mut list = PathList::default();
+        for root in roots {
+            for rel in relative {
+                let full = path::Path::new(root).join(rel);
+                for parent in full.ancestors().skip(1) {
+                    list.parents.insert(parent.to_path_buf());
+                }
+                list.paths.insert(full);
+            }
+        }
+        list
+    }
+
+    // Whether the path is listed, is below a listed directory, or is a
+    // directory that leads to one.
+    pub fn allows(&self, path: &path::Path) -> bool {
+        self.parents.contains(path) || path.ancestors().any(|p| self.paths.contains(p))
+    }
+}
+
+#[test]
+fn path_list_allows_listed_paths_and_their_parents() {
+    let list = PathList::new(
+        &["left".into(), "right".into()],
+        &["docs/a.txt".into(), "src".into()],
+    );
+
+    let allows = |path: &str| list.allows(path::Path::new(path));
+    assert!(allows("left/docs"));
+    assert!(allows("right/docs/a.txt"));
+    assert!(allows("left/src/deep/file.rs"));
+    assert!(!allows("left/docs/b.txt"));
+    assert!(!allows("left/other"));
+    assert!(!allows("elsewhere/src"));
+}
+
+// The ignore files that apply to a directory: the .gitignore and .ignore
+// files in it and in every directory above it, up to its root, the way
+// git and ripgrep use them. Files closer to an entry take precedence.
+#[derive(Clone, Default)]
+pub struct IgnoreFiles {
+    matchers: Vec<Arc<Gitignore>>,
+}
+
+// The names of the files read by --respect-gitignore, in order of
+// increasing precedence.
+const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+
+impl IgnoreFiles {
+    // The ignore files that apply to a directory inside this one.
+    pub fn entered(&self, dir: &path::Path) -> IgnoreFiles {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in IGNORE_FILE_NAMES {
+            let file = dir.join(name);
+            if !file.is_file() {
+                continue;
+            }
+            found = true;
+            if let Some(e) = builder.add(&file) {
+                eprintln!("WARN: problem in '{}': {}", file.display(), e);
+            }
+        }
+
+        if !found {
+            return self.clone();
+        }
+
+        match builder.build() {
+            Err(e) => {
+                eprintln!(
+                    "WARN: unable to use ignore files in '{}': {}",
+                    dir.display(),
+                    e
+                );
+                self.clone()
+            }
+            Ok(matcher) => {
+                let mut matchers = self.matchers.clone();
+                matchers.push(Arc::new(matcher));
+                IgnoreFiles { matchers }
+            }
+        }
+    }
+
+    // The pattern that ignores the path, if any. VCS metadata directories
+    // are always ignored.
+    pub fn matching(&self, path: &path::Path, is_dir: bool) -> Option<String> {
+        if is_dir && path.file_name().is_some_and(|name| name == ".git") {
+            return Some(".git".to_string());
+        }
+
+        for matcher in self.matchers.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(glob) => return Some(glob.original().to_string()),
+                Match::Whitelist(_) => return None,
+            }
+        }
+
+        None
+    }
+}
+
+#[test]
+fn ignore_files_nest() {
+    let root = TempDir::new("ignore");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join(".gitignore"), "target/\n*.log\n").unwrap();
+    fs::write(root.join("sub/.gitignore"), "!keep.log\n").unwrap();
+
+    let top = IgnoreFiles::default().entered(&root);
+    let sub = top.entered(&root.join("sub"));
+
+    assert_eq!(
+        top.matching(&root.join("target"), true),
+        Some("target/".to_string())
+    );
+    assert_eq!(top.matching(&root.join("target"), false), None);
+    assert_eq!(
+        top.matching(&root.join(".git"), true),
+        Some(".git".to_string())
+    );
+    assert_eq!(
+        sub.matching(&root.join("sub/a.log"), false),
+        Some("*.log".to_string())
+    );
+    assert_eq!(sub.matching(&root.join("sub/keep.log"), false), None);
+    assert_eq!(sub.matching(&root.join("sub/a.txt"), false), None);
+}
+
+// The entries a walk left out on purpose, rather than because they
+// couldn't be read, with the filter that left each of them out. Reporting
+// them lets it be checked that the filters didn't leave out more than was
+// intended.
+#[derive(Default)]
+pub struct Skipped {
+    entries: Mutex<Vec<(String, PathLocation)>>,
+}
+
+impl Skipped {
+    fn add(&self, filter: &str, path: PathLocation) {
+        self.entries
+            .lock()
+            .unwrap()
+            .push((filter.to_string(), path));
+    }
+
+    // Returns the entries skipped so far, sorted by path, and forgets them.
+    pub fn take(&self) -> Vec<(String, PathLocation)> {
+        let mut entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        entries.sort_by(|(_, lhs), (_, rhs)| lhs.path().cmp(rhs.path()));
+        entries
+    }
+}
+
+// Filter that leaves out symlinks unless they're followed.
+const SYMLINK_FILTER: &str = "symlinks (see --follow-symlinks)";
+// Filter that leaves out files that don't match any --include pattern.
+const INCLUDE_FILTER: &str = "not matching --include";
+// Filter that leaves out what isn't in the --paths-file list.
+const PATHS_FILTER: &str = "not in --paths-file";
+// Filter that leaves out directories whose contents are too deep.
+const DEPTH_FILTER: &str = "below --max-depth";
+
+// The number of files found so far by a walk, and their total size, so
+// that how far hashing has got can be reported. Files that are reported
+// as errors instead of being hashed count as empty files. The walk only
+// counts the files it finds, so files added to the results from elsewhere
+// have to be counted by whatever adds them. Counting the ones that have
+// been hashed, or that failed to be, is up to whatever receives the results.
+#[derive(Default)]
+pub struct ScanProgress {
+    pub files: AtomicU64,
+    pub bytes: AtomicU64,
+    pub hashed_files: AtomicU64,
+    pub hashed_bytes: AtomicU64,
+}
+
+impl ScanProgress {
+    pub fn add_file(&self, size: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn add_hashed(&self, size: u64) {
+        self.hashed_files.fetch_add(1, Ordering::Relaxed);
+        self.hashed_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+// What a scan has been told to do from outside.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ControlState {
+    #[default]
+    Running,
+    Paused,
+    Cancelled,
+}
+
+// Lets a scan be paused, resumed, or cancelled from another thread. Worker
+// threads check it before each directory or file, so a pause takes effect
+// once the files being hashed are done. Once cancelled, the rest of the
+// work is dropped and the scan ends early.
+#[derive(Default)]
+pub struct ScanControl {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+impl ScanControl {
+    pub fn state(&self) -> ControlState {
+        *self.state.lock().unwrap()
+    }
+
+    // Changes the state, unless the scan has already been cancelled.
+    pub fn set(&self, new_state: ControlState) {
+        let mut state = self.state.lock().unwrap();
+        if *state != ControlState::Cancelled {
+            *state = new_state;
+            self.changed.notify_all();
+        }
+    }
+
+    // Blocks while the scan is paused. Returns whether to go on with the
+    // next piece of work.
+    fn wait_until_running(&self) -> bool {
+        let state = self
+            .changed
+            .wait_while(self.state.lock().unwrap(), |state| {
+                *state == ControlState::Paused
+            })
+            .unwrap();
+        *state == ControlState::Running
+    }
+}
+
+#[test]
+fn scan_control_stays_cancelled() {
+    let control = Arc::new(ScanControl::default());
+    control.set(ControlState::Paused);
+
+    let waiter = {
+        let control = Arc::clone(&control);
+        thread::spawn(move || control.wait_until_running())
+    };
+    control.set(ControlState::Running);
+    assert!(waiter.join().unwrap());
+
+    control.set(ControlState::Cancelled);
+    control.set(ControlState::Running);
+    assert_eq!(control.state(), ControlState::Cancelled);
+    assert!(!control.wait_until_running());
+}
+
+// Slows reading down while most files fail to be read, as happens when a
+// disk is failing or a network share keeps dropping, rather than hammering
+// the device with every worker thread. After a window of mostly failures,
+// half as many files are read at a time, down to one, and then reads are
+// spaced out more and more. A window without failures undoes a step.
+// Files that are missing or can't be accessed don't count, as they say
+// nothing about the device.
+#[derive(Default)]
+pub struct Throttle {
+    state: Mutex<ThrottleState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct ThrottleState {
+    // How many files may be read at a time, or None for no limit.
+    readers: Option<usize>,
+    reading: usize,
+    // The most files that have been read at a time.
+    peak: usize,
+    delay: time::Duration,
+    window_files: usize,
+    window_errors: usize,
+}
+
+// How many files are read between decisions to slow down or speed up.
+const THROTTLE_WINDOW: usize = 20;
+// How long to wait between reads once only one file is read at a time and
+// reads still fail, before doubling, and how long the wait can get.
+const THROTTLE_MIN_DELAY: time::Duration = time::Duration::from_millis(100);
+const THROTTLE_MAX_DELAY: time::Duration = time::Duration::from_secs(5);
+
+impl Throttle {
+    // Blocks until another file may be read.
+    fn acquire(&self) {
+        let mut state = self
+            .changed
+            .wait_while(self.state.lock().unwrap(), |state| {
+                state
+                    .readers
+                    .is_some_and(|readers| state.reading >= readers)
+            })
+            .unwrap();
+        state.reading += 1;
+        state.peak = state.peak.max(state.reading);
+        let delay = state.delay;
+        drop(state);
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    fn release(&self, result: &io::Result<HashedFile>) {
+        let failed = result.as_ref().is_err_and(|e| {
+            !matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+            )
+        });
+
+        let mut state = self.state.lock().unwrap();
+        state.reading -= 1;
+        let (files, errors) = (
+            state.window_files + 1,
+            state.window_errors + failed as usize,
+        );
+        if state.record(failed) {
+            match state.readers {
+                None => eprintln!(
+                    "WARN: {} of the last {} files failed to be read. Reading at full speed again.",
+                    errors, files
+                ),
+                Some(readers) => eprintln!(
+                    "WARN: {} of the last {} files failed to be read. Now reading {} at a time, {}ms apart.",
+                    errors,
+                    files,
+                    readers,
+                    state.delay.as_millis()
+                ),
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+impl ThrottleState {
+    // Counts a file that was read and, at the end of each window, decides
+    // whether to slow down or speed up. Returns whether it did.
+    fn record(&mut self, failed: bool) -> bool {
+        self.window_files += 1;
+        self.window_errors += failed as usize;
+        if self.window_files < THROTTLE_WINDOW {
+            return false;
+        }
+
+        let (files, errors) = (self.window_files, self.window_errors);
+        self.window_files = 0;
+        self.window_errors = 0;
+
+        if errors * 2 >= files {
+            match self.readers {
+                None => self.readers = Some((self.peak / 2).max(1)),
+                Some(readers) if readers > 1 => self.readers = Some(readers / 2),
+                Some(_) if self.delay < THROTTLE_MAX_DELAY => {
+                    self.delay = (self.delay * 2).clamp(THROTTLE_MIN_DELAY, THROTTLE_MAX_DELAY)
+                }
+                Some(_) => return false,
+            }
+        } else if errors == 0 && !self.delay.is_zero() {
+            self.delay /= 2;
+            if self.delay < THROTTLE_MIN_DELAY {
+                self.delay = time::Duration::ZERO;
+            }
+        } else if let (0, Some(readers)) = (errors, self.readers) {
+            self.readers = Some(readers * 2).filter(|readers| *readers < self.peak);
+        } else {
+            return false;
+        }
+
+        true
+    }
+}
+
+#[test]
+fn throttle_backs_off_and_recovers() {
+    let mut state = ThrottleState {
+        peak: 8,
+        ..ThrottleState::default()
+    };
+    let mut window = |failed: bool| {
+        for _ in 0..THROTTLE_WINDOW {
+            state.record(failed);
+        }
+        (state.readers, state.delay)
+    };
+
+    assert_eq!(window(true), (Some(4), time::Duration::ZERO));
+    assert_eq!(window(true), (Some(2), time::Duration::ZERO));
+    assert_eq!(window(true), (Some(1), time::Duration::ZERO));
+    assert_eq!(window(true), (Some(1), THROTTLE_MIN_DELAY));
+    assert_eq!(window(true), (Some(1), THROTTLE_MIN_DELAY * 2));
+
+    assert_eq!(window(false), (Some(1), THROTTLE_MIN_DELAY));
+    assert_eq!(window(false), (Some(1), time::Duration::ZERO));
+    assert_eq!(window(false), (Some(2), time::Duration::ZERO));
+    assert_eq!(window(false), (Some(4), time::Duration::ZERO));
+    assert_eq!(window(false), (None, time::Duration::ZERO));
+    assert_eq!(window(false), (None, time::Duration::ZERO));
+}
+
+// Identifies a directory whatever path it was reached by.
+#[cfg(unix)]
+pub type DirId = (u64, u64);
+#[cfg(not(unix))]
+pub type DirId = path::PathBuf;
+
+#[cfg(unix)]
+pub fn dir_id(path: &path::Path) -> io::Result<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+// std has no stable way to get a file's index on other platforms, so the
+// directory's canonical path stands in for it.
+#[cfg(not(unix))]
+pub fn dir_id(path: &path::Path) -> io::Result<DirId> {
+    fs::canonicalize(path)
+}
+
+// Where a directory is in the walk of its root.
+#[derive(Clone, Default)]
+struct DirContext {
+    // The directories above this one, when following symlinks. Empty
+    // otherwise.
+    ancestors: Vec<DirId>,
+    // The ignore files above this directory, when respecting them. Empty
+    // otherwise.
+    ignores: IgnoreFiles,
+    // How far below its root the directory is. Roots are at 0.
+    depth: usize,
+}
+
+enum Work {
+    Directory {
+        path: PathLocation,
+        context: DirContext,
+        walk_options: Arc<WalkOptions>,
+        work_sender: Sender<Work>,
+        file_sender: Sender<Work>,
+    },
+    File {
+        path: PathLocation,
+    },
+    Stream {
+        path: PathLocation,
+    },
+}
+
+pub struct WorkResult {
+    pub path: PathLocation,
+    pub result: io::Result<HashedFile>,
+    pub is_dir: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct HashedFile {
+    pub hash: Sha256Sum,
+    // Not known for files that were only read from a manifest.
+    pub size: Option<u64>,
+    pub modified: Option<time::SystemTime>,
+    // Set when the file has more than one hard link, so that links to it
+    // can be told apart from copies of it.
+    pub file_id: Option<FileId>,
+}
+
+// Identifies a file whatever link it was reached by: its device and inode.
+pub type FileId = (u64, u64);
+
+#[derive(Clone)]
+pub enum PathLocation {
+    Left(path::PathBuf),
+    Right(path::PathBuf),
+}
+
+pub fn default_num_threads() -> usize {
+    thread::available_parallelism()
+        .unwrap_or(NonZeroUsize::new(2).unwrap())
+        .into()
+}
+
+// Walks and hashes the given roots in two passes. The first only hashes
+// the ends of big files. Only the big files whose partial hash matches a
+// file on the other side are hashed in full by the second, as the rest
+// can't have a copy there. This saves reading most of the data in trees of
+// big files, like videos, that mostly differ. With only a left side, the
+// big files whose partial hash matches another file's on it are hashed in
+// full instead.
+//
+// The results of big files that can't have a copy have their partial hash
+// instead of their full one. hash_options must not already be partial.
+pub fn scan_with_prefilter(
+    left: &[OsString],
+    right: &[OsString],
+    limits: &[ReaderLimit],
+    hash_options: &HashOptions,
+    walk_options: &Arc<WalkOptions>,
+    num_threads: usize,
+) -> Vec<WorkResult> {
+    let partial_options = Arc::new(HashOptions {
+        partial: true,
+        ..hash_options.clone()
+    });
+    let (worker_threads, results_receiver) = start_scan(
+        left,
+        right,
+        limits,
+        &partial_options,
+        walk_options,
+        num_threads,
+    );
+    let mut results: Vec<WorkResult> = results_receiver.iter().collect();
+    join_worker_threads(worker_threads);
+
+    let mut left_counts: HashMap<Sha256Sum, usize> = HashMap::new();
+    let mut right_hashes: HashSet<Sha256Sum> = HashSet::new();
+    for work_result in results.iter() {
+        if let Ok(hashed) = &work_result.result {
+            if hashed.size.is_some_and(is_partially_hashed) {
+                match work_result.path {
+                    PathLocation::Left(_) => *left_counts.entry(hashed.hash).or_default() += 1,
+                    PathLocation::Right(_) => {
+                        right_hashes.insert(hashed.hash);
+                    }
+                };
+            }
+        }
+    }
+
+    let could_have_copy = |hash: &Sha256Sum| {
+        let left_count = left_counts.get(hash).copied().unwrap_or(0);
+        if right.is_empty() {
+            left_count > 1
+        } else {
+            left_count > 0 && right_hashes.contains(hash)
+        }
+    };
+    let candidates: Vec<PathLocation> = results
+        .iter()
+        .filter(|work_result| match &work_result.result {
+            Ok(hashed) => {
+                hashed.size.is_some_and(is_partially_hashed) && could_have_copy(&hashed.hash)
+            }
+            Err(_) => false,
+        })
+        .map(|work_result| work_result.path.clone())
+        .collect();
+
+    let roots: Vec<OsString> = left.iter().chain(right.iter()).cloned().collect();
+    let (worker_threads, results_receiver) = start_file_scan(
+        candidates,
+        &roots,
+        limits,
+        &Arc::new(hash_options.clone()),
+        num_threads,
+    );
+    let mut full_results: HashMap<path::PathBuf, io::Result<HashedFile>> = results_receiver
+        .iter()
+        .map(|work_result| (work_result.path.path().to_path_buf(), work_result.result))
+        .collect();
+    join_worker_threads(worker_threads);
+
+    for work_result in results.iter_mut() {
+        if let Some(full_result) = full_results.remove(work_result.path.path()) {
+            work_result.result = full_result;
+        }
+    }
+
+    results
+}
+
+#[test]
+fn prefilter_hashes_matching_files_within_one_side_in_full() {
+    let root = TempDir::new("prefilter");
+    let contents = vec![0u8; 300 * 1024];
+    let mut middle_changed = contents.clone();
+    middle_changed[150_000] = 1;
+    fs::write(root.join("a"), &contents).unwrap();
+    fs::write(root.join("b"), &contents).unwrap();
+    fs::write(root.join("c"), &middle_changed).unwrap();
+
+    let results = scan_with_prefilter(
+        &[root.to_path_buf().into()],
+        &[],
+        &[],
+        &HashOptions::default(),
+        &Arc::new(WalkOptions::default()),
+        2,
+    );
+    let hashes: HashMap<path::PathBuf, Sha256Sum> = results
+        .into_iter()
+        .map(|work_result| {
+            let name = work_result
+                .path
+                .path()
+                .strip_prefix(&*root)
+                .unwrap()
+                .to_path_buf();
+            (name, work_result.result.unwrap().hash)
+        })
+        .collect();
+
+    assert_eq!(hashes[path::Path::new("a")], hashes[path::Path::new("b")]);
+    assert_ne!(hashes[path::Path::new("a")], hashes[path::Path::new("c")]);
+}
+
+// Hashes the given files. Files are read by the reader pool of the closest
+// root they're under, like they would be by start_scan.
+pub fn start_file_scan(
+    files: Vec<PathLocation>,
+    roots: &[OsString],
+    limits: &[ReaderLimit],
+    hash_options: &Arc<HashOptions>,
+    num_threads: usize,
+) -> (Vec<JoinHandle<()>>, Receiver<WorkResult>) {
+    let (work_sender, work_receiver) = unbounded();
+    let (results_sender, results_receiver) = unbounded();
+
+    let hard_links = Arc::new(HardLinks::default());
+    let walk_options = WalkOptions::default();
+    let mut reader_pools = ReaderPools {
+        limits,
+        hash_options,
+        hard_links: &hard_links,
+        walk_options: &walk_options,
+        work_sender: &work_sender,
+        results_sender: &results_sender,
+        threads: Vec::new(),
+    };
+
+    let mut file_senders: HashMap<&path::Path, Sender<Work>> = HashMap::new();
+    for path in files {
+        let root = roots
+            .iter()
+            .map(path::Path::new)
+            .filter(|root| path.path().starts_with(root))
+            .max_by_key(|root| root.components().count());
+
+        let file_sender = match root {
+            None => work_sender.clone(),
+            Some(root) => file_senders
+                .entry(root)
+                .or_insert_with(|| reader_pools.file_sender(root))
+                .clone(),
+        };
+        file_sender
+            .send(Work::File { path })
+            .expect("Unable to enqueue File work into work channel");
+    }
+
+    let mut worker_threads = reader_pools.threads;
+
+    // Every file has been enqueued, so dropping the senders lets the
+    // threads exit once they've processed them.
+    drop(file_senders);
+    drop(work_sender);
+
+    worker_threads.extend(start_worker_threads(
+        num_threads,
+        work_receiver,
+        results_sender,
+        hash_options,
+        &hard_links,
+        &walk_options,
+    ));
+
+    (worker_threads, results_receiver)
+}
+
+// Starts walking and hashing the given roots. Results are sent to the
+// returned receiver as they're ready. It's closed once everything has been
+// processed, after which the threads can be joined.
+pub fn start_scan(
+    left: &[OsString],
+    right: &[OsString],
+    limits: &[ReaderLimit],
+    hash_options: &Arc<HashOptions>,
+    walk_options: &Arc<WalkOptions>,
+    num_threads: usize,
+) -> (Vec<JoinHandle<()>>, Receiver<WorkResult>) {
+    let (work_sender, work_receiver) = unbounded();
+    let (results_sender, results_receiver) = unbounded();
+
+    let hard_links = Arc::new(HardLinks::default());
+    let mut reader_pools = ReaderPools {
+        limits,
+        hash_options,
+        hard_links: &hard_links,
+        walk_options,
+        work_sender: &work_sender,
+        results_sender: &results_sender,
+        threads: Vec::new(),
+    };
+
+    enqueue_initial_work_from_args(left, right, walk_options, &work_sender, &mut reader_pools);
+
+    let mut worker_threads = reader_pools.threads;
+
+    // Initial work has been enqueued. Any Directory work has its own clone
+    // of work_sender that is can use to enqueue more work.
+    //
+    // Drop this copy of the sender so that all the copies are dropped when
+    // directory enumeration is complete.
+    drop(work_sender);
+
+    worker_threads.extend(start_worker_threads(
+        num_threads,
+        work_receiver,
+        results_sender,
+        hash_options,
+        &hard_links,
+        walk_options,
+    ));
+
+    (worker_threads, results_receiver)
+}
+
+pub fn join_worker_threads(worker_threads: Vec<JoinHandle<()>>) {
+    for worker_thread in worker_threads {
+        if let Err(e) = worker_thread.join() {
+            panic::resume_unwind(e);
+        }
+    }
+}
+
+fn enqueue_initial_work_from_args(
+    left: &[OsString],
+    right: &[OsString],
+    walk_options: &Arc<WalkOptions>,
+    work_sender: &Sender<Work>,
+    reader_pools: &mut ReaderPools,
+) {
+    enqueue_initial_work_for_side(
+        left.iter(),
+        |path: &path::Path| -> PathLocation { PathLocation::new_left(path) },
+        walk_options,
+        work_sender,
+        reader_pools,
+    );
+    enqueue_initial_work_for_side(
+        right.iter(),
+        |path: &path::Path| -> PathLocation { PathLocation::new_right(path) },
+        walk_options,
+        work_sender,
+        reader_pools,
+    );
+}
+
+fn enqueue_initial_work_for_side<'a, I, F>(
+    arg_paths: I,
+    path_location_factory: F,
+    walk_options: &Arc<WalkOptions>,
+    work_sender: &Sender<Work>,
+    reader_pools: &mut ReaderPools,
+) where
+    I: IntoIterator<Item = &'a OsString>,
+    F: Fn(&path::Path) -> PathLocation,
+{
+    for arg_path in arg_paths.into_iter() {
+        let path = path::Path::new(&arg_path);
+
+        // Streams like /dev/stdin are often symlinks, and are named
+        // explicitly, so they're always followed.
+        if path.is_symlink()
+            && !walk_options.follow_symlinks
+            && !walk_options.streams.contains(path)
+        {
+            eprintln!(
+                "WARN: Symlinks are not supported: '{}'",
+                &arg_path.to_string_lossy()
+            );
+            continue;
+        }
+
+        let metadata = match path.metadata() {
+            Err(e) => {
+                eprintln!(
+                    "WARN: unable to process path on command line: '{}': {}",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+            Ok(metadata) => metadata,
+        };
+
+        let file_sender = reader_pools.file_sender(path);
+
+        if walk_options.streams.contains(path) {
+            if let Some(progress) = &walk_options.progress {
+                progress.add_file(0);
+            }
+            file_sender
+                .send(Work::Stream {
+                    path: path_location_factory(path),
+                })
+                .expect("Unable to enqueue initial Stream work into work channel");
+        } else if metadata.is_dir() {
+            let work = Work::Directory {
+                path: path_location_factory(path),
+                context: DirContext::default(),
+                walk_options: Arc::clone(walk_options),
+                work_sender: work_sender.clone(),
+                file_sender,
+            };
+            work_sender
+                .send(work)
+                .expect("Unable to enqueue initial Directory work into work channel");
+        } else if !metadata.is_file() {
+            // Pipes and devices are only read when named with 'fifo:'.
+            eprintln!(
+                "WARN: Only files and directories are supported: '{}'",
+                path.display()
+            );
+        } else {
+            if let Some(progress) = &walk_options.progress {
+                progress.add_file(metadata.len());
+            }
+            let work = Work::File {
+                path: path_location_factory(path),
+            };
+            file_sender
+                .send(work)
+                .expect("Unable to enqueue initial File work into work channel");
+        }
+    }
+}
+
+// Checks that each root can be scanned, returning a message for each one
+// that can't, saying what to do about it. Scanning skips such roots with a
+// warning, which is easy to miss and leaves the comparison incomplete, so
+// this lets them be reported together before any work is done.
+pub fn validate_roots(roots: &[OsString], walk_options: &WalkOptions) -> Vec<String> {
+    roots
+        .iter()
+        .filter_map(|root| {
+            let path = path::Path::new(root);
+            validate_root(path, walk_options)
+                .err()
+                .map(|problem| format!("'{}' {}", path.display(), problem))
+        })
+        .collect()
+}
+
+fn validate_root(path: &path::Path, walk_options: &WalkOptions) -> Result<(), String> {
+    // Opening a pipe blocks until something writes to it, so streams are
+    // only checked to be what they were said to be.
+    if walk_options.streams.contains(path) {
+        return match fs::metadata(path) {
+            Err(e) => Err(format!("cannot be accessed: {}", e)),
+            Ok(metadata) if is_stream(&metadata) => Ok(()),
+            Ok(_) => Err(
+                "is not a pipe or character device, so it can't be read with 'fifo:'.".to_string(),
+            ),
+        };
+    }
+
+    let metadata = fs::symlink_metadata(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => "does not exist. Check the spelling of the path.".to_string(),
+        io::ErrorKind::PermissionDenied => {
+            "cannot be accessed. Check the permissions of the directories above it.".to_string()
+        }
+        _ => format!("cannot be accessed: {}", e),
+    })?;
+
+    let metadata = if !metadata.is_symlink() {
+        metadata
+    } else if walk_options.follow_symlinks {
+        fs::metadata(path)
+            .map_err(|e| format!("is a symbolic link that can't be followed: {}", e))?
+    } else {
+        return Err(match fs::canonicalize(path) {
+            Ok(target) => format!(
+                "is a symbolic link, which is only followed with --follow-symlinks. Use the path it points to instead: '{}'",
+                target.display()
+            ),
+            Err(_) => "is a symbolic link, which is only followed with --follow-symlinks."
+                .to_string(),
+        });
+    };
+
+    let readable = if metadata.is_dir() {
+        fs::read_dir(path).map(|_| ())
+    } else if metadata.is_file() {
+        fs::File::open(path).map(|_| ())
+    } else {
+        return Err(
+            "is not a file or directory. To read a pipe or character device as a single file, prefix it with 'fifo:'."
+                .to_string(),
+        );
+    };
+
+    readable.map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            "cannot be read. Check its permissions or re-run with elevated privileges.".to_string()
+        }
+        _ => format!("cannot be read: {}", e),
+    })
+}
+
+#[test]
+fn validate_roots_reports_every_problem() {
+    let manifest_dir = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+    let roots: Vec<OsString> = vec![
+        manifest_dir.join("src").into(),
+        manifest_dir.join("Cargo.toml").into(),
+        manifest_dir.join("does-not-exist-1").into(),
+        manifest_dir.join("does-not-exist-2").into(),
+    ];
+
+    let problems = validate_roots(&roots, &WalkOptions::default());
+
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].contains("does-not-exist-1"));
+    assert!(problems[0].ends_with("does not exist. Check the spelling of the path."));
+    assert!(problems[1].contains("does-not-exist-2"));
+}
+
+// Files under a root with a reader limit are sent to a queue of their own
+// that is serviced by only that many threads. This keeps a slow root from
+// tying up the shared worker threads, which would otherwise all end up
+// waiting on it. Files under every other root are sent to the shared work
+// queue.
+struct ReaderPools<'a> {
+    limits: &'a [ReaderLimit],
+    hash_options: &'a Arc<HashOptions>,
+    hard_links: &'a Arc<HardLinks>,
+    // Only the control, throttle and profile apply to reading files.
+    walk_options: &'a WalkOptions,
+    work_sender: &'a Sender<Work>,
+    results_sender: &'a Sender<WorkResult>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ReaderPools<'_> {
+    fn file_sender(&mut self, root: &path::Path) -> Sender<Work> {
+        let readers = self
+            .limits
+            .iter()
+            .rev()
+            .find(|limit| limit.root.as_deref() == Some(root))
+            .or_else(|| self.limits.iter().rev().find(|limit| limit.root.is_none()));
+
+        match readers {
+            None => self.work_sender.clone(),
+            Some(limit) => {
+                // No copy of the sender is kept here, so the pool's
+                // threads exit once all the work for this root has been
+                // enqueued and processed.
+                let (file_sender, file_receiver) = unbounded();
+                self.threads.extend(start_worker_threads(
+                    limit.readers.into(),
+                    file_receiver,
+                    self.results_sender.clone(),
+                    self.hash_options,
+                    self.hard_links,
+                    self.walk_options,
+                ));
+                file_sender
+            }
+        }
+    }
+}
+
+fn start_worker_threads(
+    num_threads: usize,
+    work_receiver: Receiver<Work>,
+    results_sender: Sender<WorkResult>,
+    hash_options: &Arc<HashOptions>,
+    hard_links: &Arc<HardLinks>,
+    walk_options: &WalkOptions,
+) -> Vec<JoinHandle<()>> {
+    let mut results = Vec::with_capacity(num_threads);
+
+    for _ in 0..num_threads {
+        let thread_work_receiver = work_receiver.clone();
+        let thread_results_sender = results_sender.clone();
+        let thread_hash_options = Arc::clone(hash_options);
+        let thread_hard_links = Arc::clone(hard_links);
+        let thread_control = walk_options.control.clone();
+        let thread_throttle = walk_options.throttle.clone();
+        let thread_profile = walk_options.profile.clone().map(|profile| {
+            let worker = profile.worker();
+            (profile, worker)
+        });
+
+        results.push(thread::spawn(move || {
+            for work in thread_work_receiver.iter() {
+                if let Some(control) = &thread_control {
+                    if !control.wait_until_running() {
+                        continue;
+                    }
+                }
+
+                let started = time::Instant::now();
+                let kind = match work {
+                    Work::Directory { .. } => "walk",
+                    Work::File { .. } | Work::Stream { .. } => "hash",
+                };
+
+                match work {
+                    Work::Directory {
+                        path,
+                        context,
+                        walk_options,
+                        work_sender,
+                        file_sender,
+                    } => handle_dir_work(
+                        path,
+                        context,
+                        &walk_options,
+                        &work_sender,
+                        &file_sender,
+                        &thread_results_sender,
+                    ),
+                    Work::File { path } => handle_file_work(
+                        path,
+                        &thread_hash_options,
+                        &thread_hard_links,
+                        &thread_throttle,
+                        &thread_results_sender,
+                    ),
+                    Work::Stream { path } => {
+                        let r = hash_stream(path, &thread_hash_options);
+                        thread_results_sender
+                            .send(r)
+                            .expect("Unable to enqueue result into result channel");
+                    }
+                };
+
+                if let Some((profile, worker)) = &thread_profile {
+                    profile.record(*worker, kind, started);
+                }
+            }
+        }));
+    }
+
+    results
+}
+
+fn handle_dir_work(
+    path: PathLocation,
+    context: DirContext,
+    walk_options: &Arc<WalkOptions>,
+    work_sender: &Sender<Work>,
+    file_sender: &Sender<Work>,
+    results_sender: &Sender<WorkResult>,
+) {
+    let DirContext {
+        mut ancestors,
+        ignores,
+        depth,
+    } = context;
+
+    if walk_options.follow_symlinks {
+        // A directory that is one of its own ancestors was reached through
+        // a symlink, and walking it would never end.
+        let id = match dir_id(path.path()) {
+            Ok(id) if ancestors.contains(&id) => Err(io::Error::other(
+                "Symlink loop: this directory is one of its own parents. Ignoring.",
+            )),
+            result => result,
+        };
+        match id {
+            Err(e) => {
+                let r = WorkResult::from_dir_err(path, e);
+                results_sender
+                    .send(r)
+                    .expect("Unable to enqueue result into result channel");
+                return;
+            }
+            Ok(id) => ancestors.push(id),
+        }
+    }
+
+    let ignores = if walk_options.respect_gitignore {
+        ignores.entered(path.path())
+    } else {
+        ignores
+    };
+
+    let read_dir = match fs::read_dir(path.path()) {
+        Err(e) => {
+            let r = WorkResult::from_dir_err(path, e);
+            results_sender
+                .send(r)
+                .expect("Unable to enqueue result into result channel");
+            return;
+        }
+        Ok(read_dir) => read_dir,
+    };
+
+    for entry in read_dir {
+        let entry = match entry {
+            Err(e) => {
+                let r = WorkResult::from_dir_err(path.clone(), e);
+                results_sender
+                    .send(r)
+                    .expect("Unable to enqueue result into result channel");
+                continue;
+            }
+            Ok(entry) => entry,
+        };
+
+        let entry_path = entry.path();
+        if let Some(glob) = walk_options.exclude.matching(&entry_path) {
+            walk_options.skipped.add(
+                &format!("--exclude '{}'", glob),
+                PathLocation::new_same_side(&path, &entry_path),
+            );
+            continue;
+        }
+
+        if let Some(flag) = walk_options.excluded_paths.get(&entry_path) {
+            walk_options
+                .skipped
+                .add(flag, PathLocation::new_same_side(&path, &entry_path));
+            continue;
+        }
+
+        if let Some(flag) = walk_options.outputs.get(&entry_path) {
+            walk_options
+                .skipped
+                .add(flag, PathLocation::new_same_side(&path, &entry_path));
+            continue;
+        }
+
+        if let Some(paths) = &walk_options.paths {
+            if !paths.allows(&entry_path) {
+                walk_options.skipped.add(
+                    PATHS_FILTER,
+                    PathLocation::new_same_side(&path, &entry_path),
+                );
+                continue;
+            }
+        }
+
+        if walk_options.respect_gitignore {
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            if let Some(glob) = ignores.matching(&entry_path, is_dir) {
+                walk_options.skipped.add(
+                    &format!("--respect-gitignore '{}'", glob),
+                    PathLocation::new_same_side(&path, &entry_path),
+                );
+                continue;
+            }
+        }
+
+        if !walk_options.follow_symlinks && entry_path.is_symlink() {
+            walk_options.skipped.add(
+                SYMLINK_FILTER,
+                PathLocation::new_same_side(&path, &entry_path),
+            );
+            continue;
+        }
+
+        // This follows symlinks, so broken ones end up here.
+        let metadata = match fs::metadata(&entry_path) {
+            Err(e) => {
+                let r = WorkResult::from_err(PathLocation::new_same_side(&path, &entry_path), e);
+                if let Some(progress) = &walk_options.progress {
+                    progress.add_file(0);
+                }
+                results_sender
+                    .send(r)
+                    .expect("Unable to enqueue result into result channel");
+                continue;
+            }
+            Ok(metadata) => metadata,
+        };
+
+        if metadata.is_dir() {
+            if walk_options
+                .max_depth
+                .is_some_and(|max_depth| depth + 1 >= max_depth.get())
+            {
+                walk_options.skipped.add(
+                    DEPTH_FILTER,
+                    PathLocation::new_same_side(&path, &entry_path),
+                );
+                continue;
+            }
+
+            let w = Work::Directory {
+                path: PathLocation::new_same_side(&path, &entry_path),
+                context: DirContext {
+                    ancestors: ancestors.clone(),
+                    ignores: ignores.clone(),
+                    depth: depth + 1,
+                },
+                walk_options: Arc::clone(walk_options),
+                work_sender: work_sender.clone(),
+                file_sender: file_sender.clone(),
+            };
+            work_sender
+                .send(w)
+                .expect("Unable to enqueue Directory into work channel");
+        } else if !metadata.is_file() {
+            // Devices, pipes and sockets can't be hashed like files, and
+            // reading one could block or never end.
+            eprintln!(
+                "WARN: Only files and directories are supported: '{}'",
+                entry_path.display()
+            );
+        } else {
+            if !walk_options.include.is_empty()
+                && walk_options.include.matching(&entry_path).is_none()
+            {
+                walk_options.skipped.add(
+                    INCLUDE_FILTER,
+                    PathLocation::new_same_side(&path, &entry_path),
+                );
+                continue;
+            }
+            if let Some(progress) = &walk_options.progress {
+                progress.add_file(metadata.len());
+            }
+            let w = Work::File {
+                path: PathLocation::new_same_side(&path, &entry_path),
+            };
+            file_sender
+                .send(w)
+                .expect("Unable to enqueue File into work channel");
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_symlinks_stops_at_loops() {
+    let root = TempDir::new("loop");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub/file"), b"contents").unwrap();
+    std::os::unix::fs::symlink("..", root.join("sub/up")).unwrap();
+    std::os::unix::fs::symlink("sub", root.join("linked")).unwrap();
+
+    let (worker_threads, results_receiver) = start_scan(
+        &[root.to_path_buf().into()],
+        &[],
+        &[],
+        &Arc::new(HashOptions::default()),
+        &Arc::new(WalkOptions {
+            follow_symlinks: true,
+            ..WalkOptions::default()
+        }),
+        2,
+    );
+    let results: Vec<WorkResult> = results_receiver.iter().collect();
+    join_worker_threads(worker_threads);
+
+    let mut hashed: Vec<&path::Path> = results
+        .iter()
+        .filter(|work_result| work_result.result.is_ok())
+        .map(|work_result| work_result.path.path())
+        .collect();
+    hashed.sort();
+    assert_eq!(
+        hashed,
+        vec![root.join("linked/file"), root.join("sub/file")]
+    );
+
+    let loops = results
+        .iter()
+        .filter(|work_result| work_result.result.is_err() && work_result.is_dir)
+        .count();
+    assert_eq!(loops, 2);
+}
+
+#[test]
+fn include_only_hashes_matching_files() {
+    let root = TempDir::new("include");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    for name in ["a.jpg", "b.txt", "sub/c.jpg", "sub/d.raw"] {
+        fs::write(root.join(name), name).unwrap();
+    }
+
+    let walk_options = Arc::new(WalkOptions {
+        include: Patterns::new(&["*.jpg".to_string()]).unwrap(),
+        ..WalkOptions::default()
+    });
+    let (worker_threads, results_receiver) = start_scan(
+        &[root.to_path_buf().into()],
+        &[],
+        &[],
+        &Arc::new(HashOptions::default()),
+        &walk_options,
+        2,
+    );
+    let results: Vec<WorkResult> = results_receiver.iter().collect();
+    join_worker_threads(worker_threads);
+
+    let mut hashed: Vec<&path::Path> = results
+        .iter()
+        .map(|work_result| work_result.path.path())
+        .collect();
+    hashed.sort();
+    assert_eq!(hashed, vec![root.join("a.jpg"), root.join("sub/c.jpg")]);
+
+    let skipped: Vec<(String, path::PathBuf)> = walk_options
+        .skipped
+        .take()
+        .into_iter()
+        .map(|(filter, path)| (filter, path.path().to_path_buf()))
+        .collect();
+    assert_eq!(
+        skipped,
+        vec![
+            (INCLUDE_FILTER.to_string(), root.join("b.txt")),
+            (INCLUDE_FILTER.to_string(), root.join("sub/d.raw")),
+        ]
+    );
+}
+
+#[test]
+fn excluded_paths_are_skipped_under_the_flag_that_named_them() {
+    let root = TempDir::new("excluded");
+    fs::create_dir_all(root.join("trash")).unwrap();
+    fs::write(root.join("a"), b"a").unwrap();
+    fs::write(root.join("trash/b"), b"b").unwrap();
+
+    // With --swap, a --left-exclude path is below a right-hand root.
+    let walk_options = Arc::new(WalkOptions {
+        excluded_paths: HashMap::from([(root.join("trash"), "--left-exclude")]),
+        ..WalkOptions::default()
+    });
+    let (worker_threads, results_receiver) = start_scan(
+        &[],
+        &[root.to_path_buf().into()],
+        &[],
+        &Arc::new(HashOptions::default()),
+        &walk_options,
+        2,
+    );
+    let results: Vec<WorkResult> = results_receiver.iter().collect();
+    join_worker_threads(worker_threads);
+
+    let paths: Vec<&path::Path> = results
+        .iter()
+        .map(|work_result| work_result.path.path())
+        .collect();
+    assert_eq!(paths, vec![root.join("a")]);
+    let skipped: Vec<(String, path::PathBuf)> = walk_options
+        .skipped
+        .take()
+        .into_iter()
+        .map(|(filter, path)| (filter, path.path().to_path_buf()))
+        .collect();
+    assert_eq!(
+        skipped,
+        vec![("--left-exclude".to_string(), root.join("trash"))]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_symlinks_skips_devices() {
+    let root = TempDir::new("device");
+    fs::write(root.join("file"), b"contents").unwrap();
+    std::os::unix::fs::symlink("/dev/null", root.join("null")).unwrap();
+
+    let (worker_threads, results_receiver) = start_scan(
+        &[root.to_path_buf().into()],
+        &[],
+        &[],
+        &Arc::new(HashOptions::default()),
+        &Arc::new(WalkOptions {
+            follow_symlinks: true,
+            ..WalkOptions::default()
+        }),
+        2,
+    );
+    let results: Vec<WorkResult> = results_receiver.iter().collect();
+    join_worker_threads(worker_threads);
+
+    let paths: Vec<&path::Path> = results
+        .iter()
+        .map(|work_result| work_result.path.path())
+        .collect();
+    assert_eq!(paths, vec![root.join("file")]);
+}
+
+#[cfg(unix)]
+#[test]
+fn pipes_and_sockets_are_skipped_unless_named_as_streams() {
+    let root = TempDir::new("special");
+    fs::create_dir_all(root.join("tree")).unwrap();
+    fs::write(root.join("tree/file"), b"contents").unwrap();
+    let _socket = std::os::unix::net::UnixListener::bind(root.join("tree/socket")).unwrap();
+    let fifo = root.join("tree/fifo");
+    let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+    // SAFETY: c_path is a NUL-terminated string that outlives the call.
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+    let (worker_threads, results_receiver) = start_scan(
+        &[root.join("tree").into()],
+        &[fifo.clone().into()],
+        &[],
+        &Arc::new(HashOptions::default()),
+        &Arc::new(WalkOptions::default()),
+        2,
+    );
+    let results: Vec<WorkResult> = results_receiver.iter().collect();
+    join_worker_threads(worker_threads);
+
+    let paths: Vec<&path::Path> = results
+        .iter()
+        .map(|work_result| work_result.path.path())
+        .collect();
+    assert_eq!(paths, vec![root.join("tree/file")]);
+}
+
+fn handle_file_work(
+    path: PathLocation,
+    hash_options: &HashOptions,
+    hard_links: &HardLinks,
+    throttle: &Option<Arc<Throttle>>,
+    results_sender: &Sender<WorkResult>,
+) {
+    if let Some(throttle) = throttle {
+        throttle.acquire();
+    }
+    let r = fingerprint_one_file(path, hash_options, hard_links);
+    if let Some(throttle) = throttle {
+        throttle.release(&r.result);
+    }
+
+    results_sender
+        .send(r)
+        .expect("Unable to enqueue result into result channel");
+}
+
+fn fingerprint_one_file(
+    path: PathLocation,
+    hash_options: &HashOptions,
+    hard_links: &HardLinks,
+) -> WorkResult {
+    let file = match fs::File::open(path.path()) {
+        Err(e) => return WorkResult::from_err(path, e),
+        Ok(f) => f,
+    };
+
+    let metadata = match file.metadata() {
+        Err(e) => return WorkResult::from_err(path, e),
+        Ok(metadata) => metadata,
+    };
+
+    let structured_format = hash_options
+        .structured_formats
+        .iter()
+        .find(|format| format.matches(path.path()))
+        .filter(|_| metadata.len() <= MAX_STRUCTURED_FILE_LEN);
+
+    let hash_file = |file: fs::File| match structured_format {
+        _ if hash_options.partial && is_partially_hashed(metadata.len()) => {
+            hash_partial_contents(file, metadata.len())
+        }
+        Some(format) => hash_structured_contents(file, *format, hash_options),
+        None if metadata.len() > READ_AHEAD_BUFFER_LEN as u64 => {
+            hash_contents(ReadAhead::new(file), hash_options)
+        }
+        None => hash_contents(file, hash_options),
+    };
+
+    let file_id = linked_file_id(&metadata);
+    let hash = match file_id {
+        // Links with different extensions could be normalized differently,
+        // so only links hashed as they are share a hash.
+        Some(file_id) if structured_format.is_none() => {
+            hard_links.hash_once(file_id, || hash_file(file))
+        }
+        _ => hash_file(file),
+    };
+
+    match hash {
+        Err(e) => WorkResult::from_err(path, e),
+        Ok(hash) => WorkResult::from_hashed(
+            path,
+            HashedFile {
+                hash,
+                size: Some(metadata.len()),
+                modified: metadata.modified().ok(),
+                file_id,
+            },
+        ),
+    }
+}
+
+// Hashes everything that can be read from a pipe or character device. Its
+// size is only known once it has all been read, and it has no modification
+// time.
+fn hash_stream(path: PathLocation, hash_options: &HashOptions) -> WorkResult {
+    let mut reader = match fs::File::open(path.path()) {
+        Err(e) => return WorkResult::from_err(path, e),
+        Ok(file) => CountingReader {
+            inner: file,
+            count: 0,
+        },
+    };
+
+    match hash_contents(&mut reader, hash_options) {
+        Err(e) => WorkResult::from_err(path, e),
+        Ok(hash) => WorkResult::from_hashed(
+            path,
+            HashedFile {
+                hash,
+                size: Some(reader.count),
+                modified: None,
+                file_id: None,
+            },
+        ),
+    }
+}
+
+struct CountingReader<R: io::Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: io::Read> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+fn is_stream(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_fifo() || metadata.file_type().is_char_device()
+}
+
+// Elsewhere, anything that isn't a file or directory, like a named pipe on
+// Windows, is taken at its word.
+#[cfg(not(unix))]
+fn is_stream(metadata: &fs::Metadata) -> bool {
+    !metadata.is_file() && !metadata.is_dir()
+}
+
+#[cfg(unix)]
+#[test]
+fn hash_stream_reads_a_fifo_to_the_end() {
+    let dir = TempDir::new("fifo");
+    let fifo = dir.join("fifo");
+    let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+    // SAFETY: c_path is a NUL-terminated string that outlives the call.
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+    assert!(is_stream(&fs::metadata(&fifo).unwrap()));
+
+    let writer = {
+        let fifo = fifo.clone();
+        thread::spawn(move || fs::write(fifo, b"streamed contents").unwrap())
+    };
+    let r = hash_stream(PathLocation::new_left(&fifo), &HashOptions::default());
+    writer.join().unwrap();
+
+    let hashed = r.result.unwrap();
+    assert_eq!(hashed.size, Some(17));
+    assert_eq!(
+        hashed.hash,
+        hash_contents(&b"streamed contents"[..], &HashOptions::default()).unwrap()
+    );
+}
+
+#[cfg(unix)]
+fn linked_file_id(metadata: &fs::Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+// std has no stable way to get a file's link count or index on other
+// platforms, so hard links are hashed and reported like copies there.
+#[cfg(not(unix))]
+fn linked_file_id(_metadata: &fs::Metadata) -> Option<FileId> {
+    None
+}
+
+// The hashes of files with more than one hard link, so that each is only
+// read once however many links to it are found. Each file has a lock of
+// its own, so a worker that finds a link to a file that is being hashed
+// waits for that hash instead of reading the file again.
+#[derive(Default)]
+struct HardLinks {
+    hashes: Mutex<HashMap<FileId, Arc<Mutex<Option<Sha256Sum>>>>>,
+}
+
+impl HardLinks {
+    fn hash_once<F>(&self, file_id: FileId, hash_file: F) -> io::Result<Sha256Sum>
+    where
+        F: FnOnce() -> io::Result<Sha256Sum>,
+    {
+        let slot = Arc::clone(self.hashes.lock().unwrap().entry(file_id).or_default());
+        let mut hash = slot.lock().unwrap();
+        if let Some(hash) = *hash {
+            return Ok(hash);
+        }
+
+        // Failures aren't remembered, so the next link tries again.
+        let result = hash_file();
+        if let Ok(result) = &result {
+            *hash = Some(*result);
+        }
+        result
+    }
+}
+
+#[test]
+fn hard_links_are_hashed_once() {
+    let hard_links = HardLinks::default();
+    let mut reads = 0;
+
+    for _ in 0..3 {
+        let hash = hard_links.hash_once((1, 2), || {
+            reads += 1;
+            Ok([7; 32])
+        });
+        assert_eq!(hash.unwrap(), [7; 32]);
+    }
+    assert_eq!(reads, 1);
+
+    let failed = hard_links.hash_once((1, 3), || Err(io::Error::other("unreadable")));
+    assert!(failed.is_err());
+    assert_eq!(
+        hard_links.hash_once((1, 3), || Ok([8; 32])).unwrap(),
+        [8; 32]
+    );
+}
+
+// Reads on a thread of its own, a buffer ahead of what has been read from
+// it, so that waiting on storage for the next buffer overlaps with hashing
+// the last one. Two buffers are passed back and forth between the threads.
+// Files that fit in one buffer gain nothing from this, so they're read
+// directly.
+struct ReadAhead {
+    filled: Option<Receiver<io::Result<Vec<u8>>>>,
+    empty: Option<Sender<Vec<u8>>>,
+    buffer: Vec<u8>,
+    pos: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+const READ_AHEAD_BUFFER_LEN: usize = 1024 * 1024;
+
+impl ReadAhead {
+    fn new<R: io::Read + Send + 'static>(mut reader: R) -> ReadAhead {
+        let (filled_sender, filled) = bounded(1);
+        let (empty, empty_receiver) = bounded(2);
+        for _ in 0..2 {
+            empty
+                .send(Vec::with_capacity(READ_AHEAD_BUFFER_LEN))
+                .expect("Unable to enqueue buffer into buffer channel");
+        }
+
+        let thread = thread::spawn(move || {
+            // Both channels are closed when the ReadAhead is dropped, which
+            // ends this early if the rest isn't wanted.
+            for mut buffer in empty_receiver.iter() {
+                buffer.clear();
+                let mut chunk = io::Read::take(&mut reader, READ_AHEAD_BUFFER_LEN as u64);
+                let result = io::Read::read_to_end(&mut chunk, &mut buffer).map(|_| buffer);
+                let done = !matches!(&result, Ok(buffer) if !buffer.is_empty());
+                if filled_sender.send(result).is_err() || done {
+                    break;
+                }
+            }
+        });
+
+        ReadAhead {
+            filled: Some(filled),
+            empty: Some(empty),
+            buffer: Vec::new(),
+            pos: 0,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl io::Read for ReadAhead {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() {
+            if let Some(empty) = &self.empty {
+                // The reading thread may have already stopped, in which
+                // case the buffer isn't needed.
+                let _ = empty.send(std::mem::take(&mut self.buffer));
+            }
+            self.pos = 0;
+            // An empty buffer, or a closed channel, is the end of the file.
+            match self.filled.as_ref().map(Receiver::recv) {
+                Some(Ok(Ok(buffer))) => self.buffer = buffer,
+                Some(Ok(Err(e))) => return Err(e),
+                Some(Err(_)) | None => return Ok(0),
+            }
+        }
+
+        let len = out.len().min(self.buffer.len() - self.pos);
+        out[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        self.filled = None;
+        self.empty = None;
+        if let Some(thread) = self.thread.take() {
+            if let Err(e) = thread.join() {
+                panic::resume_unwind(e);
+            }
+        }
+    }
+}
+
+#[test]
+fn read_ahead_reads_everything_in_order() {
+    let contents: Vec<u8> = (0..READ_AHEAD_BUFFER_LEN * 3 + 7)
+        .map(|i| (i % 251) as u8)
+        .collect();
+
+    let mut read = Vec::new();
+    io::Read::read_to_end(
+        &mut ReadAhead::new(io::Cursor::new(contents.clone())),
+        &mut read,
+    )
+    .unwrap();
+    assert_eq!(read, contents);
+
+    // Stopping part way doesn't wait for the rest to be read.
+    let mut partial = [0; 10];
+    io::Read::read_exact(&mut ReadAhead::new(io::Cursor::new(contents)), &mut partial).unwrap();
+    assert_eq!(partial, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+}
+
+impl WorkResult {
+    fn from_err(path: PathLocation, err: io::Error) -> WorkResult {
+        WorkResult {
+            path,
+            result: Err(err),
+            is_dir: false,
+        }
+    }
+
+    fn from_dir_err(path: PathLocation, err: io::Error) -> WorkResult {
+        WorkResult {
+            path,
+            result: Err(err),
+            is_dir: true,
+        }
+    }
+
+    fn from_hashed(path: PathLocation, hashed: HashedFile) -> WorkResult {
+        WorkResult {
+            path,
+            result: Ok(hashed),
+            is_dir: false,
+        }
+    }
+}
+
+impl fmt::Display for WorkResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.result {
+            Ok(hashed) => write!(f, "OK: {} : {}", self.path, hex::encode(hashed.hash)),
+            Err(err) => write!(f, "ERROR: {} : {}", self.path, err),
+        }
+    }
+}
+
+impl FromStr for ReaderLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split on the last '=' so that roots that contain an '=' can
+        // still be given.
+        let (root, readers) = match s.rsplit_once('=') {
+            Some((root, readers)) => (Some(path::PathBuf::from(root)), readers),
+            None => (None, s),
+        };
+
+        let readers = readers
+            .parse::<NonZeroUsize>()
+            .map_err(|e| format!("invalid reader count '{}': {}", readers, e))?;
+
+        Ok(ReaderLimit { root, readers })
+    }
+}
+
+#[test]
+fn reader_limit_parses_root_and_count() {
+    let limit: ReaderLimit = "2".parse().unwrap();
+    assert_eq!(limit.root, None);
+    assert_eq!(limit.readers.get(), 2);
+
+    let limit: ReaderLimit = "/mnt/a=b=1".parse().unwrap();
+    assert_eq!(limit.root, Some(path::PathBuf::from("/mnt/a=b")));
+    assert_eq!(limit.readers.get(), 1);
+
+    assert!("/mnt/usb=0".parse::<ReaderLimit>().is_err());
+}
+
+impl PathLocation {
+    pub fn new_left<P: AsRef<path::Path>>(path: P) -> PathLocation {
+        PathLocation::Left(path.as_ref().to_path_buf())
+    }
+
+    pub fn new_right<P: AsRef<path::Path>>(path: P) -> PathLocation {
+        PathLocation::Right(path.as_ref().to_path_buf())
+    }
+
+    pub fn new_same_side<P: AsRef<path::Path>>(other: &PathLocation, path: P) -> PathLocation {
+        match other {
+            PathLocation::Left(_) => PathLocation::Left(path.as_ref().to_path_buf()),
+            PathLocation::Right(_) => PathLocation::Right(path.as_ref().to_path_buf()),
+        }
+    }
+
+    pub fn path(&self) -> &path::Path {
+        match self {
+            PathLocation::Left(path) => path,
+            PathLocation::Right(path) => path,
+        }
+    }
+}
+
+impl fmt::Display for PathLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathLocation::Left(path) => write!(f, "<= '{}'", path.display()),
+            PathLocation::Right(path) => write!(f, "=> '{}'", path.display()),
+        }
+    }
+}
@@ -0,0 +1,541 @@
+// Copyright 2024, G. Christopher Warrington <code@cw.codes>
+//
+// find-dups is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License Version 3 as
+// published by the Free Software Foundation.
+//
+// find-dups is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public
+// License for more details.
+//
+// A copy of the GNU Affero General Public License Version 3 is included in
+// the file LICENSE in the root of the repository.
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// An interactive browser for groups of duplicates, for when there are too
+// many to read through as text. Files are marked to be deleted or replaced
+// with a symbolic link to the copy that's kept, and nothing is changed
+// until the marks are applied on the way out.
+
+#[cfg(test)]
+use crate::TempDir;
+use crate::{
+    ensure_keeper_unchanged, ensure_unchanged, format_modified, replace_with_symlink, ActionTally,
+    Side, SymlinkStyle,
+};
+use find_dups::scanner::HashedFile;
+use indicatif::BinaryBytes;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListState, Paragraph};
+use ratatui::Frame;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path;
+
+// Files with the same content. The copies on each side are listed left
+// first when comparing two sides.
+pub struct Group {
+    files: Vec<(path::PathBuf, Option<Side>)>,
+}
+
+impl Group {
+    pub fn from_sides(lpaths: &[path::PathBuf], rpaths: &[path::PathBuf]) -> Group {
+        Group {
+            files: lpaths
+                .iter()
+                .map(|path| (path.clone(), Some(Side::Left)))
+                .chain(rpaths.iter().map(|path| (path.clone(), Some(Side::Right))))
+                .collect(),
+        }
+    }
+
+    pub fn within(paths: &[path::PathBuf]) -> Group {
+        Group {
+            files: paths.iter().map(|path| (path.clone(), None)).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mark {
+    Delete,
+    Link,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Groups,
+    Files,
+}
+
+struct Browser<'a> {
+    groups: Vec<Group>,
+    hashed_files: &'a HashMap<path::PathBuf, HashedFile>,
+    marks: HashMap<path::PathBuf, Mark>,
+    group_list: ListState,
+    file_list: ListState,
+    focus: Focus,
+    status: String,
+    confirming: bool,
+}
+
+// A change to make once browsing is done.
+#[derive(Debug, PartialEq)]
+struct Planned {
+    path: path::PathBuf,
+    mark: Mark,
+    keeper: path::PathBuf,
+}
+
+// Lets the groups be browsed and marked, then makes the marked changes,
+// printing each one like --action does. Quitting without applying the
+// marks changes nothing.
+pub fn browse_and_apply(
+    mut groups: Vec<Group>,
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+    symlink_style: SymlinkStyle,
+    dry_run: bool,
+) -> io::Result<()> {
+    if groups.is_empty() {
+        eprintln!("There are no duplicates to browse.");
+        return Ok(());
+    }
+
+    // The groups that waste the most space come first.
+    let size = |group: &Group| {
+        hashed_files
+            .get(&group.files[0].0)
+            .and_then(|h| h.size)
+            .unwrap_or(0)
+    };
+    groups.sort_by_key(|group| std::cmp::Reverse(size(group) * (group.files.len() as u64 - 1)));
+
+    let mut browser = Browser::new(groups, hashed_files);
+    let mut terminal = ratatui::try_init()?;
+    let result = browser.run(&mut terminal);
+    ratatui::try_restore()?;
+
+    match result? {
+        None => eprintln!("Quit without changing anything."),
+        Some(plan) => apply(&plan, hashed_files, symlink_style, dry_run),
+    }
+    Ok(())
+}
+
+impl<'a> Browser<'a> {
+    fn new(groups: Vec<Group>, hashed_files: &'a HashMap<path::PathBuf, HashedFile>) -> Self {
+        Browser {
+            groups,
+            hashed_files,
+            marks: HashMap::new(),
+            group_list: ListState::default().with_selected(Some(0)),
+            file_list: ListState::default().with_selected(Some(0)),
+            focus: Focus::Groups,
+            status: "Enter: files  d: delete  l: link  space: unmark  a: apply  q: quit"
+                .to_string(),
+            confirming: false,
+        }
+    }
+
+    // Returns the changes to make, or None to quit without any.
+    fn run(&mut self, terminal: &mut ratatui::DefaultTerminal) -> io::Result<Option<Vec<Planned>>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+
+            if self.confirming {
+                self.confirming = false;
+                match key.code {
+                    KeyCode::Char('y') => return Ok(Some(self.plan())),
+                    _ => self.status = "Not applied.".to_string(),
+                }
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                KeyCode::Up | KeyCode::Char('k') => self.step(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.step(1),
+                KeyCode::Enter | KeyCode::Right | KeyCode::Tab if self.focus == Focus::Groups => {
+                    self.focus = Focus::Files
+                }
+                KeyCode::Left | KeyCode::BackTab | KeyCode::Tab => self.focus = Focus::Groups,
+                KeyCode::Char('d') => self.mark(Some(Mark::Delete)),
+                KeyCode::Char('l') => self.mark(Some(Mark::Link)),
+                KeyCode::Char(' ') | KeyCode::Char('u') => self.mark(None),
+                KeyCode::Char('a') => {
+                    self.confirming = true;
+                    self.status = format!(
+                        "Apply {} change(s)? y: apply  any other key: keep browsing",
+                        self.marks.len()
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn step(&mut self, by: isize) {
+        let files = self.selected_group().files.len();
+        let (list, len) = match self.focus {
+            Focus::Groups => (&mut self.group_list, self.groups.len()),
+            Focus::Files => (&mut self.file_list, files),
+        };
+        let selected = list.selected().unwrap_or(0).saturating_add_signed(by);
+        list.select(Some(selected.min(len - 1)));
+
+        if self.focus == Focus::Groups {
+            self.file_list.select(Some(0));
+        }
+    }
+
+    fn selected_group(&self) -> &Group {
+        &self.groups[self.group_list.selected().unwrap_or(0)]
+    }
+
+    fn selected_file(&self) -> &path::Path {
+        &self.selected_group().files[self.file_list.selected().unwrap_or(0)].0
+    }
+
+    // Marks the selected file, unless that would leave its group without
+    // a copy to keep.
+    fn mark(&mut self, mark: Option<Mark>) {
+        if self.focus != Focus::Files {
+            self.status = "Press Enter to pick a file in the group first.".to_string();
+            return;
+        }
+
+        let path = self.selected_file().to_path_buf();
+        match mark {
+            None => {
+                self.marks.remove(&path);
+            }
+            Some(mark) => {
+                let unmarked = self
+                    .selected_group()
+                    .files
+                    .iter()
+                    .filter(|(other, _)| *other != path && !self.marks.contains_key(other))
+                    .count();
+                if unmarked == 0 {
+                    self.status = "At least one copy has to be kept.".to_string();
+                    return;
+                }
+                self.marks.insert(path, mark);
+            }
+        }
+        self.status = format!("{} file(s) marked.", self.marks.len());
+    }
+
+    // The first unmarked file of each group is the copy that's kept.
+    fn plan(&self) -> Vec<Planned> {
+        let mut plan = Vec::new();
+        for group in &self.groups {
+            let keeper = match group
+                .files
+                .iter()
+                .find(|(path, _)| !self.marks.contains_key(path))
+            {
+                Some((keeper, _)) => keeper,
+                None => continue,
+            };
+            for (path, _) in &group.files {
+                if let Some(mark) = self.marks.get(path) {
+                    plan.push(Planned {
+                        path: path.clone(),
+                        mark: *mark,
+                        keeper: keeper.clone(),
+                    });
+                }
+            }
+        }
+        plan
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main_area, status_area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [groups_area, group_area] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main_area);
+        let [files_area, details_area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(7)]).areas(group_area);
+
+        let border = |focus: Focus| {
+            if self.focus == focus {
+                Style::new().bold()
+            } else {
+                Style::new()
+            }
+        };
+
+        let groups: Vec<String> = self
+            .groups
+            .iter()
+            .map(|group| {
+                let first = &group.files[0].0;
+                let size = self
+                    .hashed_files
+                    .get(first)
+                    .and_then(|h| h.size)
+                    .unwrap_or(0);
+                format!(
+                    "{} copies, {} each: {}",
+                    group.files.len(),
+                    BinaryBytes(size),
+                    first.file_name().unwrap_or_default().to_string_lossy()
+                )
+            })
+            .collect();
+        let groups = List::new(groups)
+            .block(
+                Block::bordered()
+                    .title(format!(" {} group(s) ", self.groups.len()))
+                    .border_style(border(Focus::Groups)),
+            )
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(groups, groups_area, &mut self.group_list);
+
+        let group = &self.groups[self.group_list.selected().unwrap_or(0)];
+        let keeper = group
+            .files
+            .iter()
+            .find(|(path, _)| !self.marks.contains_key(path))
+            .map(|(path, _)| path);
+        let files: Vec<String> = group
+            .files
+            .iter()
+            .map(|(path, side)| {
+                let mark = match self.marks.get(path) {
+                    Some(Mark::Delete) => "[D]",
+                    Some(Mark::Link) => "[L]",
+                    None if Some(path) == keeper => "[K]",
+                    None => "[ ]",
+                };
+                let side = match side {
+                    Some(Side::Left) => "<= ",
+                    Some(Side::Right) => "=> ",
+                    None => "",
+                };
+                format!("{} {}{}", mark, side, path.display())
+            })
+            .collect();
+        let files = List::new(files)
+            .block(
+                Block::bordered()
+                    .title(" K: kept  D: delete  L: link to kept copy ")
+                    .border_style(border(Focus::Files)),
+            )
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(files, files_area, &mut self.file_list);
+
+        let path = self.selected_file();
+        let hashed = self.hashed_files.get(path);
+        let details = vec![
+            Line::from(format!("Path: {}", path.display())),
+            Line::from(format!(
+                "Size: {} ({} bytes)",
+                BinaryBytes(hashed.and_then(|h| h.size).unwrap_or(0)),
+                hashed.and_then(|h| h.size).unwrap_or(0)
+            )),
+            Line::from(format!(
+                "Modified: {}",
+                format_modified(hashed.and_then(|h| h.modified))
+            )),
+            Line::from(format!(
+                "Hash: {}",
+                hashed.map(|h| hex::encode(h.hash)).unwrap_or_default()
+            )),
+            Line::from(format!(
+                "Hard links: {}",
+                link_count(path).map_or("unknown".to_string(), |n| n.to_string())
+            )),
+        ];
+        frame.render_widget(
+            Paragraph::new(details).block(Block::bordered().title(" File ")),
+            details_area,
+        );
+
+        frame.render_widget(Line::from(self.status.as_str()), status_area);
+    }
+}
+
+#[cfg(unix)]
+fn link_count(path: &path::Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path).ok().map(|m| m.nlink())
+}
+
+#[cfg(not(unix))]
+fn link_count(_path: &path::Path) -> Option<u64> {
+    None
+}
+
+fn apply(
+    plan: &[Planned],
+    hashed_files: &HashMap<path::PathBuf, HashedFile>,
+    symlink_style: SymlinkStyle,
+    dry_run: bool,
+) {
+    let mut deleted = ActionTally::default();
+    let mut linked = ActionTally::default();
+
+    for planned in plan {
+        let hashed = hashed_files.get(&planned.path);
+        let keeper_hashed = hashed_files.get(&planned.keeper);
+        match planned.mark {
+            Mark::Delete => match delete_file(
+                &planned.path,
+                &planned.keeper,
+                hashed,
+                keeper_hashed,
+                dry_run,
+            ) {
+                Ok(()) => {
+                    deleted.add(hashed);
+                    println!("-- '{}'", planned.path.display());
+                }
+                Err(e) => {
+                    deleted.failed += 1;
+                    eprintln!("WARN: not deleting '{}': {}", planned.path.display(), e);
+                }
+            },
+            Mark::Link => match replace_with_symlink(
+                &planned.path,
+                &planned.keeper,
+                symlink_style,
+                hashed,
+                keeper_hashed,
+                dry_run,
+            ) {
+                Ok(target) => {
+                    linked.add(hashed);
+                    println!("~> '{}' -> '{}'", planned.path.display(), target.display());
+                }
+                Err(e) => {
+                    linked.failed += 1;
+                    eprintln!("WARN: not replacing '{}': {}", planned.path.display(), e);
+                }
+            },
+        }
+    }
+
+    deleted.summarize(dry_run, "Deleted", "Would delete", "for good");
+    linked.summarize(dry_run, "Replaced", "Would replace", "with symbolic links");
+}
+
+// Deletes a file, unless it or the copy being kept no longer matches what
+// was hashed. With dry_run, only the checks are made.
+fn delete_file(
+    path: &path::Path,
+    keeper: &path::Path,
+    hashed: Option<&HashedFile>,
+    keeper_hashed: Option<&HashedFile>,
+    dry_run: bool,
+) -> io::Result<()> {
+    ensure_unchanged(path, hashed)?;
+    ensure_keeper_unchanged(keeper, keeper_hashed)?;
+    if dry_run {
+        return Ok(());
+    }
+    fs::remove_file(path)
+}
+
+#[test]
+fn marks_keep_a_copy_of_each_group() {
+    let paths =
+        |names: &[&str]| -> Vec<path::PathBuf> { names.iter().map(path::PathBuf::from).collect() };
+    let hashed_files = HashMap::new();
+    let mut browser = Browser::new(
+        vec![
+            Group::from_sides(&paths(&["l/a"]), &paths(&["r/a", "r/b"])),
+            Group::within(&paths(&["x", "y"])),
+        ],
+        &hashed_files,
+    );
+
+    browser.focus = Focus::Files;
+    browser.mark(Some(Mark::Delete));
+    browser.step(1);
+    browser.mark(Some(Mark::Link));
+    browser.step(1);
+    browser.mark(Some(Mark::Delete));
+    assert_eq!(browser.status, "At least one copy has to be kept.");
+    browser.step(-2);
+    browser.mark(None);
+
+    browser.focus = Focus::Groups;
+    browser.step(1);
+    browser.focus = Focus::Files;
+    browser.step(1);
+    browser.mark(Some(Mark::Delete));
+
+    assert_eq!(
+        browser.plan(),
+        vec![
+            Planned {
+                path: "r/a".into(),
+                mark: Mark::Link,
+                keeper: "l/a".into(),
+            },
+            Planned {
+                path: "y".into(),
+                mark: Mark::Delete,
+                keeper: "x".into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn files_are_only_deleted_while_the_keeper_is_unchanged() {
+    let dir = TempDir::new("tui-delete");
+    let keeper = dir.join("keeper");
+    let copy = dir.join("copy");
+    fs::write(&keeper, b"contents").unwrap();
+    fs::write(&copy, b"contents").unwrap();
+    let hashed = |path: &path::Path| {
+        let metadata = fs::metadata(path).unwrap();
+        HashedFile {
+            hash: [0; 32],
+            size: Some(metadata.len()),
+            modified: metadata.modified().ok(),
+            file_id: None,
+        }
+    };
+    let keeper_hashed = hashed(&keeper);
+    let copy_hashed = hashed(&copy);
+
+    fs::remove_file(&keeper).unwrap();
+    let deleted = delete_file(
+        &copy,
+        &keeper,
+        Some(&copy_hashed),
+        Some(&keeper_hashed),
+        false,
+    );
+    assert!(deleted.is_err());
+    assert!(copy.exists());
+
+    fs::write(&keeper, b"contents").unwrap();
+    let keeper_hashed = hashed(&keeper);
+    delete_file(
+        &copy,
+        &keeper,
+        Some(&copy_hashed),
+        Some(&keeper_hashed),
+        false,
+    )
+    .unwrap();
+    assert!(!copy.exists());
+}